@@ -292,10 +292,11 @@ impl<K: Preimage> OnEvent<Put<K>> for Peer<K> {
             anyhow::bail!("duplicated upload chunk {}", H256(chunk))
         }
         let fragment_len = self.fragment_len;
-        self.codec_worker.submit(Box::new(move |(), sender| {
-            let encoder = Encoder::new(buf.into(), fragment_len)?;
-            sender.send(NewEncoder(chunk, encoder))
-        }))
+        self.codec_worker
+            .submit_blocking(Box::new(move |(), sender| {
+                let encoder = Encoder::new(buf.into(), fragment_len)?;
+                sender.send(NewEncoder(chunk, encoder))
+            }))
     }
 }
 
@@ -361,10 +362,12 @@ impl<K> OnEvent<Recv<InviteOk>> for Peer<K> {
             }
             state.pending.insert(invite_ok.index, invite_ok.peer_id);
             let encoder = state.encoder.clone().unwrap();
-            return self.codec_worker.submit(Box::new(move |(), sender| {
-                let fragment = encoder.encode(invite_ok.index)?;
-                sender.send(Encode(invite_ok.chunk, invite_ok.index, fragment))
-            }));
+            return self
+                .codec_worker
+                .submit_blocking(Box::new(move |(), sender| {
+                    let fragment = encoder.encode(invite_ok.index)?;
+                    sender.send(Encode(invite_ok.chunk, invite_ok.index, fragment))
+                }));
         }
         if let Some(state) = self.persists.get_mut(&invite_ok.chunk) {
             if invite_ok.index == state.index {
@@ -502,7 +505,7 @@ impl RecoverState {
     ) -> anyhow::Result<()> {
         if let Some(mut decoder) = self.decoder.take() {
             // println!("submit decode {} index {index}", H256(chunk));
-            worker.submit(Box::new(move |(), sender| {
+            worker.submit_blocking(Box::new(move |(), sender| {
                 if !decoder.decode(index, &fragment)? {
                     sender.send(Decode(chunk, decoder))
                 } else if let Some(index) = encode_index {
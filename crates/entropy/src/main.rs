@@ -3,7 +3,7 @@ use std::{
     collections::HashMap,
     env::args,
     future::IntoFuture,
-    net::SocketAddr,
+    net::{IpAddr, SocketAddr},
     sync::{
         atomic::{AtomicU32, Ordering::SeqCst},
         Arc,
@@ -23,14 +23,16 @@ use augustus::{
             session::{Buffered, Sender},
             Blanket, Session, Unify,
         },
+        shard::Shards,
         SendEvent,
     },
     kademlia::{self, Buckets, PeerRecord},
     net::{
         kademlia::{Control, PeerNet},
         session::{Dispatch, DispatchNet},
+        shape::{Rate, Shaped, Shaper},
     },
-    worker::erased::Worker,
+    worker::erased::{spawn_backend, Worker},
 };
 use axum::{
     extract::{DefaultBodyLimit, Multipart, Path, State},
@@ -41,7 +43,7 @@ use axum::{
 
 use entropy::{Get, GetOk, MessageNet, Peer, Put, PutOk};
 use entropy_control_messages::{
-    GetConfig, GetResult, PeerUrl, PutConfig, PutResult, StartPeersConfig,
+    BandwidthConfig, GetConfig, GetResult, PeerUrl, PutConfig, PutResult, StartPeersConfig,
 };
 use rand::{rngs::StdRng, seq::SliceRandom, thread_rng, RngCore, SeedableRng};
 use reqwest::multipart::{Form, Part};
@@ -92,6 +94,7 @@ async fn main() -> anyhow::Result<()> {
         .route("/benchmark-get/:get_id", get(poll_benchmark_get))
         .route("/start-peers", post(start_peers))
         .route("/stop-peers", post(stop_peers))
+        .route("/set-bandwidth", post(set_bandwidth))
         // and the same time, it also includes the following endpoints that belongs to the internal
         // of entropy, which happens to also communiate using HTTP (for aligning with IPFS)
         .route(
@@ -103,11 +106,15 @@ async fn main() -> anyhow::Result<()> {
     let (upcall_sender, mut upcall_receiver) = unbounded_channel();
     let pending_puts = Arc::new(Mutex::new(HashMap::new()));
     let pending_gets = Arc::new(Mutex::new(HashMap::new()));
-    // let runtime = Arc::new(runtime::Builder::new_multi_thread().enable_all().build()?);
+    // leave a core for the codec pool, which is shared by all local peers
+    let shards = Shards::new_pinned(std::thread::available_parallelism()?.get().max(2) - 1)?;
+    let codec_pool = Arc::new(shards.new_spare_pool("codec")?);
     let app = app.with_state(AppState {
         peers: Default::default(),
-        // runtime: runtime.clone(),
+        shards: Arc::new(shards),
+        codec_pool,
         upcall_sender,
+        shaper: Default::default(),
         pending_puts: pending_puts.clone(),
         pending_gets: pending_gets.clone(),
         op_client: reqwest::Client::new(),
@@ -154,17 +161,17 @@ async fn main() -> anyhow::Result<()> {
         result = serve => result?,
         result = upcall_session => result?,
     }
-    // Arc::try_unwrap(runtime)
-    //     .map_err(|_| anyhow::anyhow!("cannot shutdown runtime"))?
-    //     .shutdown_background();
     Ok(())
 }
 
 #[derive(Clone)]
 struct AppState {
     peers: Arc<Mutex<PeersState>>,
-    // runtime: Arc<Runtime>,
+    shards: Arc<Shards>,
+    codec_pool: Arc<rayon::ThreadPool>,
     upcall_sender: UnboundedSender<Upcall>,
+    // shared by all local peers, so the aggregate limit applies to the host
+    shaper: Shaper<IpAddr>,
     pending_puts: Arc<Mutex<HashMap<[u8; 32], oneshot::Sender<()>>>>,
     #[allow(clippy::type_complexity)]
     pending_gets: Arc<Mutex<HashMap<[u8; 32], oneshot::Sender<Vec<u8>>>>>,
@@ -222,19 +229,24 @@ async fn start_peers(State(state): State<AppState>, Json(config): Json<StartPeer
     for (record, crypto, rng) in local_peers {
         let peer_session = Session::new();
         peers.senders.push(Sender::from(peer_session.sender()));
-        // peers.sessions.spawn_on(
-        peers.sessions.spawn(
+        let records = records.clone();
+        let upcall_sender = state.upcall_sender.clone();
+        let config = config.clone();
+        let codec_pool = state.codec_pool.clone();
+        let shaper = state.shaper.clone();
+        peers.sessions.spawn(state.shards.spawn(move || {
             start_peer(
                 record,
                 crypto,
                 rng,
-                records.clone(),
+                records,
                 peer_session,
-                state.upcall_sender.clone(),
-                config.clone(),
-            ),
-            // state.runtime.handle(),
-        );
+                upcall_sender,
+                codec_pool,
+                shaper,
+                config,
+            )
+        }));
     }
 }
 
@@ -245,6 +257,8 @@ async fn start_peer(
     mut records: Vec<PeerRecord<PublicKey, SocketAddr>>,
     mut peer_session: Session<Blanket<Buffered<Peer<[u8; 32]>>>>,
     upcall_sender: UnboundedSender<Upcall>,
+    codec_pool: Arc<rayon::ThreadPool>,
+    shaper: Shaper<IpAddr>,
     config: StartPeersConfig,
 ) -> anyhow::Result<()> {
     let peer_id = record.id;
@@ -257,6 +271,12 @@ async fn start_peer(
     // let quic = augustus::net::session::Quic::new(SocketAddr::from(([0; 4], addr.port())))?;
 
     let ip = record.addr.ip();
+    // the bulk transfers are offered to the peer ids, which are shaped by the IP addresses of the
+    // peers as the messages are
+    let peer_ips = records
+        .iter()
+        .map(|record| (record.id, record.addr.ip()))
+        .collect::<HashMap<_, _>>();
     let mut buckets = Buckets::new(record);
     // we don't really need this to be deterministic actually... just too late to realize
     records.shuffle(&mut rng);
@@ -276,7 +296,11 @@ async fn start_peer(
 
     let mut kademlia_peer = Blanket(Buffered::from(kademlia::Peer::new(
         buckets,
-        MessageNet::new(DispatchNet(Sender::from(tcp_control_session.sender()))),
+        MessageNet::new(Shaped::new_keyed(
+            DispatchNet(Sender::from(tcp_control_session.sender())),
+            shaper.clone(),
+            SocketAddr::ip,
+        )),
         // MessageNet::new(DispatchNet(Sender::from(quic_control_session.sender()))),
         Sender::from(kademlia_control_session.sender()),
         Worker::new_inline(
@@ -285,10 +309,20 @@ async fn start_peer(
         ),
     )));
     let mut kademlia_control = Blanket(Buffered::from(Control::new(
-        DispatchNet(Sender::from(tcp_control_session.sender())),
+        Shaped::new_keyed(
+            DispatchNet(Sender::from(tcp_control_session.sender())),
+            shaper.clone(),
+            SocketAddr::ip,
+        ),
         // DispatchNet(Sender::from(quic_control_session.sender())),
         Sender::from(kademlia_session.sender()),
     )));
+    // the encoding and decoding take milliseconds, so they go to the blocking pool instead of the
+    // shard, and a peer cannot take up more than the whole pool
+    let (codec_worker, codec_executor) = spawn_backend(());
+    let mut codec_executor = codec_executor
+        .with_concurrency_limit(codec_pool.current_num_threads())
+        .with_blocking_pool(codec_pool);
     let mut peer = Blanket(Buffered::from(Peer::new(
         peer_id,
         crypto,
@@ -299,12 +333,11 @@ async fn start_peer(
         MessageNet::<_, SocketAddr>::new(PeerNet(Sender::from(kademlia_control_session.sender()))),
         blob_sender.clone(),
         upcall_sender,
-        Worker::new_inline((), Box::new(Sender::from(peer_session.sender()))),
+        codec_worker,
         fs_sender,
     )));
-    let mut tcp_control = Blanket(Unify(Dispatch::new(
-        augustus::net::session::Tcp::new(addr)?,
-        {
+    let mut tcp_control = Blanket(Unify(
+        Dispatch::new(augustus::net::session::Tcp::new(addr)?, {
             // let mut quic_control = Blanket(Unify(Dispatch::new(quic.clone(), {
             let mut peer_sender = Sender::from(peer_session.sender());
             let mut kademlia_sender = Sender::from(kademlia_session.sender());
@@ -316,8 +349,10 @@ async fn start_peer(
                     &mut blob_sender,
                 )
             }
-        },
-    )?));
+        })?
+        // let the kademlia peer drop the peers that cannot be connected to
+        .with_upcall(Sender::from(kademlia_session.sender())),
+    ));
 
     let socket_session = augustus::net::session::tcp_accept_session(
         listener,
@@ -328,14 +363,17 @@ async fn start_peer(
     //     Sender::from(quic_control_session.sender()),
     // );
     let kademlia_session = kademlia_session.run(&mut kademlia_peer);
-    let blob_session = bulk::session(
+    let blob_session = bulk::shaped_session(
         ip,
         blob_receiver,
         MessageNet::<_, SocketAddr>::new(PeerNet(Sender::from(kademlia_control_session.sender()))),
         Sender::from(peer_session.sender()),
+        shaper,
+        move |peer_id| peer_ips[peer_id],
     );
     let kademlia_control_session = kademlia_control_session.run(&mut kademlia_control);
     let fs_session = entropy::fs::session(path, fs_receiver, Sender::from(peer_session.sender()));
+    let codec_session = codec_executor.run(Sender::from(peer_session.sender()), |sender| sender);
     let peer_session = peer_session.run(&mut peer);
     Sender::from(tcp_control_session.sender()).send(Init)?;
     let tcp_control_session = tcp_control_session.run(&mut tcp_control);
@@ -349,6 +387,7 @@ async fn start_peer(
         result = blob_session => result?,
         result = fs_session => result?,
         result = peer_session => result?,
+        result = codec_session => result?,
         result = tcp_control_session => result?,
         // result = quic_control_session => result?,
     }
@@ -361,6 +400,26 @@ async fn stop_peers(State(state): State<AppState>) {
     peers.senders.clear();
 }
 
+// the limits take effect on the running peers immediately
+async fn set_bandwidth(
+    State(state): State<AppState>,
+    Json(config): Json<BandwidthConfig>,
+) -> Result<(), (StatusCode, String)> {
+    if config.aggregate == Some(0)
+        || config.default == Some(0)
+        || config.per_dest.iter().any(|(_, rate)| *rate == 0)
+    {
+        return Err((StatusCode::BAD_REQUEST, "zero bandwidth".into()));
+    }
+    state.shaper.clear();
+    state.shaper.set_aggregate(config.aggregate.map(Rate::new));
+    state.shaper.set_default(config.default.map(Rate::new));
+    for (dest, rate) in config.per_dest {
+        state.shaper.set_dest(dest, Rate::new(rate))
+    }
+    Ok(())
+}
+
 async fn put_chunk(
     State(state): State<AppState>,
     Path(peer_index): Path<usize>,
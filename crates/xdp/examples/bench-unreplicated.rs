@@ -0,0 +1,106 @@
+// the default UDP setup of the top level bench-unreplicated, with the kernel UDP stack replaced by
+// AF_XDP on both sides
+//
+// bench-unreplicated <interface> <replica addr>
+// bench-unreplicated <interface> <replica addr> client <client addr>
+//
+// the two sides must not share a host's network stack, or the neighbor probe goes through the
+// loopback and never resolves. on a single host, put the replica end of a veth pair into a netns
+// ip netns exec replica bench-unreplicated veth0 10.111.0.1:4000
+// bench-unreplicated veth1 10.111.0.1:4000 client 10.111.0.2:4001
+//
+// pbft plugs in the same way, i.e. `pbft::ToReplicaMessageNet::new(IndexNet::new(xdp, ..))` and
+// `pbft::ToClientMessageNet::new(xdp)`, and `pbft::to_replica_on_buf` in `recv_session`
+
+use std::{env::args, iter::repeat_with, net::SocketAddr, time::Duration};
+
+use augustus::{
+    app::Null,
+    event::{
+        erased::{self, events::Init, Blanket},
+        SendEvent as _, Session, Unify,
+    },
+    net::IndexNet,
+    unreplicated::{
+        self, to_client_on_buf, to_replica_on_buf, Client, Replica, ToClientMessageNet,
+        ToReplicaMessageNet,
+    },
+    workload::{CloseLoop, Iter, OpLatency},
+};
+use tokio::{signal::ctrl_c, time::sleep};
+use xdp::Xdp;
+
+#[tokio::main(flavor = "current_thread")]
+async fn main() -> anyhow::Result<()> {
+    tracing_subscriber::fmt::init();
+    let args = args().skip(1).collect::<Vec<_>>();
+    let (interface, replica_addr, client_addr) = match &args[..] {
+        [interface, replica_addr] => (interface, replica_addr.parse()?, None),
+        [interface, replica_addr, flag, client_addr] if flag == "client" => {
+            (interface, replica_addr.parse()?, Some(client_addr.parse()?))
+        }
+        _ => anyhow::bail!("invalid arguments {args:?}"),
+    };
+
+    if let Some(client_addr) = client_addr {
+        let raw_net = Xdp::bind(interface, client_addr)?;
+        println!(
+            "Client bind to {} ({:?} mode)",
+            raw_net.local_addr(),
+            raw_net.mode()
+        );
+        let mut state_session = Session::<unreplicated::ClientEvent>::new();
+        let mut close_loop_session = erased::Session::new();
+        let mut close_loop = Blanket(erased::Unify(CloseLoop::new(
+            state_session.sender(),
+            OpLatency::new(Iter(repeat_with(Default::default))),
+        )));
+        let mut state = Unify(Client::new(
+            rand::random(),
+            raw_net.local_addr(),
+            ToReplicaMessageNet::new(IndexNet::new(
+                raw_net.clone(),
+                vec![SocketAddr::V4(replica_addr)],
+                None,
+            )),
+            erased::session::Sender::from(close_loop_session.sender()),
+        ));
+        let mut state_sender = state_session.sender();
+        let recv_session = raw_net.recv_session(|buf| to_client_on_buf(buf, &mut state_sender));
+        erased::session::Sender::from(close_loop_session.sender()).send(Init)?;
+        tokio::select! {
+            result = recv_session => result?,
+            result = state_session.run(&mut state) => result?,
+            result = close_loop_session.run(&mut close_loop) => result?,
+            () = sleep(Duration::from_secs(10)) => {
+                let latencies = &close_loop.workload.latencies;
+                println!("{} ops/sec", latencies.len() as f32 / 10.);
+                if !latencies.is_empty() {
+                    println!(
+                        "{:?}",
+                        latencies.iter().sum::<Duration>() / latencies.len() as u32
+                    )
+                }
+                return Ok(());
+            }
+        }
+        anyhow::bail!("unexpected shutdown")
+    }
+
+    let raw_net = Xdp::bind(interface, replica_addr)?;
+    println!(
+        "Replica bind to {} ({:?} mode)",
+        raw_net.local_addr(),
+        raw_net.mode()
+    );
+    let mut state = Unify(Replica::new(Null, ToClientMessageNet::new(raw_net.clone())));
+    let mut state_session = Session::<unreplicated::ReplicaEvent<_>>::new();
+    let mut state_sender = state_session.sender();
+    let recv_session = raw_net.recv_session(move |buf| to_replica_on_buf(buf, &mut state_sender));
+    tokio::select! {
+        result = recv_session => result?,
+        result = state_session.run(&mut state) => result?,
+        result = ctrl_c() => return Ok(result?),
+    }
+    anyhow::bail!("unexpected exit")
+}
@@ -0,0 +1,269 @@
+// the XDP program that steers the datagrams into the AF_XDP socket
+//
+// the program is small enough to be assembled by hand, which saves the
+// dependencies on clang and libbpf. it matches the IPv4 (without options) UDP
+// datagrams to the bound port, redirects them into the socket through an
+// XSKMAP that is keyed by the receive queue, and passes everything else to the
+// kernel stack as usual, so the interface keeps working for e.g. SSH and ARP
+//
+// the program is attached through a BPF link, which is detached when the link
+// fd is closed i.e. when the `Program` is dropped, so a crashed node does not
+// leave the program behind
+
+use std::{
+    io,
+    mem::size_of,
+    os::fd::{AsRawFd as _, BorrowedFd, FromRawFd as _, OwnedFd},
+};
+
+const BPF_MAP_CREATE: libc::c_long = 0;
+const BPF_MAP_UPDATE_ELEM: libc::c_long = 2;
+const BPF_PROG_LOAD: libc::c_long = 5;
+const BPF_LINK_CREATE: libc::c_long = 28;
+
+const BPF_MAP_TYPE_XSKMAP: u32 = 17;
+const BPF_PROG_TYPE_XDP: u32 = 6;
+const BPF_XDP: u32 = 37;
+
+const XDP_FLAGS_SKB_MODE: u32 = 1 << 1;
+const XDP_FLAGS_DRV_MODE: u32 = 1 << 2;
+
+const XDP_PASS: i32 = 2;
+const BPF_FUNC_REDIRECT_MAP: i32 = 51;
+const BPF_PSEUDO_MAP_FD: u8 = 1;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Mode {
+    // in the driver, before any `sk_buff` is allocated
+    Native,
+    // after the `sk_buff` allocation, works with any driver
+    Generic,
+}
+
+#[derive(Debug)]
+pub struct Program {
+    _map: OwnedFd,
+    _prog: OwnedFd,
+    _link: OwnedFd,
+    pub mode: Mode,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct Insn {
+    code: u8,
+    regs: u8,
+    off: i16,
+    imm: i32,
+}
+
+const fn insn(code: u8, dst: u8, src: u8, off: i16, imm: i32) -> Insn {
+    Insn {
+        code,
+        regs: dst | src << 4,
+        off,
+        imm,
+    }
+}
+
+// the opcodes that are used below
+const MOV64_REG: u8 = 0xbf;
+const MOV64_IMM: u8 = 0xb7;
+const ADD64_IMM: u8 = 0x07;
+const LDX_W: u8 = 0x61;
+const LDX_H: u8 = 0x69;
+const LDX_B: u8 = 0x71;
+const LD_DW_IMM: u8 = 0x18;
+const JGT_REG: u8 = 0x2d;
+const JNE_IMM: u8 = 0x55;
+const CALL: u8 = 0x85;
+const EXIT: u8 = 0x95;
+
+// the offsets of the fields that are checked, in the Ethernet frame
+const ETHER_TYPE: i16 = 12;
+const IP_VERSION_IHL: i16 = 14;
+const IP_PROTOCOL: i16 = 23;
+const UDP_DEST_PORT: i16 = 36;
+const HEADERS_LEN: i32 = 42;
+
+fn instructions(map_fd: i32, port: u16) -> Vec<Insn> {
+    // the jumps are relative to the next instruction
+    const PASS: i16 = 20;
+    let to_pass = |index: i16| PASS - (index + 1);
+    // the fields are in network byte order, and loaded in host byte order
+    let ether_type_ipv4 = u16::from_ne_bytes(0x0800u16.to_be_bytes()) as i32;
+    let port = u16::from_ne_bytes(port.to_be_bytes()) as i32;
+    vec![
+        // r6 = ctx, r2 = data, r3 = data_end
+        insn(MOV64_REG, 6, 1, 0, 0),
+        insn(LDX_W, 2, 1, 0, 0),
+        insn(LDX_W, 3, 1, 4, 0),
+        // bound check that is required by the verifier
+        insn(MOV64_REG, 4, 2, 0, 0),
+        insn(ADD64_IMM, 4, 0, 0, HEADERS_LEN),
+        insn(JGT_REG, 4, 3, to_pass(5), 0),
+        insn(LDX_H, 5, 2, ETHER_TYPE, 0),
+        insn(JNE_IMM, 5, 0, to_pass(7), ether_type_ipv4),
+        insn(LDX_B, 5, 2, IP_VERSION_IHL, 0),
+        insn(JNE_IMM, 5, 0, to_pass(9), 0x45),
+        insn(LDX_B, 5, 2, IP_PROTOCOL, 0),
+        insn(JNE_IMM, 5, 0, to_pass(11), libc::IPPROTO_UDP),
+        insn(LDX_H, 5, 2, UDP_DEST_PORT, 0),
+        insn(JNE_IMM, 5, 0, to_pass(13), port),
+        // return bpf_redirect_map(&xsks, ctx->rx_queue_index, XDP_PASS)
+        // the last argument is the action when the queue has no socket
+        insn(LDX_W, 2, 6, 16, 0),
+        insn(LD_DW_IMM, 1, BPF_PSEUDO_MAP_FD, 0, map_fd),
+        insn(0, 0, 0, 0, 0),
+        insn(MOV64_IMM, 3, 0, 0, XDP_PASS),
+        insn(CALL, 0, 0, 0, BPF_FUNC_REDIRECT_MAP),
+        insn(EXIT, 0, 0, 0, 0),
+        // PASS
+        insn(MOV64_IMM, 0, 0, 0, XDP_PASS),
+        insn(EXIT, 0, 0, 0, 0),
+    ]
+}
+
+#[repr(C)]
+#[derive(Default)]
+struct MapCreateAttr {
+    map_type: u32,
+    key_size: u32,
+    value_size: u32,
+    max_entries: u32,
+    map_flags: u32,
+}
+
+#[repr(C)]
+#[derive(Default)]
+struct MapUpdateAttr {
+    map_fd: u32,
+    key: u64,
+    value: u64,
+    flags: u64,
+}
+
+#[repr(C)]
+#[derive(Default)]
+struct ProgLoadAttr {
+    prog_type: u32,
+    insn_cnt: u32,
+    insns: u64,
+    license: u64,
+    log_level: u32,
+    log_size: u32,
+    log_buf: u64,
+    kern_version: u32,
+    prog_flags: u32,
+    prog_name: [u8; 16],
+    prog_ifindex: u32,
+    expected_attach_type: u32,
+}
+
+#[repr(C)]
+#[derive(Default)]
+struct LinkCreateAttr {
+    prog_fd: u32,
+    target_ifindex: u32,
+    attach_type: u32,
+    flags: u32,
+}
+
+fn bpf<T>(cmd: libc::c_long, attr: &mut T) -> io::Result<libc::c_long> {
+    let result = unsafe { libc::syscall(libc::SYS_bpf, cmd, attr as *mut T, size_of::<T>()) };
+    if result < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(result)
+}
+
+// for the commands that return a new fd
+fn bpf_fd<T>(cmd: libc::c_long, attr: &mut T) -> io::Result<OwnedFd> {
+    let fd = bpf(cmd, attr)?;
+    Ok(unsafe { OwnedFd::from_raw_fd(fd as _) })
+}
+
+fn load(map_fd: i32, port: u16, log: Option<&mut [u8]>) -> io::Result<OwnedFd> {
+    let insns = instructions(map_fd, port);
+    let license = b"GPL\0";
+    let mut attr = ProgLoadAttr {
+        prog_type: BPF_PROG_TYPE_XDP,
+        insn_cnt: insns.len() as _,
+        insns: insns.as_ptr() as _,
+        license: license.as_ptr() as _,
+        expected_attach_type: BPF_XDP,
+        ..Default::default()
+    };
+    attr.prog_name[..10].copy_from_slice(b"augustus_x");
+    if let Some(log) = log {
+        attr.log_level = 1;
+        attr.log_size = log.len() as _;
+        attr.log_buf = log.as_mut_ptr() as _
+    }
+    bpf_fd(BPF_PROG_LOAD, &mut attr)
+}
+
+// redirect the datagrams to `port` that are received from `queue_id` of the interface into
+// `socket`. the native mode is preferred, and the generic mode is the fallback
+pub fn attach(
+    ifindex: u32,
+    queue_id: u32,
+    port: u16,
+    socket: BorrowedFd<'_>,
+) -> anyhow::Result<Program> {
+    let map = bpf_fd(
+        BPF_MAP_CREATE,
+        &mut MapCreateAttr {
+            map_type: BPF_MAP_TYPE_XSKMAP,
+            key_size: 4,
+            value_size: 4,
+            max_entries: queue_id + 1,
+            ..Default::default()
+        },
+    )?;
+    let prog = match load(map.as_raw_fd(), port, None) {
+        Ok(prog) => prog,
+        // load again for the verifier's complaint
+        Err(err) => {
+            let mut log = vec![0; 1 << 16];
+            let _ = load(map.as_raw_fd(), port, Some(&mut log));
+            let log = String::from_utf8_lossy(&log);
+            anyhow::bail!("load XDP program: {err}\n{}", log.trim_end_matches('\0'))
+        }
+    };
+    let key = queue_id;
+    let value = socket.as_raw_fd() as u32;
+    bpf(
+        BPF_MAP_UPDATE_ELEM,
+        &mut MapUpdateAttr {
+            map_fd: map.as_raw_fd() as _,
+            key: &key as *const _ as _,
+            value: &value as *const _ as _,
+            flags: 0,
+        },
+    )?;
+    let link_create = |flags| {
+        bpf_fd(
+            BPF_LINK_CREATE,
+            &mut LinkCreateAttr {
+                prog_fd: prog.as_raw_fd() as _,
+                target_ifindex: ifindex,
+                attach_type: BPF_XDP,
+                flags,
+            },
+        )
+    };
+    let (link, mode) = match link_create(XDP_FLAGS_DRV_MODE) {
+        Ok(link) => (link, Mode::Native),
+        Err(err) => {
+            tracing::info!("native XDP unavailable ({err}), fallback to generic XDP");
+            (link_create(XDP_FLAGS_SKB_MODE)?, Mode::Generic)
+        }
+    };
+    Ok(Program {
+        _map: map,
+        _prog: prog,
+        _link: link,
+        mode,
+    })
+}
@@ -0,0 +1,490 @@
+// kernel-bypass raw net over AF_XDP
+//
+// `Xdp` is a raw net, i.e. `SendMessage<SocketAddr, impl Buf>` like the UDP
+// one in `augustus::net::session`, so it plugs into the protocols through the
+// same `MessageNet` wrapping e.g. `unreplicated::ToReplicaMessageNet`, and
+// `recv_session` is the receive loop that is driven by the same `on_buf`s. the
+// datagrams are UDP on the wire, just not through the kernel UDP stack: the
+// Ethernet, IPv4 and UDP headers are built and parsed here, and the frames go
+// to and come from the driver through an AF_XDP socket (see `xsk`) and a tiny
+// XDP program (see `bpf`). the program is attached in the native mode if the
+// driver supports it, or falls back to the generic mode, which works with any
+// interface including veth pairs and the virtual NICs of plain Linux VMs
+//
+// this is for measuring the protocols with the kernel stack's cost taken out,
+// and it comes with limitations that are fine for that purpose
+// * IPv4 only
+// * the peers must be on the same link. the destination MAC addresses come
+//   from the kernel's neighbor table, which is populated by probing through
+//   a kernel socket if necessary (the message is dropped in the meantime, and
+//   the table is checked again at most once per `PROBE_INTERVAL`), or can be
+//   given with `add_neighbor`. there is no routing through gateways, and a
+//   node cannot send to itself
+// * a buffer must fit into a single frame of the link's MTU. wrap with
+//   `augustus::net::fragment::Fragmented` to send larger ones. the IP
+//   fragments are ignored
+// * a single queue. the interface should have only one receive queue (e.g.
+//   `ethtool -L <interface> combined 1`), or the traffic to the bound port
+//   should be steered to the queue
+// * the node requires CAP_NET_ADMIN and CAP_BPF (or simply root), and at most
+//   one program can be attached to an interface at a time, so one `Xdp` per
+//   interface
+//
+// this is in `crates` instead of `src` for the system requirements: a kernel
+// with AF_XDP and BPF link support (5.9+), and the capabilities above
+
+pub mod bpf;
+pub mod xsk;
+
+use std::{
+    collections::HashMap,
+    fmt::Debug,
+    fs::read_to_string,
+    net::{Ipv4Addr, SocketAddr, SocketAddrV4, UdpSocket},
+    os::fd::AsFd as _,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use augustus::net::{with_recv_buf, with_recv_from, Buf, Chunk, IterAddr, SendMessage};
+use tokio::io::{unix::AsyncFd, Interest};
+use tracing::warn;
+
+pub use bpf::Mode;
+
+type MacAddr = [u8; 6];
+
+const ETHERNET_HEADER_LEN: usize = 14;
+const IP_HEADER_LEN: usize = 20;
+const UDP_HEADER_LEN: usize = 8;
+const HEADERS_LEN: usize = ETHERNET_HEADER_LEN + IP_HEADER_LEN + UDP_HEADER_LEN;
+pub const PROBE_INTERVAL: Duration = Duration::from_millis(100);
+
+#[derive(Clone)]
+pub struct Xdp(Arc<Inner>);
+
+struct Inner {
+    // dropped first so nothing is redirected to the closed socket
+    program: bpf::Program,
+    socket: xsk::Socket,
+    addr: SocketAddrV4,
+    mac: MacAddr,
+    max_buf_len: usize,
+    neighbors: Mutex<Neighbors>,
+    // for resolving the neighbors through the kernel
+    probe: UdpSocket,
+}
+
+#[derive(Default)]
+struct Neighbors {
+    resolved: HashMap<Ipv4Addr, MacAddr>,
+    // the unresolved ones, and when they are probed last time
+    probed: HashMap<Ipv4Addr, Instant>,
+}
+
+impl Debug for Xdp {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Xdp")
+            .field("addr", &self.0.addr)
+            .field("mode", &self.0.program.mode)
+            .finish_non_exhaustive()
+    }
+}
+
+fn interface_attr(interface: &str, name: &str) -> anyhow::Result<String> {
+    Ok(
+        read_to_string(format!("/sys/class/net/{interface}/{name}"))?
+            .trim()
+            .into(),
+    )
+}
+
+fn parse_mac(s: &str) -> Option<MacAddr> {
+    let mut mac = MacAddr::default();
+    let mut parts = s.split(':');
+    for b in &mut mac {
+        *b = u8::from_str_radix(parts.next()?, 16).ok()?
+    }
+    if parts.next().is_some() {
+        return None;
+    }
+    Some(mac)
+}
+
+impl Xdp {
+    // `addr` is the address of `interface` to take over. the datagrams to its port are not seen by
+    // the kernel anymore
+    pub fn bind(interface: &str, addr: SocketAddrV4) -> anyhow::Result<Self> {
+        let queue_id = 0;
+        let ifindex = unsafe { libc::if_nametoindex(std::ffi::CString::new(interface)?.as_ptr()) };
+        if ifindex == 0 {
+            anyhow::bail!("interface {interface}: {}", std::io::Error::last_os_error())
+        }
+        let mac = interface_attr(interface, "address")?;
+        let mac = parse_mac(&mac).ok_or(anyhow::anyhow!("invalid MAC address {mac}"))?;
+        let mtu = interface_attr(interface, "mtu")?.parse::<usize>()?;
+        let socket = xsk::Socket::bind(ifindex, queue_id)?;
+        let program = bpf::attach(ifindex, queue_id, addr.port(), socket.as_fd())?;
+        Ok(Self(Arc::new(Inner {
+            program,
+            socket,
+            addr,
+            mac,
+            max_buf_len: (mtu - IP_HEADER_LEN - UDP_HEADER_LEN).min(xsk::FRAME_SIZE - HEADERS_LEN),
+            neighbors: Default::default(),
+            probe: UdpSocket::bind((*addr.ip(), 0))?,
+        })))
+    }
+
+    pub fn local_addr(&self) -> SocketAddr {
+        self.0.addr.into()
+    }
+
+    pub fn mode(&self) -> Mode {
+        self.0.program.mode
+    }
+
+    pub fn add_neighbor(&self, ip: Ipv4Addr, mac: MacAddr) {
+        let mut neighbors = self.0.neighbors.lock().unwrap();
+        neighbors.probed.remove(&ip);
+        neighbors.resolved.insert(ip, mac);
+    }
+}
+
+impl Inner {
+    // the unresolved neighbors are not looked up again within `PROBE_INTERVAL`, so the sending to
+    // them does not read the neighbor table and probe every time
+    fn neighbor(&self, ip: Ipv4Addr) -> anyhow::Result<Option<MacAddr>> {
+        {
+            let mut neighbors = self.neighbors.lock().unwrap();
+            if let Some(mac) = neighbors.resolved.get(&ip) {
+                return Ok(Some(*mac));
+            }
+            let now = Instant::now();
+            if neighbors
+                .probed
+                .get(&ip)
+                .is_some_and(|probed| now - *probed < PROBE_INTERVAL)
+            {
+                return Ok(None);
+            }
+            neighbors.probed.insert(ip, now);
+        }
+        let mut resolved = Vec::new();
+        // IP address, HW type, flags, HW address, mask, device
+        for line in read_to_string("/proc/net/arp")?.lines().skip(1) {
+            let fields = line.split_whitespace().collect::<Vec<_>>();
+            // ATF_COM, i.e. the entry is complete
+            let complete = fields
+                .get(2)
+                .and_then(|flags| u32::from_str_radix(flags.trim_start_matches("0x"), 16).ok())
+                .is_some_and(|flags| flags & 0x2 != 0);
+            if let (Some(Ok(entry_ip)), Some(Some(mac)), true) = (
+                fields.first().map(|ip| ip.parse::<Ipv4Addr>()),
+                fields.get(3).map(|mac| parse_mac(mac)),
+                complete,
+            ) {
+                resolved.push((entry_ip, mac))
+            }
+        }
+        let mut neighbors = self.neighbors.lock().unwrap();
+        for (entry_ip, mac) in resolved {
+            neighbors.probed.remove(&entry_ip);
+            neighbors.resolved.insert(entry_ip, mac);
+        }
+        if let Some(mac) = neighbors.resolved.get(&ip) {
+            return Ok(Some(*mac));
+        }
+        drop(neighbors);
+        // the discard port, so the probe is harmless even if it reaches the peer
+        self.probe.send_to(&[], (ip, 9))?;
+        Ok(None)
+    }
+}
+
+// write an Ethernet frame of the UDP datagram of `buf` into `frame`, and return the length of the
+// frame
+fn write_frame(
+    frame: &mut [u8],
+    (src_mac, src): (MacAddr, SocketAddrV4),
+    (dest_mac, dest): (MacAddr, SocketAddrV4),
+    buf: &[u8],
+) -> usize {
+    let (ethernet, frame) = frame.split_at_mut(ETHERNET_HEADER_LEN);
+    ethernet[..6].copy_from_slice(&dest_mac);
+    ethernet[6..12].copy_from_slice(&src_mac);
+    ethernet[12..].copy_from_slice(&0x0800u16.to_be_bytes());
+
+    let (ip, frame) = frame.split_at_mut(IP_HEADER_LEN);
+    let ip_len = (IP_HEADER_LEN + UDP_HEADER_LEN + buf.len()) as u16;
+    ip[0] = 0x45;
+    ip[1] = 0;
+    ip[2..4].copy_from_slice(&ip_len.to_be_bytes());
+    // identification, and don't fragment
+    ip[4..6].fill(0);
+    ip[6..8].copy_from_slice(&0x4000u16.to_be_bytes());
+    ip[8] = 64;
+    ip[9] = libc::IPPROTO_UDP as _;
+    ip[10..12].fill(0);
+    ip[12..16].copy_from_slice(&src.ip().octets());
+    ip[16..20].copy_from_slice(&dest.ip().octets());
+    let checksum = checksum(ip);
+    ip[10..12].copy_from_slice(&checksum.to_be_bytes());
+
+    let (udp, frame) = frame.split_at_mut(UDP_HEADER_LEN);
+    udp[0..2].copy_from_slice(&src.port().to_be_bytes());
+    udp[2..4].copy_from_slice(&dest.port().to_be_bytes());
+    udp[4..6].copy_from_slice(&((UDP_HEADER_LEN + buf.len()) as u16).to_be_bytes());
+    // no checksum, which is allowed for IPv4
+    udp[6..8].fill(0);
+
+    frame[..buf.len()].copy_from_slice(buf);
+    HEADERS_LEN + buf.len()
+}
+
+fn checksum(header: &[u8]) -> u16 {
+    let mut sum = header
+        .chunks(2)
+        .map(|word| u16::from_be_bytes([word[0], word[1]]) as u32)
+        .sum::<u32>();
+    while sum > 0xffff {
+        sum = (sum & 0xffff) + (sum >> 16)
+    }
+    !(sum as u16)
+}
+
+// the source and the UDP payload of `frame` if it is a datagram to `addr`
+fn payload(frame: &[u8], addr: SocketAddrV4) -> Option<(SocketAddrV4, &[u8])> {
+    if frame.len() < HEADERS_LEN
+        || frame[12..14] != 0x0800u16.to_be_bytes()
+        || frame[14] != 0x45
+        || frame[23] != libc::IPPROTO_UDP as u8
+        // more fragments, or fragment offset
+        || u16::from_be_bytes([frame[20], frame[21]]) & 0x3fff != 0
+        || frame[30..34] != addr.ip().octets()
+        || u16::from_be_bytes([frame[36], frame[37]]) != addr.port()
+    {
+        return None;
+    }
+    let udp_len = u16::from_be_bytes([frame[38], frame[39]]) as usize;
+    if udp_len < UDP_HEADER_LEN || ETHERNET_HEADER_LEN + IP_HEADER_LEN + udp_len > frame.len() {
+        return None;
+    }
+    let src = SocketAddrV4::new(
+        Ipv4Addr::new(frame[26], frame[27], frame[28], frame[29]),
+        u16::from_be_bytes([frame[34], frame[35]]),
+    );
+    Some((
+        src,
+        &frame[HEADERS_LEN..ETHERNET_HEADER_LEN + IP_HEADER_LEN + udp_len],
+    ))
+}
+
+impl Xdp {
+    pub async fn recv_session(
+        &self,
+        mut on_buf: impl FnMut(&[u8]) -> anyhow::Result<()>,
+    ) -> anyhow::Result<()> {
+        let fd = AsyncFd::with_interest(
+            self.0.socket.as_fd().try_clone_to_owned()?,
+            Interest::READABLE,
+        )?;
+        // the payloads are copied out so the frames are given back to the kernel at once, and they
+        // are cut from a chunk that holds many of them, as what `session::Udp` does
+        let mut chunk = Chunk::new(1 << 20);
+        let mut lens = Vec::new();
+        loop {
+            let mut guard = fd.readable().await?;
+            let count = self.0.socket.recv(|frame| {
+                let Some((src, payload)) = payload(frame, self.0.addr) else {
+                    return;
+                };
+                chunk.reserve(payload.len()).extend_from_slice(payload);
+                lens.push((src, payload.len()))
+            });
+            if count == 0 {
+                guard.clear_ready();
+                continue;
+            }
+            for (src, len) in lens.drain(..) {
+                let buf = chunk.split_to(len);
+                with_recv_from(src.into(), || with_recv_buf(buf, &mut on_buf))?
+            }
+        }
+    }
+}
+
+impl<B: Buf> SendMessage<SocketAddr, B> for Xdp {
+    fn send(&mut self, dest: SocketAddr, buf: B) -> anyhow::Result<()> {
+        let SocketAddr::V4(dest) = dest else {
+            anyhow::bail!("unsupported destination {dest}")
+        };
+        let buf = buf.as_ref();
+        if buf.len() > self.0.max_buf_len {
+            anyhow::bail!("buffer of {} bytes exceeds the frame", buf.len())
+        }
+        let Some(mac) = self.0.neighbor(*dest.ip())? else {
+            warn!("{dest} dropped: neighbor not resolved yet");
+            return Ok(());
+        };
+        if !self
+            .0
+            .socket
+            .send(|frame| write_frame(frame, (self.0.mac, self.0.addr), (mac, dest), buf))?
+        {
+            warn!("{dest} dropped: no free frame")
+        }
+        Ok(())
+    }
+}
+
+impl<B: Buf> SendMessage<IterAddr<'_, SocketAddr>, B> for Xdp {
+    fn send(&mut self, dest: IterAddr<'_, SocketAddr>, buf: B) -> anyhow::Result<()> {
+        for addr in dest.0 {
+            self.send(addr, buf.clone())?
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::process::Command;
+
+    use bytes::Bytes;
+    use tokio::sync::mpsc::unbounded_channel;
+
+    use super::*;
+
+    #[test]
+    fn ip_checksum() {
+        // a known header with its checksum 0xb861 zeroed
+        let mut header = [
+            0x45, 0x00, 0x00, 0x73, 0x00, 0x00, 0x40, 0x00, 0x40, 0x11, 0x00, 0x00, 0xc0, 0xa8,
+            0x00, 0x01, 0xc0, 0xa8, 0x00, 0xc7,
+        ];
+        assert_eq!(checksum(&header), 0xb861);
+        header[10..12].copy_from_slice(&0xb861u16.to_be_bytes());
+        assert_eq!(checksum(&header), 0)
+    }
+
+    #[test]
+    fn mac() {
+        assert_eq!(
+            parse_mac("02:00:5e:10:0a:FF"),
+            Some([0x02, 0x00, 0x5e, 0x10, 0x0a, 0xff])
+        );
+        assert_eq!(parse_mac("02:00:5e:10:0a"), None);
+        assert_eq!(parse_mac("02:00:5e:10:0a:ff:01"), None);
+        assert_eq!(parse_mac("02:00:5e:10:0a:fg"), None)
+    }
+
+    #[test]
+    fn frame() {
+        let src = (
+            [2, 0, 0, 0, 0, 1],
+            SocketAddrV4::new([10, 0, 0, 1].into(), 4000),
+        );
+        let dest = (
+            [2, 0, 0, 0, 0, 2],
+            SocketAddrV4::new([10, 0, 0, 2].into(), 5000),
+        );
+        let mut frame = vec![0; 2048];
+        let len = write_frame(&mut frame, src, dest, b"hello");
+        let frame = &frame[..len];
+        assert_eq!(len, HEADERS_LEN + 5);
+        assert_eq!(frame[..6], dest.0);
+        assert_eq!(frame[6..12], src.0);
+        assert_eq!(checksum(&frame[ETHERNET_HEADER_LEN..][..IP_HEADER_LEN]), 0);
+        assert_eq!(payload(frame, dest.1), Some((src.1, &b"hello"[..])));
+
+        // not to the address
+        assert_eq!(payload(frame, src.1), None);
+        assert_eq!(
+            payload(frame, SocketAddrV4::new([10, 0, 0, 3].into(), 5000)),
+            None
+        );
+        assert_eq!(
+            payload(frame, SocketAddrV4::new([10, 0, 0, 2].into(), 5001)),
+            None
+        );
+        // truncated
+        assert_eq!(payload(&frame[..len - 1], dest.1), None);
+        // fragmented
+        let mut fragment = frame.to_vec();
+        fragment[20] |= 0x20;
+        assert_eq!(payload(&fragment, dest.1), None)
+    }
+
+    struct Veth;
+
+    impl Veth {
+        fn new() -> anyhow::Result<Self> {
+            let veth = Self;
+            for args in [
+                "link add xdp-test0 type veth peer name xdp-test1",
+                "addr add 10.111.0.1/24 dev xdp-test0",
+                "addr add 10.111.0.2/24 dev xdp-test1",
+                "link set xdp-test0 up",
+                "link set xdp-test1 up",
+            ] {
+                anyhow::ensure!(Command::new("ip")
+                    .args(args.split_whitespace())
+                    .status()?
+                    .success())
+            }
+            Ok(veth)
+        }
+    }
+
+    impl Drop for Veth {
+        fn drop(&mut self) {
+            let _ = Command::new("ip")
+                .args(["link", "del", "xdp-test0"])
+                .status();
+        }
+    }
+
+    // requires root and iproute2
+    #[tokio::test]
+    #[ignore]
+    async fn veth() -> anyhow::Result<()> {
+        let _veth = Veth::new()?;
+        let addrs = [
+            SocketAddrV4::new([10, 111, 0, 1].into(), 5000),
+            SocketAddrV4::new([10, 111, 0, 2].into(), 5000),
+        ];
+        let nets = [
+            Xdp::bind("xdp-test0", addrs[0])?,
+            Xdp::bind("xdp-test1", addrs[1])?,
+        ];
+        // both ends are local addresses, so the kernel does not resolve them through the link
+        for (net, (addr, interface)) in nets
+            .iter()
+            .zip(addrs.iter().rev().zip(["xdp-test1", "xdp-test0"]))
+        {
+            net.add_neighbor(
+                *addr.ip(),
+                parse_mac(&interface_attr(interface, "address")?).unwrap(),
+            )
+        }
+
+        let (sender, mut receiver) = unbounded_channel();
+        let net = nets[1].clone();
+        tokio::spawn(async move {
+            net.recv_session(|buf| Ok(sender.send(Bytes::copy_from_slice(buf))?))
+                .await
+        });
+        let mut net = nets[0].clone();
+        for i in 0..10u8 {
+            net.send(
+                SocketAddr::V4(addrs[1]),
+                Bytes::from(vec![i; i as usize * 100]),
+            )?
+        }
+        for i in 0..10u8 {
+            assert_eq!(receiver.recv().await.unwrap(), vec![i; i as usize * 100])
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,399 @@
+// AF_XDP socket
+//
+// the socket exchanges frames with the kernel through a memory area that is
+// shared with it (UMEM) and four single-producer single-consumer rings: the
+// free frames are given to the kernel through the fill ring and come back
+// through the receive ring with packets in them, and the outgoing frames are
+// given through the transmit ring and come back through the completion ring
+// after they are sent. the UMEM is split in half, one for receiving and one for
+// sending, so the two directions never wait for each other, and each direction
+// is guarded by its own lock
+//
+// no flag is given at binding, so the kernel goes zero-copy if the driver
+// supports it and copies otherwise, which is always the case for the generic
+// XDP mode. the need-wakeup optimization is not enabled, so every batch of
+// sending is followed by a `sendto` kick
+
+use std::{
+    io,
+    mem::size_of,
+    os::fd::{AsFd, AsRawFd as _, BorrowedFd, FromRawFd as _, OwnedFd},
+    ptr::null_mut,
+    sync::{
+        atomic::{AtomicU32, Ordering},
+        Mutex,
+    },
+};
+
+const SOL_XDP: libc::c_int = 283;
+const XDP_MMAP_OFFSETS: libc::c_int = 1;
+const XDP_RX_RING: libc::c_int = 2;
+const XDP_TX_RING: libc::c_int = 3;
+const XDP_UMEM_REG: libc::c_int = 4;
+const XDP_UMEM_FILL_RING: libc::c_int = 5;
+const XDP_UMEM_COMPLETION_RING: libc::c_int = 6;
+
+const XDP_PGOFF_RX_RING: libc::off_t = 0;
+const XDP_PGOFF_TX_RING: libc::off_t = 0x80000000;
+const XDP_UMEM_PGOFF_FILL_RING: libc::off_t = 0x100000000;
+const XDP_UMEM_PGOFF_COMPLETION_RING: libc::off_t = 0x180000000;
+
+pub const FRAME_SIZE: usize = 4096;
+const NUM_FRAMES: usize = 4096;
+// for each of the four rings, and each direction owns this many frames
+const RING_SIZE: u32 = NUM_FRAMES as u32 / 2;
+
+#[repr(C)]
+#[derive(Default)]
+struct UmemReg {
+    addr: u64,
+    len: u64,
+    chunk_size: u32,
+    headroom: u32,
+    flags: u32,
+    tx_metadata_len: u32,
+}
+
+#[repr(C)]
+#[derive(Default)]
+struct RingOffset {
+    producer: u64,
+    consumer: u64,
+    desc: u64,
+    flags: u64,
+}
+
+#[repr(C)]
+#[derive(Default)]
+struct MmapOffsets {
+    rx: RingOffset,
+    tx: RingOffset,
+    fr: RingOffset,
+    cr: RingOffset,
+}
+
+#[repr(C)]
+struct SockaddrXdp {
+    family: u16,
+    flags: u16,
+    ifindex: u32,
+    queue_id: u32,
+    shared_umem_fd: u32,
+}
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Default)]
+struct Desc {
+    addr: u64,
+    len: u32,
+    options: u32,
+}
+
+fn check(result: libc::c_int) -> io::Result<()> {
+    if result < 0 {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(())
+    }
+}
+
+fn set_option<T>(fd: BorrowedFd<'_>, name: libc::c_int, value: &T) -> io::Result<()> {
+    check(unsafe {
+        libc::setsockopt(
+            fd.as_raw_fd(),
+            SOL_XDP,
+            name,
+            value as *const T as _,
+            size_of::<T>() as _,
+        )
+    })
+}
+
+#[derive(Debug)]
+struct Mmap(*mut libc::c_void, usize);
+
+// the mapped memory is only accessed through the rings and the frames that are owned according to
+// the rings, under the locks of `Socket`
+unsafe impl Send for Mmap {}
+unsafe impl Sync for Mmap {}
+
+impl Mmap {
+    fn new(fd: Option<BorrowedFd<'_>>, len: usize, offset: libc::off_t) -> io::Result<Self> {
+        let (fd, flags) = if let Some(fd) = fd {
+            (fd.as_raw_fd(), libc::MAP_SHARED | libc::MAP_POPULATE)
+        } else {
+            (
+                -1,
+                libc::MAP_PRIVATE | libc::MAP_ANONYMOUS | libc::MAP_POPULATE,
+            )
+        };
+        let addr = unsafe {
+            libc::mmap(
+                null_mut(),
+                len,
+                libc::PROT_READ | libc::PROT_WRITE,
+                flags,
+                fd,
+                offset,
+            )
+        };
+        if addr == libc::MAP_FAILED {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(Self(addr, len))
+    }
+}
+
+impl Drop for Mmap {
+    fn drop(&mut self) {
+        unsafe { libc::munmap(self.0, self.1) };
+    }
+}
+
+#[derive(Debug)]
+struct Ring<T> {
+    _map: Mmap,
+    producer: *const AtomicU32,
+    consumer: *const AtomicU32,
+    descs: *mut T,
+}
+
+unsafe impl<T> Send for Ring<T> {}
+unsafe impl<T> Sync for Ring<T> {}
+
+impl<T: Copy> Ring<T> {
+    fn new(fd: BorrowedFd<'_>, offset: &RingOffset, pgoff: libc::off_t) -> io::Result<Self> {
+        let map = Mmap::new(
+            Some(fd),
+            offset.desc as usize + RING_SIZE as usize * size_of::<T>(),
+            pgoff,
+        )?;
+        let at = |offset| unsafe { map.0.cast::<u8>().add(offset as _) };
+        Ok(Self {
+            producer: at(offset.producer).cast(),
+            consumer: at(offset.consumer).cast(),
+            descs: at(offset.desc).cast(),
+            _map: map,
+        })
+    }
+
+    fn producer(&self) -> &AtomicU32 {
+        unsafe { &*self.producer }
+    }
+
+    fn consumer(&self) -> &AtomicU32 {
+        unsafe { &*self.consumer }
+    }
+
+    // as the producer, return the number of the items that are produced
+    fn produce(&mut self, items: impl IntoIterator<Item = T>) -> usize {
+        let producer = self.producer().load(Ordering::Relaxed);
+        let free = RING_SIZE - producer.wrapping_sub(self.consumer().load(Ordering::Acquire));
+        let mut count = 0;
+        for item in items.into_iter().take(free as _) {
+            let index = producer.wrapping_add(count) & (RING_SIZE - 1);
+            unsafe { self.descs.add(index as _).write(item) }
+            count += 1
+        }
+        self.producer()
+            .store(producer.wrapping_add(count), Ordering::Release);
+        count as _
+    }
+
+    // as the consumer
+    fn consume(&mut self, items: &mut Vec<T>) {
+        let consumer = self.consumer().load(Ordering::Relaxed);
+        let available = self
+            .producer()
+            .load(Ordering::Acquire)
+            .wrapping_sub(consumer);
+        for i in 0..available {
+            let index = consumer.wrapping_add(i) & (RING_SIZE - 1);
+            items.push(unsafe { self.descs.add(index as _).read() })
+        }
+        self.consumer()
+            .store(consumer.wrapping_add(available), Ordering::Release)
+    }
+}
+
+#[derive(Debug)]
+struct Receiving {
+    rx: Ring<Desc>,
+    fill: Ring<u64>,
+    descs: Vec<Desc>,
+}
+
+#[derive(Debug)]
+struct Sending {
+    tx: Ring<Desc>,
+    completion: Ring<u64>,
+    free: Vec<u64>,
+}
+
+#[derive(Debug)]
+pub struct Socket {
+    // the rings and the UMEM are unmapped before the socket is closed
+    receiving: Mutex<Receiving>,
+    sending: Mutex<Sending>,
+    umem: Mmap,
+    fd: OwnedFd,
+}
+
+impl AsFd for Socket {
+    fn as_fd(&self) -> BorrowedFd<'_> {
+        self.fd.as_fd()
+    }
+}
+
+impl Socket {
+    pub fn bind(ifindex: u32, queue_id: u32) -> anyhow::Result<Self> {
+        let fd = unsafe { libc::socket(libc::AF_XDP, libc::SOCK_RAW | libc::SOCK_CLOEXEC, 0) };
+        check(fd)?;
+        let fd = unsafe { OwnedFd::from_raw_fd(fd) };
+        let umem = Mmap::new(None, NUM_FRAMES * FRAME_SIZE, 0)?;
+        set_option(
+            fd.as_fd(),
+            XDP_UMEM_REG,
+            &UmemReg {
+                addr: umem.0 as _,
+                len: umem.1 as _,
+                chunk_size: FRAME_SIZE as _,
+                ..Default::default()
+            },
+        )?;
+        for name in [
+            XDP_UMEM_FILL_RING,
+            XDP_UMEM_COMPLETION_RING,
+            XDP_RX_RING,
+            XDP_TX_RING,
+        ] {
+            set_option(fd.as_fd(), name, &RING_SIZE)?
+        }
+        let mut offsets = MmapOffsets::default();
+        let mut len = size_of::<MmapOffsets>() as libc::socklen_t;
+        check(unsafe {
+            libc::getsockopt(
+                fd.as_raw_fd(),
+                SOL_XDP,
+                XDP_MMAP_OFFSETS,
+                &mut offsets as *mut _ as _,
+                &mut len,
+            )
+        })?;
+        let mut fill = Ring::new(fd.as_fd(), &offsets.fr, XDP_UMEM_PGOFF_FILL_RING)?;
+        let completion = Ring::new(fd.as_fd(), &offsets.cr, XDP_UMEM_PGOFF_COMPLETION_RING)?;
+        let rx = Ring::new(fd.as_fd(), &offsets.rx, XDP_PGOFF_RX_RING)?;
+        let tx = Ring::new(fd.as_fd(), &offsets.tx, XDP_PGOFF_TX_RING)?;
+        let frames = (0..NUM_FRAMES as u64).map(|i| i * FRAME_SIZE as u64);
+        let (receive_frames, send_frames) =
+            frames.partition::<Vec<_>, _>(|addr| *addr < (NUM_FRAMES / 2 * FRAME_SIZE) as u64);
+        let count = fill.produce(receive_frames);
+        assert_eq!(count, RING_SIZE as usize);
+
+        let addr = SockaddrXdp {
+            family: libc::AF_XDP as _,
+            flags: 0,
+            ifindex,
+            queue_id,
+            shared_umem_fd: 0,
+        };
+        check(unsafe {
+            libc::bind(
+                fd.as_raw_fd(),
+                &addr as *const _ as _,
+                size_of::<SockaddrXdp>() as _,
+            )
+        })?;
+        Ok(Self {
+            receiving: Mutex::new(Receiving {
+                rx,
+                fill,
+                descs: Default::default(),
+            }),
+            sending: Mutex::new(Sending {
+                tx,
+                completion,
+                free: send_frames,
+            }),
+            umem,
+            fd,
+        })
+    }
+
+    fn frame(&self, addr: u64, len: usize) -> *mut u8 {
+        assert!(addr as usize + len <= self.umem.1);
+        unsafe { self.umem.0.cast::<u8>().add(addr as _) }
+    }
+
+    // call `on_frame` with every received frame, and return how many there are
+    pub fn recv(&self, mut on_frame: impl FnMut(&[u8])) -> usize {
+        let mut receiving = self.receiving.lock().unwrap();
+        let Receiving { rx, fill, descs } = &mut *receiving;
+        rx.consume(descs);
+        for desc in &*descs {
+            // the frame is owned by this side until it is given back through the fill ring
+            on_frame(unsafe {
+                std::slice::from_raw_parts(self.frame(desc.addr, desc.len as _), desc.len as _)
+            })
+        }
+        let count = descs.len();
+        // in the aligned mode the kernel takes any address within a frame as the frame
+        let count_filled = fill.produce(descs.drain(..).map(|desc| desc.addr));
+        // the fill ring holds all the receiving frames, so there's always room for them
+        assert_eq!(count_filled, count);
+        count
+    }
+
+    // `write` writes a packet into the given frame and returns its length. return false if there's
+    // no free frame, i.e. the kernel falls behind
+    pub fn send(&self, write: impl FnOnce(&mut [u8]) -> usize) -> anyhow::Result<bool> {
+        let mut sending = self.sending.lock().unwrap();
+        let Sending {
+            tx,
+            completion,
+            free,
+        } = &mut *sending;
+        completion.consume(free);
+        let Some(addr) = free.pop() else {
+            // the frames may be stuck in the transmit ring if the last kick is not effective
+            self.kick()?;
+            return Ok(false);
+        };
+        let len = write(unsafe {
+            std::slice::from_raw_parts_mut(self.frame(addr, FRAME_SIZE), FRAME_SIZE)
+        });
+        let count = tx.produce([Desc {
+            addr,
+            len: len as _,
+            options: 0,
+        }]);
+        // the transmit ring is as large as the sending frames
+        assert_eq!(count, 1);
+        self.kick()?;
+        Ok(true)
+    }
+
+    fn kick(&self) -> io::Result<()> {
+        let result = unsafe {
+            libc::sendto(
+                self.fd.as_raw_fd(),
+                null_mut(),
+                0,
+                libc::MSG_DONTWAIT,
+                null_mut(),
+                0,
+            )
+        };
+        if result < 0 {
+            let err = io::Error::last_os_error();
+            // the kernel is busy with the previous kicks, and will get to the frames later
+            if !matches!(
+                err.raw_os_error(),
+                Some(libc::EAGAIN | libc::EBUSY | libc::ENOBUFS | libc::ENETDOWN)
+            ) {
+                return Err(err);
+            }
+        }
+        Ok(())
+    }
+}
@@ -16,7 +16,9 @@
 use std::{
     collections::HashSet,
     env::args,
+    fs::File,
     future::{pending, Future},
+    io::{BufReader, BufWriter},
     iter::repeat_with,
     net::SocketAddr,
     time::Duration,
@@ -28,11 +30,13 @@ use augustus::{
         blocking,
         erased::{self, events::Init, Blanket},
         ordered::Timer,
-        Inline, OnTimer, SendEvent as _, Session, Unify, UnreachableTimer,
+        record::{replay, Recorded},
+        Inline, OnTimer, SendEvent as _, Session, Unify, UnreachableTimer, Void,
     },
     net::{
         session::{
-            quic_accept_session, simplex, tcp_accept_session, Dispatch, DispatchNet, Quic, Tcp, Udp,
+            quic_accept_session, simplex, tcp_accept_session, Dispatch, DispatchNet, Quic,
+            QuicConfig, QuicMode, Tcp, Udp,
         },
         IndexNet,
     },
@@ -74,6 +78,11 @@ async fn main() -> anyhow::Result<()> {
     let flag_blocking = args.remove("blocking");
     let flag_dual = args.remove("dual");
     let flag_quic = args.remove("quic");
+    let flag_record = args.remove("record");
+    let flag_replay = args.remove("replay");
+    let flag_batch = args.remove("batch");
+    let flag_datagram = args.remove("datagram");
+    let flag_stream = args.remove("stream");
     if !args.is_empty() {
         anyhow::bail!("unknown arguments {args:?}")
     }
@@ -83,9 +92,29 @@ async fn main() -> anyhow::Result<()> {
         || (flag_tcp || flag_quic) && (flag_blocking || flag_dyn)
         || flag_dual && !flag_blocking
         || flag_simplex && !flag_tcp
+        || flag_record && (flag_client || flag_tcp || flag_quic || flag_blocking || flag_dyn)
+        || flag_replay && (flag_client || flag_record)
+        || flag_batch
+            && (flag_client || flag_tcp || flag_quic || flag_blocking || flag_dyn || flag_record)
+        || (flag_datagram || flag_stream) && !flag_quic
+        || flag_datagram && flag_stream
     {
         anyhow::bail!("invalid argument combination")
     }
+    let quic_config = QuicConfig::default().with_mode(if flag_datagram {
+        QuicMode::Datagram
+    } else if flag_stream {
+        QuicMode::Stream
+    } else {
+        QuicMode::UniStream
+    });
+
+    if flag_replay {
+        let mut state = Unify(Replica::new(Null, ToClientMessageNet::new(Void)));
+        replay::<ReplicaEvent<SocketAddr>>(BufReader::new(File::open("replica.log")?), &mut state)?;
+        println!("Replay finished");
+        return Ok(());
+    }
 
     if flag_client {
         let replica_addrs = vec![replica_addr];
@@ -175,7 +204,7 @@ async fn main() -> anyhow::Result<()> {
                 } else if flag_quic {
                     let mut quic_session = erased::Session::new();
                     let raw_net = DispatchNet(erased::session::Sender::from(quic_session.sender()));
-                    let quic = Quic::new(client_addr)?;
+                    let quic = Quic::with_config(client_addr, quic_config.clone())?;
                     let mut state = Unify(Client::new(
                         id,
                         quic.0.local_addr()?,
@@ -365,7 +394,7 @@ async fn main() -> anyhow::Result<()> {
         let mut state = Unify(Replica::new(Null, ToClientMessageNet::new(raw_net)));
         let mut state_session = Session::new();
         let mut state_sender = state_session.sender();
-        let quic = Quic::new(replica_addr)?;
+        let quic = Quic::with_config(replica_addr, quic_config)?;
         let mut quic_control = Blanket(erased::Unify(Dispatch::new(
             quic.clone(),
             move |buf: &_| to_replica_on_buf(buf, &mut state_sender),
@@ -390,6 +419,28 @@ async fn main() -> anyhow::Result<()> {
         .await;
     }
 
+    if flag_batch {
+        println!("Starting replica with batched UDP");
+        let raw_net = augustus::net::batch::Udp::new(UdpSocket::bind(replica_addr).await?);
+        let mut state = Unify(Replica::new(Null, ToClientMessageNet::new(raw_net.clone())));
+        let mut state_session = Session::<unreplicated::ReplicaEvent<_>>::new();
+        let mut state_sender = state_session.sender();
+        let recv_session =
+            raw_net.recv_session(move |buf| to_replica_on_buf(buf, &mut state_sender));
+        let flush_session = raw_net.flush_session();
+        let state_session = state_session.run(&mut state);
+        return run(
+            async {
+                tokio::select! {
+                    result = recv_session => result,
+                    result = flush_session => result,
+                }
+            },
+            state_session,
+        )
+        .await;
+    }
+
     let socket = UdpSocket::bind(replica_addr).await?;
     let raw_net = Udp(socket.into());
     let net = ToClientMessageNet::new(raw_net.clone());
@@ -403,6 +454,18 @@ async fn main() -> anyhow::Result<()> {
         });
         let state_session = state_session.run(&mut state);
         run(recv_session, state_session).await
+    } else if flag_record {
+        println!("Starting replica with events recorded into replica.log");
+        let mut state = Recorded::new(
+            Unify(Replica::new(Null, net)),
+            BufWriter::new(File::create("replica.log")?),
+        );
+        let mut state_session = Session::<unreplicated::ReplicaEvent<_>>::new();
+        let mut state_sender = state_session.sender();
+        let recv_session =
+            raw_net.recv_session(move |buf| to_replica_on_buf(buf, &mut state_sender));
+        let state_session = state_session.run(&mut state);
+        run(recv_session, state_session).await
     } else {
         let mut state = Unify(Replica::new(Null, net));
         let mut state_session = Session::<unreplicated::ReplicaEvent<_>>::new();
@@ -104,7 +104,7 @@ fn main() -> anyhow::Result<()> {
         state.push_client(Recorded::from(Iter(
             (0..3)
                 .map(move |x| (Op::Append(String::from("foo"), x.to_string())))
-                .map(|op| Ok(Payload(serde_json::to_vec(&op)?)))
+                .map(|op| Ok(Payload::from(serde_json::to_vec(&op)?)))
                 .collect::<anyhow::Result<Vec<_>>>()?
                 .into_iter(),
         )))?
@@ -1,43 +1,37 @@
-use std::{env::args, net::SocketAddr};
+use std::env::args;
 
 use augustus::{
-    crypto::peer::{Crypto, Verifiable},
-    event::{
-        erased::{
-            session::{Buffered, Sender},
-            Blanket, Session,
-        },
-        SendEvent as _,
+    crypto::peer::Crypto,
+    event::erased::{
+        session::{Buffered, Sender},
+        Blanket, Session,
     },
-    kademlia::{Buckets, FindPeer, FindPeerOk, Peer, PeerId, PeerRecord},
+    kademlia::{self, Buckets, Peer, PeerId, PeerRecord},
     net::{
-        events::Recv,
+        deserialize,
         kademlia::{Control, Multicast, PeerNet},
+        mux::{Channel, ChannelId, Demux},
         session::Udp,
-        SendMessage,
+        MessageNet, SendMessage,
     },
     worker::erased::Worker,
 };
-use bincode::Options;
 use primitive_types::H256;
 use rand::{rngs::StdRng, thread_rng, SeedableRng};
 use serde::{Deserialize, Serialize};
 use tokio::{net::UdpSocket, spawn};
 use tokio_util::sync::CancellationToken;
 
-#[allow(clippy::large_enum_variant)]
-#[derive(Debug, Clone, Serialize, Deserialize, derive_more::From)]
+// the Kademlia messages go through their own channel, so this only covers the application
+#[derive(Debug, Clone, Serialize, Deserialize)]
 enum Message {
-    FindPeer(Verifiable<FindPeer<SocketAddr>>),
-    FindPeerOk(Verifiable<FindPeerOk<SocketAddr>>),
-    #[from(ignore)]
     Hello(PeerId),
     HelloOk,
-    #[from(ignore)]
     Join(PeerId),
 }
 
-type MessageNet<T> = augustus::net::MessageNet<T, Message>;
+const KADEMLIA_CHANNEL: ChannelId = 0;
+const HELLO_CHANNEL: ChannelId = 1;
 
 #[tokio::main(flavor = "current_thread")]
 async fn main() -> anyhow::Result<()> {
@@ -66,7 +60,7 @@ async fn main() -> anyhow::Result<()> {
         buckets.insert(seed_peer)?;
         peer = Peer::new(
             buckets,
-            MessageNet::new(socket_net.clone()),
+            kademlia::MessageNet::new(Channel::new(socket_net.clone(), KADEMLIA_CHANNEL)),
             Sender::from(control_session.sender()),
             Worker::new_inline(crypto, Box::new(Sender::from(peer_session.sender()))),
         );
@@ -83,7 +77,7 @@ async fn main() -> anyhow::Result<()> {
         let buckets = Buckets::new(peer_record);
         peer = Peer::new(
             buckets,
-            MessageNet::new(socket_net.clone()),
+            kademlia::MessageNet::new(Channel::new(socket_net.clone(), KADEMLIA_CHANNEL)),
             Sender::from(control_session.sender()),
             Worker::new_inline(seed_crypto, Box::new(Sender::from(peer_session.sender()))),
         );
@@ -103,33 +97,33 @@ async fn main() -> anyhow::Result<()> {
     });
 
     let mut peer_sender = Sender::from(peer_session.sender());
-    let socket_session = socket_net.recv_session(|buf| {
-        let message = bincode::options()
-            .allow_trailing_bytes()
-            .deserialize::<Message>(buf)?;
-        match message {
-            Message::FindPeer(message) => peer_sender.send(Recv(message))?,
-            Message::FindPeerOk(message) => peer_sender.send(Recv(message))?,
-            Message::Hello(peer_id) => {
-                println!("Replying Hello from {}", H256(peer_id));
-                peer_net.send(peer_id, Message::HelloOk)?;
-                peer_net.send(
-                    Multicast(peer_id, 3.try_into().unwrap()),
-                    Message::Join(peer_id),
-                )?
+    let mut demux = Demux::new()
+        .with_channel(KADEMLIA_CHANNEL, move |buf| {
+            kademlia::on_buf(buf, &mut peer_sender)
+        })
+        .with_channel(HELLO_CHANNEL, move |buf| {
+            match deserialize(buf)? {
+                Message::Hello(peer_id) => {
+                    println!("Replying Hello from {}", H256(peer_id));
+                    peer_net.send(peer_id, Message::HelloOk)?;
+                    peer_net.send(
+                        Multicast(peer_id, 3.try_into().unwrap()),
+                        Message::Join(peer_id),
+                    )?
+                }
+                Message::HelloOk => {
+                    println!("Received HelloOk")
+                }
+                Message::Join(peer_id) => {
+                    println!("Joining peer {}", H256(peer_id))
+                }
             }
-            Message::HelloOk => {
-                println!("Received HelloOk")
-            }
-            Message::Join(peer_id) => {
-                println!("Joining peer {}", H256(peer_id))
-            }
-        }
-        Ok(())
-    });
+            Ok(())
+        });
+    let socket_session = socket_net.recv_session(|buf| demux.on_buf(buf));
 
     let mut control = Blanket(Buffered::from(Control::new(
-        augustus::net::MessageNet::<_, Message>::new(socket_net.clone()),
+        MessageNet::<_, Message>::new(Channel::new(socket_net.clone(), HELLO_CHANNEL)),
         Sender::from(peer_session.sender()),
     )));
     let mut peer = Blanket(Buffered::from(peer));
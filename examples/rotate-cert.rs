@@ -1,6 +1,57 @@
-use std::{fs::write, path::Path};
+// provision QUIC identities
+//
+//   rotate-cert
+// regenerates the testbed CA embedded in the source code
+//   rotate-cert <dir> <addr>...
+// issues a key and a certificate for every node address into <dir>, which can
+// be loaded with `QuicIdentity::load`. the CA is created in <dir> on the first
+// run and reused afterwards, so rotating the nodes' certificates does not need
+// to touch the others. distribute `ca.der` and the node's own two files to each
+// node, and keep `ca.key.pem` on this machine
+use std::{
+    env::args,
+    fs::{create_dir_all, read_to_string, write},
+    net::SocketAddr,
+    path::Path,
+};
+
+use augustus::net::session::{quic_ca, quic_server_name, QuicIdentity};
+use primitive_types::H256;
 
 fn main() -> anyhow::Result<()> {
+    let mut args = args().skip(1);
+    let Some(dir) = args.next() else {
+        return rotate_testbed();
+    };
+    let dir = Path::new(&dir);
+    create_dir_all(dir)?;
+
+    let ca_key_path = dir.join("ca.key.pem");
+    let ca_key = if ca_key_path.exists() {
+        rcgen::KeyPair::from_pem(&read_to_string(&ca_key_path)?)?
+    } else {
+        let ca_key = rcgen::KeyPair::generate()?;
+        write(&ca_key_path, ca_key.serialize_pem())?;
+        ca_key
+    };
+    // the CA certificate is simply reissued. the certificates issued earlier keep verifying
+    // against it since the key and the name stay the same
+    let ca = quic_ca(&ca_key)?;
+    write(dir.join("ca.der"), ca.der())?;
+
+    for addr in args {
+        let addr = addr.parse::<SocketAddr>()?;
+        let name = quic_server_name(addr);
+        let identity =
+            QuicIdentity::issue(&ca, &ca_key, rcgen::KeyPair::generate()?, name.clone())?;
+        write(dir.join(format!("{name}.cert.der")), identity.cert_der())?;
+        write(dir.join(format!("{name}.key.der")), identity.key_der())?;
+        println!("{addr} {name} {:?}", H256(identity.peer().0))
+    }
+    Ok(())
+}
+
+fn rotate_testbed() -> anyhow::Result<()> {
     let certified_key = rcgen::generate_simple_self_signed(vec!["neatworks.quic".into()])?;
     write(
         Path::new(env!("CARGO_MANIFEST_DIR")).join("src/cert.pem"),
@@ -41,25 +41,7 @@ impl App for BTreeMap {
             Op::Scan(key, count) => Result::ScanOk(
                 self.0
                     .range(key..)
-                    // .map(|(_, values)| values.clone())
-                    // whole string is too long to be sent over UDP packets, so reduce each field
-                    // into single checksum letter
-                    // 1000 max scan length * (10 field * 1 byte per field + 1 byte length) = 11K
-                    // roughly 7 * MTU, probably acceptable
-                    .map(|(_, values)| {
-                        values
-                            .iter()
-                            .map(|value| {
-                                use std::hash::{BuildHasher, BuildHasherDefault};
-                                format!(
-                                    "{:x}",
-                                    BuildHasherDefault::<rustc_hash::FxHasher>::default()
-                                        .hash_one(value)
-                                        & 0xf
-                                )
-                            })
-                            .collect()
-                    })
+                    .map(|(_, values)| values.clone())
                     .take(count)
                     .collect(),
             ),
@@ -67,8 +67,8 @@ pub fn static_workload(
         rounds
             .map(|(op, result)| {
                 Ok((
-                    Payload(serde_json::to_vec(&op)?),
-                    Payload(serde_json::to_vec(&result)?),
+                    Payload::from(serde_json::to_vec(&op)?),
+                    Payload::from(serde_json::to_vec(&result)?),
                 ))
             })
             .collect::<anyhow::Result<Vec<_>>>()?
@@ -123,8 +123,8 @@ impl Iterator for InfinitePutGet {
         };
         self.should_get = !self.should_get;
         Some((
-            Payload(serde_json::to_vec(&op).unwrap()),
-            Payload(serde_json::to_vec(&result).unwrap()),
+            Payload::from(serde_json::to_vec(&op).unwrap()),
+            Payload::from(serde_json::to_vec(&result).unwrap()),
         ))
     }
 }
@@ -98,17 +98,7 @@ impl App for Sqlite {
                 let mut records = Vec::new();
                 while let Some(row) = rows.next()? {
                     let record = (0..self.field_count)
-                        // .map(|i| row.get(i))
-                        // ration explained in `btree`
-                        .map(|i| {
-                            use std::hash::{BuildHasher, BuildHasherDefault};
-                            Ok(format!(
-                                "{:x}",
-                                BuildHasherDefault::<rustc_hash::FxHasher>::default()
-                                    .hash_one(row.get::<_, String>(i)?)
-                                    & 0xf
-                            ))
-                        })
+                        .map(|i| row.get(i))
                         .collect::<rusqlite::Result<Vec<_>>>()?;
                     records.push(record)
                 }
@@ -370,7 +370,7 @@ impl<R: Rng> Workload<R> {
     pub fn startup_ops(&mut self) -> impl Iterator<Item = Payload> + '_ {
         let record_count = self.settings.record_count;
         repeat_with(|| {
-            Payload(
+            Payload::from(
                 bincode::options()
                     .serialize(&self.startup_insert())
                     .unwrap(),
@@ -436,7 +436,7 @@ impl<R: Rng> crate::workload::Workload for Workload<R> {
         };
         Ok(if let Some(op) = op {
             Some((
-                Payload(bincode::options().serialize(&op)?),
+                Payload::from(bincode::options().serialize(&op)?),
                 if matches!(op, Op::Insert(..)) {
                     Some(key_num)
                 } else {
@@ -30,6 +30,7 @@
 
 use std::{
     collections::HashMap,
+    hash::Hash,
     net::{IpAddr, SocketAddr},
     time::Duration,
 };
@@ -38,7 +39,7 @@ use bytes::Bytes;
 use serde::{Deserialize, Serialize};
 use tokio::{
     io::{AsyncReadExt as _, AsyncWriteExt as _},
-    net::{TcpListener, TcpStream},
+    net::TcpStream,
     sync::mpsc::UnboundedReceiver,
     task::JoinSet,
     time::sleep,
@@ -48,7 +49,7 @@ use tracing::info;
 
 use crate::{
     event::SendEvent,
-    net::{events::Recv, SendMessage},
+    net::{events::Recv, session::bind_tcp_listener, shape::Shaper, SendMessage},
 };
 
 pub struct Offer<A, M> {
@@ -161,13 +162,32 @@ impl<T: Service<A, M, N> + ?Sized, A, M, N> ServiceExt<A, M, N> for T {
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Serve<M>(M, SocketAddr, u32);
 
-pub async fn session<A, M, N: Send + 'static>(
+pub async fn session<A: Clone + Eq + Hash + Send + Sync + 'static, M, N: Send + 'static>(
+    ip: impl Into<Option<IpAddr>>,
+    events: UnboundedReceiver<Event<A, M, N>>,
+    net: impl SendMessage<A, Serve<M>>,
+    upcall: impl SendEvent<RecvOffer<M>> + SendEvent<N>,
+) -> anyhow::Result<()> {
+    shaped_session(ip, events, net, upcall, Shaper::default(), A::clone).await
+}
+
+// the bulk data is written to the accepting side through `shaper`, under the limits of the `key`
+// of the offer's destination, see `Shaped`. the `Serve` messages go through `net`, which can be
+// shaped on its own
+pub async fn shaped_session<
+    A: Clone + Eq + Hash + Send + Sync + 'static,
+    K: Clone + Eq + Hash + Send + Sync + 'static,
+    M,
+    N: Send + 'static,
+>(
     ip: impl Into<Option<IpAddr>>,
     mut events: UnboundedReceiver<Event<A, M, N>>,
     mut net: impl SendMessage<A, Serve<M>>,
     mut upcall: impl SendEvent<RecvOffer<M>> + SendEvent<N>,
+    shaper: Shaper<K>,
+    key: impl Fn(&A) -> K,
 ) -> anyhow::Result<()> {
-    let listener = TcpListener::bind((ip.into().unwrap_or(IpAddr::from([0, 0, 0, 0])), 0)).await?;
+    let listener = bind_tcp_listener(ip, 0)?;
     let addr = listener.local_addr()?;
     let mut id = 0;
     let mut pending_accept = HashMap::new();
@@ -192,7 +212,7 @@ pub async fn session<A, M, N: Send + 'static>(
         } {
             Select::Recv(Event::Offer(offer)) => {
                 id += 1;
-                pending_accept.insert(id, (offer.buf, offer.cancel.clone()));
+                pending_accept.insert(id, (offer.dest.clone(), offer.buf, offer.cancel.clone()));
                 cancel_tasks.spawn(async move {
                     if let Some(cancel) = offer.cancel {
                         cancel.cancelled().await
@@ -257,13 +277,15 @@ pub async fn session<A, M, N: Send + 'static>(
                 let Ok(id) = stream.read_u32().await else {
                     continue;
                 };
-                let Some((buf, cancel)) = pending_accept.remove(&id) else {
+                let Some((dest, buf, cancel)) = pending_accept.remove(&id) else {
                     continue; // drop stream to close connection
                 };
                 // technically this does not need to be joined
                 // just as a good habbit and in case needed later
+                let shaper = shaper.clone();
+                let key = key(&dest);
                 send_tasks.spawn(async move {
-                    let task = stream.write_all(&buf);
+                    let task = shaper.write_all(&key, &mut stream, &buf);
                     let result = if let Some(cancel) = cancel {
                         tokio::select! {
                             result = task => result,
@@ -129,6 +129,7 @@ pub enum Signature {
 pub struct Crypto {
     provider: CryptoProvider,
     public_keys: Vec<PublicKey>,
+    batcher: Option<batch::Batcher<Self>>,
 }
 
 #[derive(Debug, Clone)]
@@ -182,6 +183,7 @@ impl Crypto {
                         secret_key: secret_keys[replica_id.into()],
                         secp,
                     }),
+                    batcher: None,
                 }
             }
             CryptoFlavor::Schnorrkel => {
@@ -200,7 +202,9 @@ impl Crypto {
                     provider: CryptoProvider::Schnorrkel(Box::new(peer::Crypto {
                         keypair: secret_keys.remove(replica_id.into()),
                         context: schnorrkel::signing_context(b"default"),
+                        batcher: None,
                     })),
+                    batcher: None,
                 }
             }
         };
@@ -234,16 +238,27 @@ impl Crypto {
         index: impl Into<usize>,
         signed: &Verifiable<M>,
     ) -> anyhow::Result<()> {
-        let Some(public_key) = self.public_keys.get(index.into()) else {
+        let mut state = Sha256::new();
+        DigestHash::hash(&signed.inner, &mut state);
+        self.verify_prehashed(index.into(), state, &signed.signature)
+    }
+
+    fn verify_prehashed(
+        &self,
+        index: usize,
+        state: Sha256,
+        signature: &Signature,
+    ) -> anyhow::Result<()> {
+        let Some(public_key) = self.public_keys.get(index) else {
             anyhow::bail!("no identifier for index")
         };
-        match (&self.provider, public_key, &signed.signature) {
+        match (&self.provider, public_key, signature) {
             (
                 CryptoProvider::Secp256k1(crypto),
                 PublicKey::Secp256k1(public_key),
                 Signature::Secp256k1(signature),
             ) => {
-                let digest = secp256k1::Message::from_digest(signed.inner.sha256());
+                let digest = secp256k1::Message::from_digest(state.finalize().into());
                 crypto.secp.verify_ecdsa(&digest, signature, public_key)?
             }
             // this feels even more monkey patch > <
@@ -251,7 +266,7 @@ impl Crypto {
                 CryptoProvider::Schnorrkel(crypto),
                 PublicKey::Schnorrkel(public_key),
                 Signature::Schnorrkel(signature),
-            ) => crypto.verify_internal(public_key, &signed.inner, signature)?,
+            ) => crypto.verify_prehashed(public_key, state, signature)?,
             _ => anyhow::bail!("unimplemented"),
         }
         Ok(())
@@ -277,6 +292,7 @@ pub mod peer {
     pub struct Crypto {
         pub keypair: Keypair,
         pub context: SigningContext,
+        pub(super) batcher: Option<super::batch::Batcher<Self>>,
     }
 
     impl Debug for Crypto {
@@ -292,6 +308,7 @@ pub mod peer {
             Self {
                 keypair: Keypair::generate_with(rng),
                 context: SigningContext::new(b"default"),
+                batcher: None,
             }
         }
 
@@ -325,6 +342,15 @@ pub mod peer {
         ) -> anyhow::Result<()> {
             let mut state = Sha256::new();
             DigestHash::hash(message, &mut state);
+            self.verify_prehashed(public_key, state, signature)
+        }
+
+        pub fn verify_prehashed(
+            &self,
+            public_key: &PublicKey,
+            state: Sha256,
+            signature: &Signature,
+        ) -> anyhow::Result<()> {
             public_key
                 .verify(self.context.hash256(state), signature)
                 .map_err(anyhow::Error::msg)
@@ -332,6 +358,336 @@ pub mod peer {
     }
 }
 
+// batched signature verification
+// the protocols submit one work per `Verifiable` to the crypto worker, so every signature is
+// verified individually. schnorrkel verifies a batch of signatures notably faster than one by
+// one, so the crypto can have a batcher attached, and the verifications submitted with
+// `Worker::submit_verify` are then accumulated for a short window (or until there are enough of
+// them), and verified in one batch. if the batch fails, i.e. there's at least one invalid
+// signature in it, fall back to individual verifications to figure out the valid ones
+// the verifying is an async work on a spawned worker, so the hashing stays off the event loop
+// as before, and the work continues as the protocol specifies once the batch is done, e.g.
+// sending the same `Verified<_>` events. on an inline worker, or without a batcher attached, it
+// falls back to the plain individual verification
+pub mod batch {
+    use std::{fmt::Debug, time::Duration};
+
+    use sha2::{Digest as _, Sha256};
+    use tokio::{
+        sync::{
+            mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender},
+            oneshot,
+        },
+        time::{timeout_at, Instant},
+    };
+    use tracing::warn;
+
+    use crate::worker::erased::Worker;
+
+    use super::{peer, Crypto, CryptoProvider, DigestHash, PublicKey, Verifiable};
+
+    pub trait BatchVerify: Sized {
+        type Key;
+        type Signature;
+
+        fn verify_prehashed(
+            &self,
+            key: &Self::Key,
+            state: Sha256,
+            signature: &Self::Signature,
+        ) -> anyhow::Result<()>;
+
+        // fails if any signature in the batch is invalid, without telling which
+        fn verify_batch(&self, batch: &[Verification<Self>]) -> anyhow::Result<()> {
+            for verification in batch {
+                self.verify_prehashed(
+                    &verification.key,
+                    verification.state.clone(),
+                    &verification.signature,
+                )?
+            }
+            Ok(())
+        }
+
+        fn batcher(&self) -> Option<&Batcher<Self>>;
+
+        fn set_batcher(&mut self, batcher: Batcher<Self>);
+    }
+
+    pub struct Verification<C: BatchVerify> {
+        key: C::Key,
+        state: Sha256,
+        signature: C::Signature,
+        verified: oneshot::Sender<bool>,
+    }
+
+    impl<C: BatchVerify> Debug for Verification<C> {
+        fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+            f.debug_struct("Verification").finish_non_exhaustive()
+        }
+    }
+
+    impl<C: BatchVerify> Verification<C> {
+        fn verify_one(self, crypto: &C) {
+            let verified = crypto
+                .verify_prehashed(&self.key, self.state, &self.signature)
+                .is_ok();
+            // the work is gone only if the worker is shutting down
+            let _ = self.verified.send(verified);
+        }
+    }
+
+    pub struct Batcher<C: BatchVerify>(UnboundedSender<Verification<C>>);
+
+    impl<C: BatchVerify> Clone for Batcher<C> {
+        fn clone(&self) -> Self {
+            Self(self.0.clone())
+        }
+    }
+
+    impl<C: BatchVerify> Debug for Batcher<C> {
+        fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+            f.debug_tuple("Batcher").finish()
+        }
+    }
+
+    impl BatchVerify for Crypto {
+        // the index of replica, as in `Crypto::verify`
+        type Key = usize;
+        type Signature = super::Signature;
+
+        fn verify_prehashed(
+            &self,
+            key: &Self::Key,
+            state: Sha256,
+            signature: &Self::Signature,
+        ) -> anyhow::Result<()> {
+            Crypto::verify_prehashed(self, *key, state, signature)
+        }
+
+        fn verify_batch(&self, batch: &[Verification<Self>]) -> anyhow::Result<()> {
+            let CryptoProvider::Schnorrkel(crypto) = &self.provider else {
+                // no batching for secp256k1
+                for verification in batch {
+                    BatchVerify::verify_prehashed(
+                        self,
+                        &verification.key,
+                        verification.state.clone(),
+                        &verification.signature,
+                    )?
+                }
+                return Ok(());
+            };
+            let mut transcripts = Vec::with_capacity(batch.len());
+            let mut signatures = Vec::with_capacity(batch.len());
+            let mut public_keys = Vec::with_capacity(batch.len());
+            for verification in batch {
+                let (
+                    Some(PublicKey::Schnorrkel(public_key)),
+                    super::Signature::Schnorrkel(signature),
+                ) = (
+                    self.public_keys.get(verification.key),
+                    &verification.signature,
+                )
+                else {
+                    anyhow::bail!("unimplemented")
+                };
+                transcripts.push(crypto.context.hash256(verification.state.clone()));
+                signatures.push(*signature);
+                public_keys.push(*public_key)
+            }
+            schnorrkel::verify_batch(transcripts, &signatures, &public_keys, false)
+                .map_err(anyhow::Error::msg)
+        }
+
+        fn batcher(&self) -> Option<&Batcher<Self>> {
+            self.batcher.as_ref()
+        }
+
+        fn set_batcher(&mut self, batcher: Batcher<Self>) {
+            self.batcher = Some(batcher)
+        }
+    }
+
+    impl BatchVerify for peer::Crypto {
+        type Key = peer::PublicKey;
+        type Signature = peer::Signature;
+
+        fn verify_prehashed(
+            &self,
+            key: &Self::Key,
+            state: Sha256,
+            signature: &Self::Signature,
+        ) -> anyhow::Result<()> {
+            peer::Crypto::verify_prehashed(self, key, state, signature)
+        }
+
+        fn verify_batch(&self, batch: &[Verification<Self>]) -> anyhow::Result<()> {
+            let transcripts = batch
+                .iter()
+                .map(|verification| self.context.hash256(verification.state.clone()));
+            let signatures = batch
+                .iter()
+                .map(|verification| verification.signature)
+                .collect::<Vec<_>>();
+            let public_keys = batch
+                .iter()
+                .map(|verification| verification.key)
+                .collect::<Vec<_>>();
+            schnorrkel::verify_batch(transcripts, &signatures, &public_keys, false)
+                .map_err(anyhow::Error::msg)
+        }
+
+        fn batcher(&self) -> Option<&Batcher<Self>> {
+            self.batcher.as_ref()
+        }
+
+        fn set_batcher(&mut self, batcher: Batcher<Self>) {
+            self.batcher = Some(batcher)
+        }
+    }
+
+    // through the batcher if there's one attached
+    pub async fn verify<C: BatchVerify, M: DigestHash>(
+        crypto: &C,
+        key: C::Key,
+        signed: &Verifiable<M, C::Signature>,
+    ) -> anyhow::Result<()>
+    where
+        C::Signature: Clone,
+    {
+        let mut state = Sha256::new();
+        DigestHash::hash(&signed.inner, &mut state);
+        let Some(Batcher(sender)) = crypto.batcher() else {
+            return crypto.verify_prehashed(&key, state, &signed.signature);
+        };
+        let (verified, receiver) = oneshot::channel();
+        sender
+            .send(Verification {
+                key,
+                state,
+                signature: signed.signature.clone(),
+                verified,
+            })
+            .map_err(|err| anyhow::anyhow!(err.to_string()))?;
+        if !receiver.await? {
+            anyhow::bail!("invalid signature")
+        }
+        Ok(())
+    }
+
+    impl<C: BatchVerify + Send + Sync + 'static, E: ?Sized + Send> Worker<C, E>
+    where
+        C::Key: Send + Sync + 'static,
+        C::Signature: Clone + Send + Sync + 'static,
+    {
+        // verify `signed` in a work, and continue with `on_verified` if it is valid. the invalid
+        // ones are dropped
+        pub fn submit_verify<M: DigestHash + Send + Sync + 'static>(
+            &mut self,
+            key: C::Key,
+            signed: Verifiable<M, C::Signature>,
+            on_verified: impl FnOnce(Verifiable<M, C::Signature>, &mut E) -> anyhow::Result<()>
+                + Send
+                + Sync
+                + 'static,
+        ) -> anyhow::Result<()> {
+            if let Self::Spawn(_) = self {
+                return self.submit_async(Box::new(move |crypto, sender| {
+                    Box::pin(async move {
+                        match verify(crypto, key, &signed).await {
+                            Ok(()) => on_verified(signed, sender),
+                            Err(err) => {
+                                warn!("fail to verify: {err}");
+                                Ok(())
+                            }
+                        }
+                    })
+                }));
+            }
+            self.submit(Box::new(move |crypto, sender| {
+                let mut state = Sha256::new();
+                DigestHash::hash(&signed.inner, &mut state);
+                match crypto.verify_prehashed(&key, state, &signed.signature) {
+                    Ok(()) => on_verified(signed, sender),
+                    Err(err) => {
+                        warn!("fail to verify: {err}");
+                        Ok(())
+                    }
+                }
+            }))
+        }
+    }
+
+    #[derive(Debug)]
+    pub struct BatchExecutor<C: BatchVerify> {
+        crypto: C,
+        receiver: UnboundedReceiver<Verification<C>>,
+        max_batch_size: usize,
+        window: Duration,
+    }
+
+    // returns the crypto with a batcher attached, which is expected to be the state of the crypto
+    // worker
+    // the window starts on receiving the first verification of a batch, so a verification waits
+    // for at most `window` before getting verified, even if the traffic is light
+    pub fn spawn_backend<C: BatchVerify + Clone>(
+        mut crypto: C,
+        max_batch_size: usize,
+        window: Duration,
+    ) -> (C, BatchExecutor<C>) {
+        let (sender, receiver) = unbounded_channel();
+        let executor = BatchExecutor {
+            crypto: crypto.clone(),
+            receiver,
+            max_batch_size,
+            window,
+        };
+        crypto.set_batcher(Batcher(sender));
+        (crypto, executor)
+    }
+
+    impl<C: BatchVerify> BatchExecutor<C> {
+        // the batches are verified one after another in this session, which is expected to be
+        // spawned alongside the crypto worker's executor
+        pub async fn run(&mut self) -> anyhow::Result<()> {
+            loop {
+                let verification = self
+                    .receiver
+                    .recv()
+                    .await
+                    .ok_or(anyhow::anyhow!("channel closed"))?;
+                let deadline = Instant::now() + self.window;
+                let mut batch = vec![verification];
+                let mut closed = false;
+                while batch.len() < self.max_batch_size {
+                    match timeout_at(deadline, self.receiver.recv()).await {
+                        Ok(Some(verification)) => batch.push(verification),
+                        Ok(None) => {
+                            // still finish the collected ones
+                            closed = true;
+                            break;
+                        }
+                        Err(_) => break,
+                    }
+                }
+                if self.crypto.verify_batch(&batch).is_ok() {
+                    for verification in batch {
+                        let _ = verification.verified.send(true);
+                    }
+                } else {
+                    for verification in batch {
+                        verification.verify_one(&self.crypto)
+                    }
+                }
+                if closed {
+                    anyhow::bail!("channel closed")
+                }
+            }
+        }
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -349,4 +705,43 @@ mod tests {
         };
         assert_ne!(foo.sha256(), <[u8; 32]>::default());
     }
+
+    #[tokio::test]
+    async fn batch_verify_fallback() -> anyhow::Result<()> {
+        use std::time::Duration;
+
+        use tokio::sync::mpsc::unbounded_channel;
+
+        use crate::{event::SendEvent, worker::erased::spawn_backend};
+
+        let crypto = peer::Crypto::new_random(&mut rand::thread_rng());
+        let other_crypto = peer::Crypto::new_random(&mut rand::thread_rng());
+        let (batched, mut batch_executor) =
+            batch::spawn_backend(crypto.clone(), 4, Duration::from_millis(10));
+        let (mut worker, mut executor) =
+            spawn_backend::<_, dyn SendEvent<u32> + Send + Sync>(batched);
+        let (sender, mut receiver) = unbounded_channel::<u32>();
+        tokio::spawn(async move {
+            tokio::select! {
+                result = executor.run(sender, |sender| sender) => result,
+                result = batch_executor.run() => result,
+            }
+        });
+        let signed = (0..4)
+            .map(|i| crypto.sign(i))
+            .chain((4..6).map(|i| other_crypto.sign(i)))
+            .chain([crypto.sign(6)]);
+        for signed in signed {
+            worker.submit_verify(crypto.public_key(), signed, |signed, sender| {
+                sender.send(*signed)
+            })?
+        }
+        let mut verified = Vec::new();
+        for _ in 0..5 {
+            verified.push(receiver.recv().await.unwrap())
+        }
+        verified.sort_unstable();
+        assert_eq!(verified, [0, 1, 2, 3, 6]);
+        Ok(())
+    }
 }
@@ -13,10 +13,14 @@
 pub mod blocking;
 pub mod linear;
 pub mod ordered;
+pub mod record;
 pub mod session;
+pub mod shard;
 
 use std::{collections::HashMap, time::Duration};
 
+use serde::{Deserialize, Serialize};
+
 pub trait SendEvent<M> {
     fn send(&mut self, event: M) -> anyhow::Result<()>;
 }
@@ -56,7 +60,7 @@ impl<N: Into<M>, M> SendEvent<N> for Transient<M> {
 // is, calling `unset` consume the TimerId so the timer cannot be referred
 // anymore
 // this does not solve leak though so Clone is permitted
-#[derive(Debug, Clone, Hash, PartialEq, Eq)]
+#[derive(Debug, Clone, Hash, PartialEq, Eq, Serialize, Deserialize)]
 pub struct TimerId(u32);
 
 pub trait Timer {
@@ -77,6 +81,27 @@ impl Timer for UnreachableTimer {
     }
 }
 
+// returned from event handlers to request the hosting session to shut down. it
+// is caught by `Session::run` and `blocking::run`, which then return `Ok(())`
+// instead of the error. this is the reason it is structured while most of the
+// other errors are not
+//
+// the state machine should finish all its business before returning this, e.g.
+// on receiving `erased::events::Stop`. no timer fires after it, but the events
+// that are already queued are still delivered, so the messages that others
+// have sent through this session are not lost. the `Exit`s returned for them
+// are ignored
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Exit;
+
+impl std::fmt::Display for Exit {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "state machine exit")
+    }
+}
+
+impl std::error::Error for Exit {}
+
 pub trait OnEvent {
     type Event;
 
@@ -410,6 +435,11 @@ pub mod erased {
     pub mod events {
         #[derive(Debug)]
         pub struct Init;
+
+        // the counterpart of `Init`. the state machine flushes whatever is pending e.g. the
+        // buffered outgoing messages, then usually returns `Exit`
+        #[derive(Debug)]
+        pub struct Stop;
     }
 
     pub mod session {
@@ -3,7 +3,7 @@ use std::{
     time::Instant,
 };
 
-use super::{ordered::Timer, OnEventUniversal, OnTimerUniversal, SendEvent};
+use super::{ordered::Timer, Exit, OnEventUniversal, OnTimerUniversal, SendEvent};
 
 pub type Sender<M> = std::sync::mpsc::Sender<M>;
 
@@ -13,8 +13,11 @@ impl<N: Into<M>, M> SendEvent<N> for Sender<M> {
     }
 }
 
-// there's no way to cancel a blocking run, so it should be fine to create
-// scoped timer inside the function. not absolutely sure though
+// there's no way to cancel a blocking run from outside, so it should be fine to
+// create scoped timer inside the function. not absolutely sure though
+// the run only ends when the state machine returns `Exit`. the events that are
+// already queued at that point are still delivered as `Session::run` does, and
+// the timers that are still set are dropped along with it
 pub fn run<M>(
     receiver: Receiver<M>,
     state: &mut (impl OnEventUniversal<Timer, Event = M> + OnTimerUniversal<Timer>),
@@ -30,10 +33,21 @@ pub fn run<M>(
         } else {
             Some(receiver.recv()?)
         };
-        if let Some(event) = event {
-            state.on_event(event, &mut timer)?
+        let result = if let Some(event) = event {
+            state.on_event(event, &mut timer)
         } else {
-            state.on_timer(timer.advance()?, &mut timer)?
+            state.on_timer(timer.advance()?, &mut timer)
+        };
+        match result {
+            Err(err) if err.is::<Exit>() => break,
+            result => result?,
+        }
+    }
+    while let Ok(event) = receiver.try_recv() {
+        match state.on_event(event, &mut timer) {
+            Err(err) if !err.is::<Exit>() => return Err(err),
+            _ => {}
         }
     }
+    Ok(())
 }
@@ -0,0 +1,172 @@
+// recording the events delivered to a state machine, and replaying them later
+//
+// the intended usage is post mortem: wrap the crashing state machine with
+// `Recorded` during evaluation, and when it crashes again, feed the log into a
+// fresh state machine in a unit test or a debugger to get a deterministic
+// reproduction. the timestamps are recorded for inspecting only. replaying does
+// not wait for them, since the timers are driven by the log as well
+//
+// only the state machines with serializable event type can be recorded. in
+// practice that means non-erased state machines e.g. `Unify(_)` whose events
+// are mostly `Recv<_>` of network messages. type-erased events are opaque
+// closures that cannot be written out, so the recording has to happen before
+// the erasure, if ever needed
+//
+// the replay relies on the determinism of the state machine (which it should
+// have, otherwise model checking would be in trouble as well), and also on the
+// fact that all timer implementations in this codebase allocate timer ids
+// sequentially starting from 1. if some day the latter does not hold, the
+// `Timer` entries below need to be replaced with something more stable e.g.
+// the position of the corresponding `set` call
+
+use std::{
+    io::{ErrorKind, Read, Write},
+    time::{Duration, Instant},
+};
+
+use bincode::Options as _;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+
+use super::{linear, Inline, OnEventUniversal, OnTimerUniversal, SendEvent, TimerId};
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum Entry<M> {
+    Event(M),
+    Timer(TimerId),
+}
+
+// the entries are written with `W` as is. wrap it with `BufWriter` if desired.
+// the buffered entries are flushed when dropping, which covers both error
+// returning and (unwinding) panicking
+#[derive(Debug, derive_more::Deref, derive_more::DerefMut)]
+pub struct Recorded<S, W: Write> {
+    #[deref]
+    #[deref_mut]
+    pub inner: S,
+    writer: W,
+    start: Instant,
+}
+
+impl<S, W: Write> Recorded<S, W> {
+    pub fn new(inner: S, writer: W) -> Self {
+        Self {
+            inner,
+            writer,
+            start: Instant::now(),
+        }
+    }
+
+    fn record<M: Serialize>(&mut self, entry: Entry<M>) -> anyhow::Result<()> {
+        bincode::options().serialize_into(&mut self.writer, &(self.start.elapsed(), entry))?;
+        Ok(())
+    }
+}
+
+impl<S: OnEventUniversal<T>, T, W: Write> OnEventUniversal<T> for Recorded<S, W>
+where
+    S::Event: Serialize,
+{
+    type Event = S::Event;
+
+    fn on_event(&mut self, event: Self::Event, timer: &mut T) -> anyhow::Result<()> {
+        self.record(Entry::Event(&event))?;
+        self.inner.on_event(event, timer)
+    }
+}
+
+impl<S: OnTimerUniversal<T>, T, W: Write> OnTimerUniversal<T> for Recorded<S, W> {
+    fn on_timer(&mut self, timer_id: TimerId, timer: &mut T) -> anyhow::Result<()> {
+        self.record(Entry::<()>::Timer(timer_id.clone()))?;
+        self.inner.on_timer(timer_id, timer)
+    }
+}
+
+// a truncated last entry, which is likely to present if the recorded process
+// got killed, is silently skipped
+pub fn entries<M: DeserializeOwned>(
+    mut reader: impl Read,
+) -> impl Iterator<Item = anyhow::Result<(Duration, Entry<M>)>> {
+    std::iter::from_fn(move || {
+        let err = match bincode::options().deserialize_from(&mut reader) {
+            Ok(entry) => return Some(Ok(entry)),
+            Err(err) => err,
+        };
+        match *err {
+            bincode::ErrorKind::Io(err) if err.kind() == ErrorKind::UnexpectedEof => None,
+            err => Some(Err(err.into())),
+        }
+    })
+}
+
+pub fn replay<M: DeserializeOwned>(
+    reader: impl Read,
+    state: &mut (impl OnEventUniversal<linear::Timer, Event = M> + OnTimerUniversal<linear::Timer>),
+) -> anyhow::Result<()> {
+    let mut timer = linear::Timer::default();
+    for entry in entries(reader) {
+        match entry?.1 {
+            Entry::Event(event) => Inline(&mut *state, &mut timer).send(event)?,
+            Entry::Timer(timer_id) => {
+                // also ensure the timer has not been unset at this point of replaying
+                timer.step_timer(&timer_id)?;
+                state.on_timer(timer_id, &mut timer)?
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use crate::event::{OnEvent, OnTimer, Timer, Unify};
+
+    use super::*;
+
+    #[derive(Default)]
+    struct Counter {
+        count: u32,
+        timer_id: Option<TimerId>,
+    }
+
+    impl OnEvent for Counter {
+        type Event = u32;
+
+        fn on_event(&mut self, event: Self::Event, timer: &mut impl Timer) -> anyhow::Result<()> {
+            self.count += event;
+            if self.timer_id.is_none() {
+                self.timer_id = Some(timer.set(Duration::from_millis(1))?)
+            }
+            Ok(())
+        }
+    }
+
+    impl OnTimer for Counter {
+        fn on_timer(&mut self, timer_id: TimerId, timer: &mut impl Timer) -> anyhow::Result<()> {
+            anyhow::ensure!(self.timer_id.as_ref() == Some(&timer_id));
+            timer.unset(timer_id)?;
+            self.timer_id = None;
+            self.count *= 2;
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn record_replay() -> anyhow::Result<()> {
+        let mut state = Recorded::new(Unify(Counter::default()), Vec::new());
+        let mut timer = linear::Timer::default();
+        state.on_event(1, &mut timer)?;
+        state.on_event(2, &mut timer)?;
+        let timer_id = state.timer_id.clone().unwrap();
+        state.on_timer(timer_id, &mut timer)?;
+        state.on_event(3, &mut timer)?;
+        assert_eq!(state.count, 9);
+
+        let mut replayed = Unify(Counter::default());
+        replay(&state.writer[..], &mut replayed)?;
+        assert_eq!(replayed.count, 9);
+        assert!(replayed.timer_id.is_some());
+        Ok(())
+    }
+}
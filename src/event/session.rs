@@ -6,7 +6,7 @@ use tokio::{
     time::{interval, sleep},
 };
 
-use crate::event::{SendEvent, Timer, TimerId};
+use crate::event::{Exit, SendEvent, Timer, TimerId};
 
 use super::{OnEventUniversal, OnTimerUniversal};
 
@@ -125,7 +125,7 @@ impl<M> Session<M> {
                 }
                 Select::Recv(event) => event.ok_or(anyhow::anyhow!("channel closed"))?,
             };
-            match event {
+            let result = match event {
                 Event::Timer(timer_id) => {
                     if !self.timer.handles.contains_key(&timer_id) {
                         // unset/timeout contention, force to skip timer as long as it has been
@@ -149,12 +149,48 @@ impl<M> Session<M> {
                         // (so wish i have direct access to the timer wheel...)
                         continue;
                     }
-                    state.on_timer(TimerId(timer_id), &mut self.timer)?
+                    state.on_timer(TimerId(timer_id), &mut self.timer)
                 }
-                Event::Other(event) => state.on_event(event, &mut self.timer)?,
+                Event::Other(event) => state.on_event(event, &mut self.timer),
+            };
+            if let Err(err) = result {
+                if err.is::<Exit>() {
+                    return self.drain(state).await;
+                }
+                return Err(err);
             }
         }
     }
+
+    // the state machine has finished its business on `Exit`, but the events that are already
+    // queued may still carry business of others e.g. the messages that are sent through a
+    // `DispatchNet` right before the `Stop`, so they are still delivered, and the `Exit`s returned
+    // for them are ignored. closing the channel first makes further sending fail, so the producers
+    // e.g. receiving sessions notice the shutdown and exit as well, instead of keep buffering into
+    // a dead session, and the draining ends
+    // the timers are not fired anymore. they are cancelled and joined, so nothing of this session
+    // outlives `run`
+    async fn drain(
+        &mut self,
+        state: &mut (impl OnEventUniversal<SessionTimer, Event = M> + OnTimerUniversal<SessionTimer>),
+    ) -> anyhow::Result<()>
+    where
+        M: Send + 'static,
+    {
+        self.receiver.close();
+        self.timer.handles.clear();
+        while let Ok(event) = self.receiver.try_recv() {
+            let Event::Other(event) = event else {
+                continue;
+            };
+            match state.on_event(event, &mut self.timer) {
+                Err(err) if !err.is::<Exit>() => return Err(err),
+                _ => {}
+            }
+        }
+        self.timer.sessions.shutdown().await;
+        Ok(())
+    }
 }
 
 impl Timer for SessionTimer {
@@ -182,3 +218,50 @@ impl Timer for SessionTimer {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use crate::event::{OnEvent, OnTimer, Unify};
+
+    use super::*;
+
+    // the remaining events before exit, and the delivered events
+    struct Countdown(u32, u32);
+
+    impl OnEvent for Countdown {
+        type Event = ();
+
+        fn on_event(&mut self, (): Self::Event, timer: &mut impl Timer) -> anyhow::Result<()> {
+            // keep a timer around to check it does not block the shutdown
+            timer.set(Duration::from_secs(1))?;
+            self.1 += 1;
+            self.0 = self.0.saturating_sub(1);
+            if self.0 == 0 {
+                Err(Exit)?
+            }
+            Ok(())
+        }
+    }
+
+    impl OnTimer for Countdown {
+        fn on_timer(&mut self, _: TimerId, _: &mut impl Timer) -> anyhow::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn exit() -> anyhow::Result<()> {
+        let mut session = Session::new();
+        let mut sender = session.sender();
+        for _ in 0..5 {
+            sender.send(())?
+        }
+        let mut state = Unify(Countdown(3, 0));
+        session.run(&mut state).await?;
+        assert_eq!(state.0 .0, 0);
+        // the events queued before the exit are drained
+        assert_eq!(state.0 .1, 5);
+        assert!(sender.send(()).is_err());
+        Ok(())
+    }
+}
@@ -0,0 +1,259 @@
+// a fixed pool of worker threads, each running a single-threaded Tokio runtime
+// that sessions can be spawned onto
+//
+// the motivation is to host many independent `impl OnEvent` in one process,
+// e.g. 100 entropy peers, or many close loops of benchmark clients, without
+// either constructing one runtime per state machine (which results in as many
+// threads as state machines), or putting all of them onto a multi-thread
+// runtime (which keeps migrating sessions among threads). each spawned session
+// is pinned to one shard for its whole lifetime, so the `Session::run` in it
+// preserves the same single-threaded execution as before
+//
+// the session is constructed by a closure that executed on the shard, so the
+// session itself does not need to be `Send`. (although currently all the
+// sessions happen to be.) a session is cancelled as soon as its handle i.e. the
+// returned future is dropped, mirroring the semantic of `JoinSet`
+
+use std::{
+    future::Future,
+    sync::atomic::{AtomicUsize, Ordering::SeqCst},
+    thread::JoinHandle,
+};
+
+use tokio::{
+    runtime,
+    sync::{
+        mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender},
+        oneshot,
+    },
+    task::LocalSet,
+};
+use tracing::warn;
+
+type Task = Box<dyn FnOnce() + Send>;
+
+#[derive(Debug)]
+pub struct Shards {
+    senders: Vec<UnboundedSender<Task>>,
+    threads: Vec<JoinHandle<anyhow::Result<()>>>,
+    next: AtomicUsize,
+    // the allowed cores that no shard is pinned to
+    spare_cores: Vec<usize>,
+}
+
+impl Shards {
+    // `cores` is the list of cpu that shards are pinned to, one shard per core. `None` for not
+    // pinning, and in that case `available_parallelism` number of shards are created
+    pub fn new(cores: impl Into<Option<Vec<usize>>>) -> anyhow::Result<Self> {
+        let cores = match cores.into() {
+            Some(cores) => cores.into_iter().map(Some).collect::<Vec<_>>(),
+            None => vec![None; std::thread::available_parallelism()?.get()],
+        };
+        if cores.is_empty() {
+            anyhow::bail!("no shard")
+        }
+        let mut senders = Vec::new();
+        let mut threads = Vec::new();
+        for (index, core) in cores.into_iter().enumerate() {
+            let (sender, receiver) = unbounded_channel();
+            senders.push(sender);
+            threads.push(
+                std::thread::Builder::new()
+                    .name(format!("shard-{index}"))
+                    .spawn(move || Self::run(core, receiver))?,
+            );
+        }
+        Ok(Self {
+            senders,
+            threads,
+            next: Default::default(),
+            spare_cores: Default::default(),
+        })
+    }
+
+    // pin shards to the first `num_shard` cores that this process is allowed to run on, so it
+    // works under `taskset` and cgroup cpusets. the rest of the allowed cores are left for
+    // `new_spare_pool`
+    pub fn new_pinned(num_shard: usize) -> anyhow::Result<Self> {
+        let allowed = rustix::process::sched_getaffinity(None)?;
+        let mut cores = (0..rustix::process::CpuSet::MAX_CPU)
+            .filter(|&core| allowed.is_set(core))
+            .collect::<Vec<_>>();
+        if cores.len() < num_shard {
+            anyhow::bail!("{num_shard} shards but only {} cores allowed", cores.len())
+        }
+        let spare_cores = cores.split_off(num_shard);
+        Ok(Self {
+            spare_cores,
+            ..Self::new(cores)?
+        })
+    }
+
+    pub fn spare_cores(&self) -> &[usize] {
+        &self.spare_cores
+    }
+
+    // a pool for the blocking works alongside the shards e.g. crypto and codec, with one thread per
+    // spare core. the threads are pinned to the spare cores (as a whole, the kernel still balances
+    // among them), so they do not compete with the shards
+    // the threads that the shards spawn (e.g. Tokio's blocking threads) inherit the shard's single
+    // core, so the blocking works should go to a pool like this instead
+    // if there's no spare core, i.e. all allowed cores are taken by shards or the shards are not
+    // pinned, fall back to one thread that is not pinned either
+    pub fn new_spare_pool(&self, name: &'static str) -> anyhow::Result<rayon::ThreadPool> {
+        let mut cpu_set = rustix::process::CpuSet::new();
+        for &core in &self.spare_cores {
+            cpu_set.set(core)
+        }
+        let pinned = !self.spare_cores.is_empty();
+        let pool = rayon::ThreadPoolBuilder::new()
+            .num_threads(self.spare_cores.len().max(1))
+            .thread_name(move |index| format!("{name}-{index}"))
+            .start_handler(move |_| {
+                if pinned {
+                    if let Err(err) = rustix::process::sched_setaffinity(None, &cpu_set) {
+                        warn!("pin pool thread {err}")
+                    }
+                }
+            })
+            .build()?;
+        Ok(pool)
+    }
+
+    pub fn len(&self) -> usize {
+        self.senders.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.senders.is_empty()
+    }
+
+    fn run(core: Option<usize>, mut receiver: UnboundedReceiver<Task>) -> anyhow::Result<()> {
+        if let Some(core) = core {
+            let mut cpu_set = rustix::process::CpuSet::new();
+            cpu_set.set(core);
+            rustix::process::sched_setaffinity(None, &cpu_set)?
+        }
+        let runtime = runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()?;
+        let local = LocalSet::new();
+        local.block_on(&runtime, async move {
+            while let Some(task) = receiver.recv().await {
+                task()
+            }
+        });
+        // the sessions that still running are dropped along with `local`
+        Ok(())
+    }
+
+    // spawn onto shards in round robin
+    pub fn spawn<F: Future<Output = anyhow::Result<()>> + 'static>(
+        &self,
+        session: impl FnOnce() -> F + Send + 'static,
+    ) -> impl Future<Output = anyhow::Result<()>> + Send + 'static {
+        let index = self.next.fetch_add(1, SeqCst) % self.senders.len();
+        self.spawn_on(index, session)
+    }
+
+    pub fn spawn_on<F: Future<Output = anyhow::Result<()>> + 'static>(
+        &self,
+        index: usize,
+        session: impl FnOnce() -> F + Send + 'static,
+    ) -> impl Future<Output = anyhow::Result<()>> + Send + 'static {
+        let (mut result_sender, result_receiver) = oneshot::channel();
+        let task = move || {
+            tokio::task::spawn_local(async move {
+                tokio::select! {
+                    result = session() => {
+                        if let Err(Err(err)) = result_sender.send(result) {
+                            // the handle has gone so no one will observe the error otherwise
+                            warn!("detached session {err}")
+                        }
+                    }
+                    // handle dropped, cancel the session
+                    () = result_sender.closed() => {}
+                }
+            });
+        };
+        let submitted = self
+            .senders
+            .get(index)
+            .ok_or(anyhow::anyhow!("shard index out of bound"))
+            .and_then(|sender| {
+                sender
+                    .send(Box::new(task))
+                    .map_err(|err| anyhow::anyhow!(err.to_string()))
+            });
+        async move {
+            submitted?;
+            result_receiver
+                .await
+                .map_err(|_| anyhow::anyhow!("shard shutdown"))?
+        }
+    }
+
+    pub fn shutdown(self) -> anyhow::Result<()> {
+        drop(self.senders);
+        for thread in self.threads {
+            thread
+                .join()
+                .map_err(|_| anyhow::anyhow!("shard thread panicked"))??
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn spawn_on_every_shard() -> anyhow::Result<()> {
+        let shards = Shards::new_pinned(std::thread::available_parallelism()?.get())?;
+        let (sender, mut receiver) = unbounded_channel();
+        let mut handles = Vec::new();
+        for index in 0..shards.len() {
+            let sender = sender.clone();
+            handles.push(shards.spawn_on(index, move || async move {
+                let name = std::thread::current().name().map(ToString::to_string);
+                sender.send((index, name))?;
+                Ok(())
+            }))
+        }
+        for handle in handles {
+            handle.await?
+        }
+        drop(sender);
+        let mut ran = Vec::new();
+        while let Some((index, name)) = receiver.recv().await {
+            assert_eq!(name, Some(format!("shard-{index}")));
+            ran.push(index)
+        }
+        ran.sort();
+        assert_eq!(ran, (0..shards.len()).collect::<Vec<_>>());
+        shards.shutdown()
+    }
+
+    #[test]
+    fn spare_pool() -> anyhow::Result<()> {
+        let shards = Shards::new_pinned(std::thread::available_parallelism()?.get().max(2) - 1)?;
+        let pool = shards.new_spare_pool("spare")?;
+        let (name, allowed) = pool.install(|| {
+            (
+                std::thread::current().name().map(ToString::to_string),
+                rustix::process::sched_getaffinity(None),
+            )
+        });
+        assert!(name.is_some_and(|name| name.starts_with("spare-")));
+        let allowed = allowed?;
+        // on a single core the pool thread is not pinned, so nothing to check
+        if !shards.spare_cores().is_empty() {
+            assert_eq!(allowed.count() as usize, shards.spare_cores().len());
+            for &core in shards.spare_cores() {
+                assert!(allowed.is_set(core))
+            }
+        }
+        shards.shutdown()
+    }
+}
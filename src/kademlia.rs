@@ -2,6 +2,7 @@ use std::{
     array::from_fn,
     collections::{HashMap, HashSet},
     fmt::Debug,
+    net::SocketAddr,
     num::NonZeroUsize,
     time::Duration,
 };
@@ -20,7 +21,10 @@ use crate::{
         erased::{OnEventRichTimer as OnEvent, RichTimer as Timer},
         SendEvent, TimerId,
     },
-    net::{events::Recv, Addr, SendMessage, SendMessageToEach, SendMessageToEachExt as _},
+    net::{
+        events::Recv, session::ConnectionEvent, Addr, SendMessage, SendMessageToEach,
+        SendMessageToEachExt as _,
+    },
     worker::erased::Worker,
 };
 
@@ -170,6 +174,16 @@ impl<K: Clone, A: Addr, const BITS: usize> Buckets<K, A, BITS> {
         Some(record)
     }
 
+    // the peers that are reached at `addr`, usually at most one
+    pub fn find_addr(&self, addr: &A) -> Vec<PeerId> {
+        self.distances
+            .iter()
+            .flat_map(|bucket| &bucket.records)
+            .filter(|record| &record.addr == addr)
+            .map(|record| record.id)
+            .collect()
+    }
+
     fn find_closest(&self, target: &Target, count: NonZeroUsize) -> Vec<PeerRecord<K, A>> {
         let mut records = Vec::new();
         let index = if *target == self.origin.id {
@@ -469,16 +483,18 @@ impl<A: Addr> OnEvent<Recv<Verifiable<FindPeer<A>>>> for Peer<A> {
         //     H256(self.record.id),
         //     H256(find_peer.target)
         // );
-        self.crypto_worker.submit(Box::new(move |crypto, sender| {
-            if find_peer.record.key.sha256() == find_peer.record.id
-                && crypto.verify(&find_peer.record.key, &find_peer).is_ok()
-            {
+        // check the id before verifying as always. it's one hash over a public key so cheap
+        // enough for here, and a forged record does not fail the verification batch it would
+        // otherwise join
+        if find_peer.record.key.sha256() != find_peer.record.id {
+            warn!("fail to verify FindPeer");
+            return Ok(());
+        }
+        let key = find_peer.record.key;
+        self.crypto_worker
+            .submit_verify(key, find_peer, |find_peer, sender| {
                 sender.send(Verified(find_peer))
-            } else {
-                warn!("fail to verify FindPeer");
-                Ok(())
-            }
-        }))
+            })
     }
 }
 
@@ -530,18 +546,15 @@ impl<A: Addr> OnEvent<Recv<Verifiable<FindPeerOk<A>>>> for Peer<A> {
         Recv(find_peer_ok): Recv<Verifiable<FindPeerOk<A>>>,
         _: &mut impl Timer<Self>,
     ) -> anyhow::Result<()> {
-        self.crypto_worker.submit(Box::new(move |crypto, sender| {
-            if find_peer_ok.record.key.sha256() == find_peer_ok.record.id
-                && crypto
-                    .verify(&find_peer_ok.record.key, &find_peer_ok)
-                    .is_ok()
-            {
+        if find_peer_ok.record.key.sha256() != find_peer_ok.record.id {
+            warn!("fail to verify FindPeerOk");
+            return Ok(());
+        }
+        let key = find_peer_ok.record.key;
+        self.crypto_worker
+            .submit_verify(key, find_peer_ok, |find_peer_ok, sender| {
                 sender.send(Verified(find_peer_ok))
-            } else {
-                warn!("fail to verify FindPeerOk");
-                Ok(())
-            }
-        }))
+            })
     }
 }
 
@@ -680,6 +693,24 @@ impl<A: Addr> OnEvent<Verified<FindPeerOk<A>, Signature>> for Peer<A> {
     }
 }
 
+// the liveness signal from `net::session::Dispatch`. a peer that cannot be
+// connected to is removed immediately instead of waiting for queries to time out
+// on it, which is the only way to find out dead peers otherwise
+// the reclaimed connections are not the peers' fault and are ignored
+impl OnEvent<ConnectionEvent> for Peer<SocketAddr> {
+    fn on_event(&mut self, event: ConnectionEvent, _: &mut impl Timer<Self>) -> anyhow::Result<()> {
+        let (ConnectionEvent::ConnectFailed(addr) | ConnectionEvent::Disconnected(addr)) = event
+        else {
+            return Ok(());
+        };
+        for id in self.buckets.find_addr(&addr) {
+            warn!("remove unreachable peer {} at {addr}", H256(id));
+            self.buckets.remove(&id);
+        }
+        Ok(())
+    }
+}
+
 impl<A: Addr> Peer<A> {
     fn refresh_buckets(&mut self) -> anyhow::Result<()> {
         let mut start_index = 0;
@@ -744,21 +775,14 @@ fn rand_distance_model(index: usize) -> U256 {
     U256::from_little_endian(&bytes)
 }
 
-// #[derive(Debug, Clone, derive_more::From, Serialize, Deserialize)]
-// pub enum Message<A> {
-//     FindPeer(Verifiable<FindPeer<A>>),
-//     FindPeerOk(Verifiable<FindPeerOk<A>>),
-// }
-
-pub trait SendRecvEvent<A>:
-    SendEvent<Recv<Verifiable<FindPeer<A>>>> + SendEvent<Recv<Verifiable<FindPeerOk<A>>>>
-{
-}
-impl<
-        T: SendEvent<Recv<Verifiable<FindPeer<A>>>> + SendEvent<Recv<Verifiable<FindPeerOk<A>>>>,
-        A,
-    > SendRecvEvent<A> for T
-{
+crate::messages! {
+    pub enum Message<A> {
+        FindPeer(Verifiable<FindPeer<A>>),
+        FindPeerOk(Verifiable<FindPeerOk<A>>),
+    }
+    pub type MessageNet;
+    pub trait SendRecvEvent;
+    pub fn on_buf;
 }
 
 #[cfg(test)]
@@ -1,61 +1,91 @@
 use std::{
     future::{pending, Future},
     iter::repeat_with,
-    net::SocketAddr,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
     sync::{Arc, Mutex},
     time::Duration,
 };
 
 use augustus::{
     app::{ycsb, App, Sqlite},
-    crypto::{Crypto, CryptoFlavor},
+    crypto::{batch, Crypto, CryptoFlavor},
     event::{
         erased::{
-            events::Init,
+            events::{Init, Stop},
             session::{Buffered, Sender},
             Blanket, Event, Session, Unify,
         },
         session::SessionTimer,
+        shard::Shards,
         OnEventUniversal, OnTimerUniversal, SendEvent,
     },
-    net::{session::Udp, IndexNet},
+    net::{
+        book::{AddrBook, AddrBookNet},
+        broadcast::{self, Broadcast},
+        fault::{self, FaultControl, Faults, Faulty},
+        fragment::{Fragmented, Reassemble},
+        recv_from,
+        session::{bind_tcp_listener, bind_udp, Udp},
+        traffic::{Counted, Direction, Metered, Traffic},
+    },
     pbft, unreplicated,
     worker::erased::spawn_backend,
     workload::{CloseLoop, Invoke, InvokeOk, Iter, OpLatency, Workload},
 };
 use axum::{
     extract::State,
+    http::StatusCode,
     routing::{get, post},
     Json, Router,
 };
 use rand::{rngs::StdRng, SeedableRng};
 use replication_control_messages::{
-    BenchmarkResult, ClientConfig, Protocol, ReplicaConfig, YcsbBackend,
+    BenchmarkResult, ClientConfig, FaultConfig, Protocol, ReplicaConfig, TrafficCount,
+    TrafficDirection, YcsbBackend,
 };
 use tokio::{
-    runtime,
     signal::ctrl_c,
     spawn,
     sync::Barrier,
-    task::{spawn_blocking, JoinHandle, JoinSet},
+    task::{JoinHandle, JoinSet},
 };
 use tokio_util::sync::CancellationToken;
 
+// all messages of the artifact go through `Fragmented`, mostly for the large
+// replies of YCSB scans and the large PBFT batches. fragments are sized to fit
+// in the common MTU, so that IP fragmentation does not happen
+const MAX_DATAGRAM_SIZE: usize = 1400;
+const REASSEMBLE_TIMEOUT: Duration = Duration::from_secs(1);
+
 #[tokio::main(flavor = "current_thread")]
 async fn main() -> anyhow::Result<()> {
+    // leave a core for the crypto pool, which verifies for the replica shard
+    let shards = Shards::new_pinned(std::thread::available_parallelism()?.get().max(2) - 1)?;
+    let crypto_pool = Arc::new(shards.new_spare_pool("crypto")?);
     let app = Router::new()
         .route("/ok", get(ok))
         .route("/start-client", post(start_client))
         .route("/take-benchmark-result", post(take_benchmark_result))
         .route("/start-replica", post(start_replica))
         .route("/stop-replica", post(stop_replica))
+        .route("/set-faults", post(set_faults))
+        .route("/update-replica-addrs", post(update_replica_addrs))
+        .route("/traffic", get(traffic))
         .with_state(AppState {
             session: Default::default(),
             benchmark_result: Default::default(),
+            faults: Default::default(),
+            replica_addrs: Default::default(),
+            traffic: Default::default(),
+            shards: Arc::new(shards),
+            crypto_pool,
         });
-    let ip = std::env::args().nth(1);
-    let ip = ip.as_deref().unwrap_or("0.0.0.0");
-    let listener = tokio::net::TcpListener::bind(format!("{ip}:3000")).await?;
+    // dual-stack when no address is given
+    let ip = std::env::args()
+        .nth(1)
+        .map(|ip| ip.parse::<IpAddr>())
+        .transpose()?;
+    let listener = bind_tcp_listener(ip, 3000)?;
     axum::serve(listener, app)
         .with_graceful_shutdown(async { ctrl_c().await.unwrap() })
         .await?;
@@ -66,6 +96,16 @@ async fn main() -> anyhow::Result<()> {
 struct AppState {
     session: Arc<Mutex<Option<AppSession>>>,
     benchmark_result: Arc<Mutex<Option<BenchmarkResult>>>,
+    shards: Arc<Shards>,
+    // on the cores that are not taken by the shards
+    crypto_pool: Arc<rayon::ThreadPool>,
+    // applied on the replica's outgoing messages
+    faults: FaultControl<SocketAddr>,
+    // reset to the config's addresses when a client or a replica starts, and updated in the middle
+    // of a run to move or replace replicas
+    replica_addrs: AddrBook<SocketAddr>,
+    // of the client or the replica that is currently running
+    traffic: Traffic<SocketAddr>,
 }
 
 type AppSession = (JoinHandle<anyhow::Result<()>>, CancellationToken);
@@ -92,28 +132,45 @@ async fn start_client(State(state): State<AppState>, Json(config): Json<ClientCo
     let cancel = CancellationToken::new();
     let benchmark_result = state.benchmark_result.clone();
     benchmark_result.lock().unwrap().take();
-    let handle = spawn_blocking(move || {
-        let runtime = &runtime::Builder::new_multi_thread()
-            .worker_threads(1)
-            .enable_all()
-            .build()?;
+    let shards = state.shards.clone();
+    let replica_addrs = state.replica_addrs.clone();
+    replica_addrs.set(config.replica_addrs.clone());
+    let traffic = state.traffic.clone();
+    traffic.clear();
+    let handle = spawn(state.shards.spawn(move || async move {
         match config.protocol {
-            Protocol::Unreplicated => runtime.block_on(client_session::<
-                Blanket<Unify<unreplicated::Client<_, _, _>>>,
-            >(
-                config,
-                unreplicated::erased::to_client_on_buf,
-                benchmark_result,
-            )),
+            Protocol::Unreplicated => {
+                let on_buf_traffic = traffic.clone();
+                client_session::<Blanket<Unify<unreplicated::Client<_, _, _>>>>(
+                    config,
+                    move |buf: &_, sender: &mut _| {
+                        on_buf_traffic.on_buf::<unreplicated::Reply>(recv_from(), buf);
+                        unreplicated::erased::to_client_on_buf(buf, sender)
+                    },
+                    benchmark_result,
+                    replica_addrs,
+                    traffic,
+                    &shards,
+                )
+                .await
+            }
             Protocol::Pbft => {
-                runtime.block_on(client_session::<Blanket<Buffered<pbft::Client<_>>>>(
+                let on_buf_traffic = traffic.clone();
+                client_session::<Blanket<Buffered<pbft::Client<_>>>>(
                     config,
-                    pbft::to_client_on_buf,
+                    move |buf: &_, sender: &mut _| {
+                        on_buf_traffic.on_buf::<pbft::Reply>(recv_from(), buf);
+                        pbft::to_client_on_buf(buf, sender)
+                    },
                     benchmark_result,
-                ))
+                    replica_addrs,
+                    traffic,
+                    &shards,
+                )
+                .await
             }
         }
-    });
+    }));
     let replaced = session.replace((handle, cancel));
     assert!(replaced.is_none())
 }
@@ -124,6 +181,8 @@ trait NewClient<S> {
         id: u32,
         addr: SocketAddr,
         net: Udp,
+        replica_addrs: AddrBook<SocketAddr>,
+        traffic: Traffic<SocketAddr>,
         upcall: impl SendEvent<InvokeOk> + Send + Sync + 'static,
     ) -> S;
 }
@@ -146,6 +205,8 @@ impl
         id: u32,
         addr: SocketAddr,
         net: Udp,
+        replica_addrs: AddrBook<SocketAddr>,
+        traffic: Traffic<SocketAddr>,
         upcall: impl SendEvent<InvokeOk> + Send + Sync + 'static,
     ) -> Blanket<
         Unify<
@@ -159,10 +220,12 @@ impl
         Blanket(Unify(unreplicated::Client::new(
             id,
             addr,
-            Box::new(unreplicated::ToReplicaMessageNet::new(IndexNet::new(
-                net,
-                self.replica_addrs.clone(),
-                None,
+            Box::new(Counted::from(unreplicated::ToReplicaMessageNet::new(
+                AddrBookNet::new(
+                    Metered::new(Fragmented::new(net, MAX_DATAGRAM_SIZE), traffic),
+                    replica_addrs,
+                    None,
+                ),
             ))),
             Box::new(upcall),
         )))
@@ -175,12 +238,18 @@ impl NewClient<Blanket<Buffered<pbft::Client<SocketAddr>>>> for ClientConfig {
         id: u32,
         addr: SocketAddr,
         net: Udp,
+        replica_addrs: AddrBook<SocketAddr>,
+        traffic: Traffic<SocketAddr>,
         upcall: impl SendEvent<InvokeOk> + Send + Sync + 'static,
     ) -> Blanket<Buffered<pbft::Client<SocketAddr>>> {
         Blanket(Buffered::from(pbft::Client::new(
             id,
             addr,
-            pbft::ToReplicaMessageNet::new(IndexNet::new(net, self.replica_addrs.clone(), None)),
+            Counted::from(pbft::ToReplicaMessageNet::new(AddrBookNet::new(
+                Metered::new(Fragmented::new(net, MAX_DATAGRAM_SIZE), traffic),
+                replica_addrs,
+                None,
+            ))),
             upcall,
             self.num_replica,
             self.num_faulty,
@@ -198,6 +267,9 @@ async fn client_session<
     config: ClientConfig,
     on_buf: impl Fn(&[u8], &mut Sender<S>) -> anyhow::Result<()> + Clone + Send + Sync + 'static,
     benchmark_result: Arc<Mutex<Option<BenchmarkResult>>>,
+    replica_addrs: AddrBook<SocketAddr>,
+    traffic: Traffic<SocketAddr>,
+    shards: &Shards,
 ) -> anyhow::Result<()>
 where
     ClientConfig: NewClient<S>,
@@ -210,18 +282,18 @@ where
 
     use replication_control_messages::App::*;
     match &config.app {
-        Null => {
-            spawn_client_sessions(
-                &mut sessions,
-                config,
-                on_buf,
-                || OpLatency::new(Iter(repeat_with(Default::default))),
-                stop.clone(),
-                latencies.clone(),
-                barrier.clone(),
-            )
-            .await?
-        }
+        Null => spawn_client_sessions(
+            &mut sessions,
+            shards,
+            config,
+            on_buf,
+            replica_addrs,
+            traffic.clone(),
+            || OpLatency::new(Iter(repeat_with(Default::default))),
+            stop.clone(),
+            latencies.clone(),
+            barrier.clone(),
+        )?,
         Ycsb(ycsb_config) => {
             use replication_control_messages::YcsbProfile::*;
             let workload = ycsb::Workload::new(
@@ -238,8 +310,11 @@ where
             let mut i = 0;
             spawn_client_sessions(
                 &mut sessions,
+                shards,
                 config,
                 on_buf,
+                replica_addrs,
+                traffic.clone(),
                 || {
                     i += 1;
                     workload.clone_reseed(StdRng::seed_from_u64(117418 + i))
@@ -247,8 +322,7 @@ where
                 stop.clone(),
                 latencies.clone(),
                 barrier.clone(),
-            )
-            .await?
+            )?
         }
     }
 
@@ -271,11 +345,13 @@ where
     benchmark_result.lock().unwrap().replace(BenchmarkResult {
         throughput: latencies.len() as f32,
         latency: latencies.drain(..).sum::<Duration>() / (throughput.floor() as u32 + 1),
+        traffic: traffic_counts(&traffic),
     });
     Ok(())
 }
 
-async fn spawn_client_sessions<
+#[allow(clippy::too_many_arguments)]
+fn spawn_client_sessions<
     S: OnEventUniversal<SessionTimer, Event = Event<S, SessionTimer>>
         + OnTimerUniversal<SessionTimer>
         + Send
@@ -284,8 +360,11 @@ async fn spawn_client_sessions<
     W: Workload + Into<Vec<Duration>> + Send + Sync + 'static,
 >(
     sessions: &mut JoinSet<anyhow::Result<()>>,
+    shards: &Shards,
     config: ClientConfig,
     on_buf: impl Fn(&[u8], &mut Sender<S>) -> anyhow::Result<()> + Clone + Send + Sync + 'static,
+    replica_addrs: AddrBook<SocketAddr>,
+    traffic: Traffic<SocketAddr>,
     mut workload: impl FnMut() -> W,
     stop: CancellationToken,
     latencies: Arc<Mutex<Vec<Duration>>>,
@@ -297,45 +376,80 @@ where
     W::Attach: Send + Sync,
 {
     for client_id in repeat_with(rand::random).take(config.num_close_loop) {
-        let socket = tokio::net::UdpSocket::bind("0.0.0.0:0").await?;
-        let addr = socket.local_addr()?;
-        println!("Client {client_id:08x} bind to {addr}");
-        let net = Udp(socket.into());
-
-        let mut session = Session::new();
-        let mut close_loop_session = Session::new();
-
-        let mut state = config.new_client(
-            client_id,
-            addr,
-            net.clone(),
-            Sender::from(close_loop_session.sender()),
-        );
-        let mut close_loop = Blanket(Unify(CloseLoop::new(
-            Sender::from(session.sender()),
-            workload(),
-        )));
-
-        let mut sender = Sender::from(session.sender());
+        let config = config.clone();
         let on_buf = on_buf.clone();
-        sessions.spawn(async move { net.recv_session(|buf| on_buf(buf, &mut sender)).await });
-        sessions.spawn(async move { session.run(&mut state).await });
+        let replica_addrs = replica_addrs.clone();
+        let traffic = traffic.clone();
+        let workload = workload();
         let stop = stop.clone();
         let latencies = latencies.clone();
         let barrier = barrier.clone();
-        sessions.spawn(async move {
-            Sender::from(close_loop_session.sender()).send(Init)?;
+        // the client and its close loop are spawned together so they always share a shard
+        sessions.spawn(shards.spawn(move || async move {
+            // dual-stack, and the replicas reply to the local address that the requests to them
+            // are sent from
+            let socket = bind_udp(None, 0)?;
+            let addr = SocketAddr::new(
+                route_ip(
+                    *config
+                        .replica_addrs
+                        .first()
+                        .ok_or(anyhow::anyhow!("no replica address"))?,
+                )?,
+                socket.local_addr()?.port(),
+            );
+            println!("Client {client_id:08x} bind to {addr}");
+            let net = Udp(socket.into());
+
+            let mut session = Session::new();
+            let mut close_loop_session = Session::new();
+
+            let mut state = config.new_client(
+                client_id,
+                addr,
+                net.clone(),
+                replica_addrs,
+                traffic,
+                Sender::from(close_loop_session.sender()),
+            );
+            let mut close_loop = Blanket(Unify(CloseLoop::new(
+                Sender::from(session.sender()),
+                workload,
+            )));
+
+            let mut sender = Sender::from(session.sender());
+            let mut reassemble = Reassemble::new(REASSEMBLE_TIMEOUT);
+            let recv_session =
+                net.recv_session(|buf| reassemble.on_buf(buf, |buf| on_buf(buf, &mut sender)));
+            let state_session = session.run(&mut state);
+            let mut close_loop_sender = Sender::from(close_loop_session.sender());
+            let close_loop_session = async {
+                close_loop_sender.send(Init)?;
+                {
+                    let run = close_loop_session.run(&mut close_loop);
+                    tokio::pin!(run);
+                    tokio::select! {
+                        result = &mut run => result?,
+                        () = stop.cancelled() => {
+                            close_loop_sender.send(Stop)?;
+                            run.await?
+                        }
+                    }
+                }
+                latencies
+                    .lock()
+                    .unwrap()
+                    .extend(close_loop.0 .0.workload.into());
+                barrier.wait().await;
+                anyhow::Result::<_>::Ok(())
+            };
             tokio::select! {
-                result = close_loop_session.run(&mut close_loop) => result?,
-                () = stop.cancelled() => {}
+                result = recv_session => result?,
+                result = state_session => result?,
+                result = close_loop_session => return result,
             }
-            latencies
-                .lock()
-                .unwrap()
-                .extend(close_loop.0 .0.workload.into());
-            barrier.wait().await;
-            Ok(())
-        });
+            Err(anyhow::anyhow!("unexpected shutdown"))
+        }));
     }
     Ok(())
 }
@@ -377,13 +491,15 @@ async fn start_replica(State(state): State<AppState>, Json(config): Json<Replica
 
     let cancel = CancellationToken::new();
     let session_cancel = cancel.clone();
-    let handle = spawn_blocking(move || {
-        let runtime = runtime::Builder::new_current_thread()
-            .enable_all()
-            .build()?;
-        let socket = runtime.block_on(tokio::net::UdpSocket::bind(
-            config.replica_addrs[config.replica_id as usize],
-        ))?;
+    let faults = state.faults.clone();
+    let replica_addrs = state.replica_addrs.clone();
+    replica_addrs.set(config.replica_addrs.clone());
+    let traffic = state.traffic.clone();
+    traffic.clear();
+    let crypto_pool = state.crypto_pool.clone();
+    let handle = spawn(state.shards.spawn(move || async move {
+        let socket =
+            tokio::net::UdpSocket::bind(config.replica_addrs[config.replica_id as usize]).await?;
         println!(
             "Replica {} bind to {:?}",
             config.replica_id,
@@ -397,47 +513,110 @@ async fn start_replica(State(state): State<AppState>, Json(config): Json<Replica
             CryptoFlavor::Schnorrkel,
             // CryptoFlavor::Secp256k1,
         )?;
-        let (crypto_worker, mut crypto_executor) = spawn_backend(crypto);
+        // the received signatures are verified in batches
+        let (crypto, mut batch_executor) =
+            batch::spawn_backend(crypto, 32, Duration::from_micros(100));
+        let (crypto_worker, crypto_executor) = spawn_backend(crypto);
+        let mut crypto_executor = crypto_executor
+            .with_concurrency_limit(crypto_pool.current_num_threads())
+            .with_blocking_pool(crypto_pool);
 
         match config.protocol {
             Protocol::Unreplicated => {
                 assert_eq!(config.replica_id, 0);
+                let faulty_net = Metered::new(
+                    Fragmented::new(Faulty::new(net.clone(), faults), MAX_DATAGRAM_SIZE),
+                    traffic.clone(),
+                );
                 let state = Blanket(Unify(unreplicated::Replica::new(
                     app,
-                    Box::new(unreplicated::ToClientMessageNet::new(net.clone())),
+                    Box::new(Counted::from(unreplicated::ToClientMessageNet::new(
+                        faulty_net,
+                    ))),
                 )));
-                runtime.block_on(replica_session(
+                replica_session(
                     state,
-                    unreplicated::erased::to_replica_on_buf,
+                    move |buf: &_, sender: &mut _| {
+                        traffic.on_buf::<augustus::message::Request<SocketAddr>>(recv_from(), buf);
+                        unreplicated::erased::to_replica_on_buf(buf, sender)
+                    },
                     net,
                     |_| pending(),
                     session_cancel,
-                ))
+                )
+                .await
             }
             Protocol::Pbft => {
+                // the sending shares the socket with the receiving `net`. the all-to-all messages
+                // go through the multicast group if it is configured, or else every replica
+                // still gets the same buffer with one `sendmmsg`
+                let local_addr = config.replica_addrs[config.replica_id as usize];
+                let mut broadcast_net =
+                    Broadcast::new(augustus::net::batch::Udp::new(net.0.clone()));
+                let mut group_socket = None;
+                if let Some(group) = config.multicast_group {
+                    let members = config.replica_addrs.iter().copied();
+                    broadcast_net =
+                        broadcast_net.with_group(group, members.filter(|addr| *addr != local_addr));
+                    group_socket = Some(broadcast::join(group, Ipv4Addr::UNSPECIFIED)?)
+                }
+                let faulty_net = Metered::new(
+                    Fragmented::new(
+                        Faulty::new(broadcast_net.clone(), faults),
+                        MAX_DATAGRAM_SIZE,
+                    ),
+                    traffic.clone(),
+                );
+                let group_traffic = traffic.clone();
                 let state = Blanket(Buffered::from(pbft::Replica::<_, SocketAddr>::new(
                     config.replica_id,
                     app,
-                    pbft::ToReplicaMessageNet::new(IndexNet::new(
-                        net.clone(),
-                        config.replica_addrs,
+                    Counted::from(pbft::ToReplicaMessageNet::new(AddrBookNet::new(
+                        faulty_net.clone(),
+                        replica_addrs,
                         config.replica_id as usize,
-                    )),
-                    pbft::ToClientMessageNet::new(net.clone()),
+                    ))),
+                    Counted::from(pbft::ToClientMessageNet::new(faulty_net)),
                     crypto_worker,
                     config.num_replica,
                     config.num_faulty,
                 )));
-                runtime.block_on(replica_session(
+                replica_session(
                     state,
-                    pbft::to_replica_on_buf,
+                    move |buf: &_, sender: &mut _| {
+                        traffic.on_buf::<pbft::ToReplica<SocketAddr>>(recv_from(), buf);
+                        pbft::to_replica_on_buf(buf, sender)
+                    },
                     net,
-                    move |sender| async move { crypto_executor.run(sender, |sender| sender).await },
+                    move |sender| async move {
+                        let mut group_sender = sender.clone();
+                        let group_session = async {
+                            let Some(socket) = group_socket else {
+                                return pending().await;
+                            };
+                            let mut reassemble = Reassemble::new(REASSEMBLE_TIMEOUT);
+                            broadcast::recv_group_session(&socket, local_addr, |buf| {
+                                reassemble.on_buf(buf, |buf| {
+                                    group_traffic
+                                        .on_buf::<pbft::ToReplica<SocketAddr>>(recv_from(), buf);
+                                    pbft::to_replica_on_buf(buf, &mut group_sender)
+                                })
+                            })
+                            .await
+                        };
+                        tokio::select! {
+                            result = crypto_executor.run(sender, |sender| sender) => result,
+                            result = batch_executor.run() => result,
+                            result = broadcast_net.flush_session() => result,
+                            result = group_session => result,
+                        }
+                    },
                     session_cancel,
-                ))
+                )
+                .await
             }
         }
-    });
+    }));
     let replaced = session.replace((handle, cancel));
     assert!(replaced.is_none())
 }
@@ -454,16 +633,24 @@ async fn replica_session<
     net: Udp,
     crypto_session: impl FnOnce(Sender<S>) -> F,
     cancel: CancellationToken,
-) -> anyhow::Result<()> {
+) -> anyhow::Result<()>
+where
+    Sender<S>: SendEvent<Stop>,
+{
     let mut session = Session::new();
     let mut recv_session = spawn({
         let mut sender = Sender::from(session.sender());
-        async move { net.recv_session(|buf| on_buf(buf, &mut sender)).await }
+        let mut reassemble = Reassemble::new(REASSEMBLE_TIMEOUT);
+        async move {
+            net.recv_session(|buf| reassemble.on_buf(buf, |buf| on_buf(buf, &mut sender)))
+                .await
+        }
     });
     let mut crypto_session = spawn({
         let sender = Sender::from(session.sender());
         crypto_session(sender)
     });
+    let mut stop_sender = Sender::from(session.sender());
     let mut state_session = spawn(async move { session.run(&mut state).await });
     'select: {
         tokio::select! {
@@ -474,15 +661,106 @@ async fn replica_session<
         }
         return Err(anyhow::anyhow!("unexpected shutdown"));
     }
+    stop_sender.send(Stop)?;
+    // the state session returns on its own after handling `Stop`, and the other sessions that feed
+    // it are useless since then
+    let result = state_session.await;
     recv_session.abort();
     crypto_session.abort();
-    state_session.abort();
     let _ = recv_session.await;
     let _ = crypto_session.await;
-    let _ = state_session.await;
+    result?
+}
+
+// the local address that the kernel picks as the source of the datagrams to `remote`
+fn route_ip(remote: SocketAddr) -> anyhow::Result<IpAddr> {
+    let unspecified = if remote.is_ipv4() {
+        IpAddr::from(Ipv4Addr::UNSPECIFIED)
+    } else {
+        IpAddr::from(Ipv6Addr::UNSPECIFIED)
+    };
+    // connecting a datagram socket only looks up the route, nothing is sent
+    let socket = std::net::UdpSocket::bind((unspecified, 0))?;
+    socket.connect(remote)?;
+    Ok(socket.local_addr()?.ip())
+}
+
+async fn set_faults(
+    State(state): State<AppState>,
+    Json(config): Json<FaultConfig>,
+) -> Result<(), (StatusCode, String)> {
+    let into_faults = |faults: replication_control_messages::Faults| {
+        let faults = Faults {
+            drop_rate: faults.drop_rate,
+            duplicate_rate: faults.duplicate_rate,
+            reorder_rate: faults.reorder_rate,
+            delay: faults.delay,
+            jitter: faults.jitter,
+            jitter_distribution: match faults.jitter_distribution {
+                replication_control_messages::Jitter::Uniform => fault::Jitter::Uniform,
+                replication_control_messages::Jitter::Normal => fault::Jitter::Normal,
+                replication_control_messages::Jitter::Exponential => fault::Jitter::Exponential,
+            },
+            partitioned: faults.partitioned,
+        };
+        faults
+            .validate()
+            .map_err(|err| (StatusCode::BAD_REQUEST, err.to_string()))?;
+        Ok(faults)
+    };
+    state.faults.set(fault::FaultConfig {
+        default: into_faults(config.default)?,
+        per_dest: config
+            .per_dest
+            .into_iter()
+            .map(|(addr, faults)| Ok((addr, into_faults(faults)?)))
+            .collect::<Result<_, _>>()?,
+    });
     Ok(())
 }
 
+// the moved or replaced replica should be started with the updated addresses as well, and the other
+// nodes should be updated before it starts to reply
+// the replicas are indexed into the list, so it must have as many addresses as the running config
+async fn update_replica_addrs(
+    State(state): State<AppState>,
+    Json(replica_addrs): Json<Vec<SocketAddr>>,
+) -> Result<(), (StatusCode, String)> {
+    let num_replica = state.replica_addrs.len();
+    if replica_addrs.len() != num_replica {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            format!(
+                "expect {num_replica} replica addresses, got {}",
+                replica_addrs.len()
+            ),
+        ));
+    }
+    state.replica_addrs.set(replica_addrs);
+    Ok(())
+}
+
+async fn traffic(State(state): State<AppState>) -> Json<Vec<TrafficCount>> {
+    Json(traffic_counts(&state.traffic))
+}
+
+fn traffic_counts(traffic: &Traffic<SocketAddr>) -> Vec<TrafficCount> {
+    traffic
+        .counts()
+        .into_iter()
+        .map(|(direction, addr, kind, count)| TrafficCount {
+            direction: match direction {
+                Direction::Sent => TrafficDirection::Sent,
+                Direction::Received => TrafficDirection::Received,
+            },
+            addr,
+            kind: kind.into(),
+            messages: count.messages,
+            bytes: count.bytes,
+        })
+        .collect()
+}
+
 async fn stop_replica(State(state): State<AppState>) {
     let (handle, cancel) = {
         let mut session = state.session.lock().unwrap();
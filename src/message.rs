@@ -1,11 +1,72 @@
 use std::fmt::Debug;
 
-use serde::{Deserialize, Serialize};
+use bytes::Bytes;
+use serde::{de::Visitor, Deserialize, Deserializer, Serialize, Serializer};
 
-#[derive(
-    Clone, PartialEq, Eq, PartialOrd, Ord, Hash, Default, derive_more::Deref, Serialize, Deserialize,
-)]
-pub struct Payload(pub Vec<u8>);
+use crate::net::slice_recv_buf;
+
+// `Bytes` instead of `Vec<u8>` so the payload that comes from network can slice
+// the receiving buffer instead of copying out of it. see `net::with_recv_buf`
+#[derive(Clone, PartialEq, Eq, PartialOrd, Ord, Hash, Default, derive_more::Deref)]
+pub struct Payload(pub Bytes);
+
+impl From<Vec<u8>> for Payload {
+    fn from(value: Vec<u8>) -> Self {
+        Self(value.into())
+    }
+}
+
+// same wire format as the `Vec<u8>` one, for bincode at least
+impl Serialize for Payload {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_bytes(&self.0)
+    }
+}
+
+impl<'de> Deserialize<'de> for Payload {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct PayloadVisitor;
+
+        impl<'de> Visitor<'de> for PayloadVisitor {
+            type Value = Payload;
+
+            fn expecting(&self, formatter: &mut std::fmt::Formatter) -> std::fmt::Result {
+                formatter.write_str("bytes")
+            }
+
+            fn visit_borrowed_bytes<E: serde::de::Error>(
+                self,
+                v: &'de [u8],
+            ) -> Result<Self::Value, E> {
+                Ok(Payload(
+                    slice_recv_buf(v).unwrap_or_else(|| Bytes::copy_from_slice(v)),
+                ))
+            }
+
+            fn visit_bytes<E: serde::de::Error>(self, v: &[u8]) -> Result<Self::Value, E> {
+                Ok(Payload(Bytes::copy_from_slice(v)))
+            }
+
+            fn visit_byte_buf<E: serde::de::Error>(self, v: Vec<u8>) -> Result<Self::Value, E> {
+                Ok(v.into())
+            }
+
+            // for the self-describing formats e.g. JSON that encode bytes as sequence
+            fn visit_seq<A: serde::de::SeqAccess<'de>>(
+                self,
+                mut seq: A,
+            ) -> Result<Self::Value, A::Error> {
+                let mut buf = Vec::with_capacity(seq.size_hint().unwrap_or_default());
+                while let Some(b) = seq.next_element()? {
+                    buf.push(b)
+                }
+                Ok(buf.into())
+            }
+        }
+
+        deserializer.deserialize_bytes(PayloadVisitor)
+    }
+}
 
 impl Debug for Payload {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
@@ -32,3 +93,36 @@ pub struct Request<A> {
     pub seq: u32,
     pub op: Payload,
 }
+
+impl<A> crate::net::traffic::Kind for Request<A> {
+    fn kind(&self) -> &'static str {
+        "Request"
+    }
+
+    fn kind_of_buf(_: &[u8]) -> Option<&'static str> {
+        Some("Request")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bincode::Options as _;
+
+    use crate::net::{deserialize, with_recv_buf};
+
+    use super::*;
+
+    #[test]
+    fn payload_slices_recv_buf() -> anyhow::Result<()> {
+        let op = b"hello".to_vec();
+        // wire compatible with the plain `Vec<u8>`
+        let buf = Bytes::from(bincode::options().serialize(&op)?);
+        let payload = with_recv_buf(buf.clone(), deserialize::<Payload>)?;
+        assert_eq!(&*payload.0, &op[..]);
+        assert!(buf.as_ptr_range().contains(&payload.0.as_ptr()));
+
+        let payload = deserialize::<Payload>(&buf)?;
+        assert!(!buf.as_ptr_range().contains(&payload.0.as_ptr()));
+        Ok(())
+    }
+}
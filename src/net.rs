@@ -12,17 +12,38 @@
 // over network stack like TCP. there are many details user cannot specify, but
 // they probably do not care anyway if they are happy with SendMessage<_, _>
 
+pub mod auth;
+pub mod batch;
 pub mod blocking;
+pub mod book;
+pub mod broadcast;
+pub mod codec;
+pub mod fault;
+pub mod fragment;
 pub mod kademlia;
+pub mod memory;
+pub mod mux;
+pub mod ring;
 pub mod session;
+pub mod shape;
+pub mod traffic;
+pub mod unix;
 
-use std::{fmt::Debug, hash::Hash, marker::PhantomData};
+use std::{
+    cell::{Cell, RefCell},
+    fmt::Debug,
+    hash::Hash,
+    marker::PhantomData,
+    net::SocketAddr,
+};
 
 use bincode::Options as _;
-use bytes::Bytes;
+use bytes::{Bytes, BytesMut};
 use serde::{de::DeserializeOwned, Deserialize, Serialize};
 
-use crate::event::SendEvent;
+use crate::event::{SendEvent, Void};
+
+use self::codec::Codec;
 
 pub trait Addr:
     Send + Sync + Clone + Eq + Hash + Debug + Serialize + DeserializeOwned + 'static
@@ -84,6 +105,14 @@ impl<T: ?Sized + SendMessage<A, M>, A, M> SendMessage<A, M> for Box<T> {
     }
 }
 
+// for testing, and for replaying recorded state machines where the outgoing
+// messages are not interested
+impl<A, M> SendMessage<A, M> for Void {
+    fn send(&mut self, _: A, _: M) -> anyhow::Result<()> {
+        Ok(())
+    }
+}
+
 // an `IterAddr` type for broadcast (or multicast, depends on context)
 // serivce provider site should impl `SendMessage<IterAddr<_>, _>`, to fully
 // leverage the combinator that works with generic address types
@@ -119,7 +148,9 @@ pub trait SendMessageToEachExt<A, M>: SendMessageToEach<A, M> {
 impl<T: ?Sized + SendMessageToEach<A, M>, A, M> SendMessageToEachExt<A, M> for T {}
 
 pub mod events {
-    #[derive(Debug, Clone)]
+    use serde::{Deserialize, Serialize};
+
+    #[derive(Debug, Clone, Serialize, Deserialize)]
     pub struct Recv<M>(pub M);
 }
 
@@ -135,12 +166,62 @@ impl<E: SendEvent<M>, M> SendMessage<SendAddr<E>, M> for Auto<SendAddr<E>> {
     }
 }
 
+// the buffers that are produced one after another, e.g. the serialized messages
+// or the received packets, are cut from a pooled chunk instead of allocated one
+// by one. `BytesMut::reserve` reclaims the chunk once every buffer that was cut
+// from it is dropped, or else allocates a new chunk, so with steady traffic the
+// allocation happens once per chunk instead of once per buffer
+// the chunk is not shared between clones
 #[derive(Debug)]
-pub struct MessageNet<T, M>(pub T, PhantomData<M>);
+pub struct Chunk {
+    buf: BytesMut,
+    len: usize,
+}
+
+impl Chunk {
+    pub fn new(len: usize) -> Self {
+        Self {
+            buf: BytesMut::new(),
+            len,
+        }
+    }
+
+    // the chunk to write the next buffer into, with at least `len` bytes of spare capacity
+    pub fn reserve(&mut self, len: usize) -> &mut BytesMut {
+        if self.buf.capacity() - self.buf.len() < len {
+            self.buf.reserve(self.len.max(len))
+        }
+        &mut self.buf
+    }
+
+    // cut everything written since the last cut
+    pub fn split(&mut self) -> Bytes {
+        self.buf.split().freeze()
+    }
+
+    pub fn split_to(&mut self, len: usize) -> Bytes {
+        self.buf.split_to(len).freeze()
+    }
+}
+
+impl Clone for Chunk {
+    fn clone(&self) -> Self {
+        Self::new(self.len)
+    }
+}
+
+#[derive(Debug)]
+pub struct MessageNet<T, M>(pub T, Codec, Chunk, PhantomData<M>);
+
+const SEND_CHUNK: usize = 1 << 16;
 
 impl<T, M> MessageNet<T, M> {
     pub fn new(raw_net: T) -> Self {
-        Self(raw_net, Default::default())
+        Self::with_codec(raw_net, Default::default())
+    }
+
+    pub fn with_codec(raw_net: T, codec: Codec) -> Self {
+        Self(raw_net, codec, Chunk::new(SEND_CHUNK), Default::default())
     }
 }
 
@@ -152,18 +233,21 @@ impl<T, M> From<T> for MessageNet<T, M> {
 
 impl<T: Clone, M> Clone for MessageNet<T, M> {
     fn clone(&self) -> Self {
-        Self::new(self.0.clone())
+        Self(self.0.clone(), self.1, self.2.clone(), Default::default())
     }
 }
 
 impl<T: SendMessage<A, Bytes>, A, M: Into<N>, N: Serialize> SendMessage<A, M> for MessageNet<T, N> {
     fn send(&mut self, dest: A, message: M) -> anyhow::Result<()> {
+        self.1
+            .encode(&message.into(), self.2.reserve(SEND_CHUNK / 4))?;
         // the `dest` may be an IterAddr, use Bytes to reduce cloning overhead
-        let buf = Bytes::from(bincode::options().serialize(&message.into())?);
+        let buf = self.2.split();
         self.0.send(dest, buf)
     }
 }
 
+// the decoding counterpart of the default codec i.e. `Codec::Bincode`
 pub fn deserialize<M: DeserializeOwned>(buf: &[u8]) -> anyhow::Result<M> {
     bincode::options()
         .allow_trailing_bytes()
@@ -171,6 +255,167 @@ pub fn deserialize<M: DeserializeOwned>(buf: &[u8]) -> anyhow::Result<M> {
         .map_err(Into::into)
 }
 
+// declare a message enum together with everything around it. the sending side
+// is the enum itself (with `From` of every variant's type, so the state
+// machines can send the variant types directly) and a `MessageNet` alias, the
+// receiving side is a trait that collects `SendEvent<Recv<_>>` of every
+// variant's type and an `on_buf` function that decodes and dispatches to it.
+// adding a message type is then one line in the declaration
+//
+//   crate::messages! {
+//       pub enum ToReplica<A> {
+//           Request(Request<A>),
+//           Prepare(Verifiable<Prepare>),
+//       }
+//       pub type ToReplicaMessageNet;
+//       pub trait SendReplicaRecvEvent;
+//       pub fn to_replica_on_buf;
+//   }
+//
+// expands to (besides the enum)
+//
+//   pub type ToReplicaMessageNet<T, A> = MessageNet<T, ToReplica<A>>;
+//   pub trait SendReplicaRecvEvent<A>:
+//       SendEvent<Recv<Request<A>>> + SendEvent<Recv<Verifiable<Prepare>>> {}
+//   pub fn to_replica_on_buf<A>(
+//       buf: &[u8],
+//       sender: &mut impl SendReplicaRecvEvent<A>,
+//   ) -> anyhow::Result<()>
+//   where ToReplica<A>: DeserializeOwned;
+//
+// the enum also implements `traffic::Kind` with the variant names
+// every variant has exactly one field, which is delivered as `Recv(field)`.
+// use a tuple type for the variants that carry multiple things, which has the
+// same bincode layout as a multi-field variant
+// the generic parameters of the enum must be plain type parameters without
+// bounds, and the name `T` is taken by the inner net of the `MessageNet` alias
+// the generated code refers to `serde`, `derive_more` and `anyhow` of the
+// calling crate
+// the receiving side assumes a single receiver session. a message enum that is
+// dispatched to multiple sessions e.g. the entropy one is still hand-written
+#[macro_export]
+macro_rules! messages {
+    (
+        $(#[$meta:meta])*
+        $vis:vis enum $name:ident $(<$($g:ident),+>)? {
+            $($variant:ident($ty:ty)),+ $(,)?
+        }
+        $net_vis:vis type $net:ident;
+        $send_vis:vis trait $send:ident;
+        $on_buf_vis:vis fn $on_buf:ident;
+    ) => {
+        #[derive(Debug, Clone, ::serde::Serialize, ::serde::Deserialize, ::derive_more::From)]
+        $(#[$meta])*
+        $vis enum $name $(<$($g),+>)? {
+            $($variant($ty)),+
+        }
+
+        $net_vis type $net<T $($(, $g)+)?> = $crate::net::MessageNet<T, $name $(<$($g),+>)?>;
+
+        $send_vis trait $send $(<$($g),+>)?:
+            $($crate::event::SendEvent<$crate::net::events::Recv<$ty>> +)+
+        {
+        }
+        impl<T: $($crate::event::SendEvent<$crate::net::events::Recv<$ty>> +)+ $($(, $g)+)?>
+            $send $(<$($g),+>)? for T
+        {
+        }
+
+        impl $(<$($g),+>)? $crate::net::traffic::Kind for $name $(<$($g),+>)? {
+            fn kind(&self) -> &'static str {
+                match self {
+                    $($name::$variant(_) => stringify!($variant)),+
+                }
+            }
+
+            fn kind_of_buf(buf: &[u8]) -> Option<&'static str> {
+                // the variant index is the leading varint, which is a single byte below 251
+                const KINDS: &[&str] = &[$(stringify!($variant)),+];
+                KINDS.get(*buf.first()? as usize).copied()
+            }
+        }
+
+        $on_buf_vis fn $on_buf $(<$($g),+>)?(
+            buf: &[u8],
+            sender: &mut impl $send $(<$($g),+>)?,
+        ) -> ::anyhow::Result<()>
+        where
+            $name $(<$($g),+>)?: ::serde::de::DeserializeOwned,
+        {
+            match $crate::net::deserialize(buf)? {
+                $($name::$variant(message) => {
+                    $crate::event::SendEvent::send(sender, $crate::net::events::Recv(message))
+                })+
+            }
+        }
+    };
+}
+
+// receiving side filters
+// the `on_buf` filters that stack between a receiving loop and the protocol's
+// `*_on_buf` e.g. `auth::Keys::on_buf`, `fragment::Reassemble::on_buf` and
+// `mux::Demux::on_buf` drop the buffers they cannot handle (malformed, failing
+// authentication, etc.) with a warning instead of failing `on_buf`. an error
+// from `on_buf` terminates the receiving loop, so a random host must not be
+// able to produce one by sending garbage
+
+// zero-copy receiving
+// the `on_buf` callbacks take borrowed `&[u8]`, and the messages are
+// deserialized into owned types, so the bytes fields e.g. `Payload` must be
+// copied out of the receiving buffer by default. a receiving loop that owns the
+// buffer as `Bytes` may run `on_buf` inside `with_recv_buf`, then those fields
+// can instead get a `Bytes` that refers to the receiving buffer with
+// `slice_recv_buf`
+// this goes through a thread local instead of changing the `on_buf` signature,
+// so all the existing `*_on_buf` functions benefit without modification
+// be aware that a sliced field keeps the whole receiving buffer alive. the
+// receiving loops should cut the buffers from a `Chunk` instead of allocating
+// one for every packet
+thread_local! {
+    static RECV_BUF: RefCell<Option<Bytes>> = const { RefCell::new(None) };
+}
+
+pub fn with_recv_buf<T>(buf: Bytes, f: impl FnOnce(&[u8]) -> T) -> T {
+    let saved = RECV_BUF.with(|recv_buf| recv_buf.replace(Some(buf.clone())));
+    let output = f(&buf);
+    RECV_BUF.with(|recv_buf| *recv_buf.borrow_mut() = saved);
+    output
+}
+
+pub fn slice_recv_buf(slice: &[u8]) -> Option<Bytes> {
+    RECV_BUF.with(|recv_buf| {
+        let recv_buf = recv_buf.borrow();
+        let recv_buf = recv_buf.as_ref()?;
+        let range = recv_buf.as_ptr_range();
+        if range.start <= slice.as_ptr() && slice.as_ptr_range().end <= range.end {
+            Some(recv_buf.slice_ref(slice))
+        } else {
+            None
+        }
+    })
+}
+
+// the source address of the buffer being received, through a thread local for the same reason
+// as `with_recv_buf`. the receiving loops of the IP raw nets run `on_buf` inside `with_recv_from`,
+// so e.g. `traffic::Traffic::on_buf` can tell where a message comes from
+thread_local! {
+    static RECV_FROM: Cell<Option<SocketAddr>> = const { Cell::new(None) };
+}
+
+pub fn with_recv_from<T>(remote: SocketAddr, f: impl FnOnce() -> T) -> T {
+    // a dual-stack socket tells the IPv4 remotes as IPv4-mapped IPv6 addresses, which should match
+    // the IPv4 addresses that are sent to
+    let remote = SocketAddr::new(remote.ip().to_canonical(), remote.port());
+    let saved = RECV_FROM.with(|recv_from| recv_from.replace(Some(remote)));
+    let output = f();
+    RECV_FROM.with(|recv_from| recv_from.set(saved));
+    output
+}
+
+pub fn recv_from() -> Option<SocketAddr> {
+    RECV_FROM.with(Cell::get)
+}
+
 #[derive(Debug, Clone, derive_more::Deref, derive_more::DerefMut)]
 pub struct IndexNet<N, A> {
     #[deref]
@@ -216,3 +461,48 @@ impl<N: for<'a> SendMessageToEach<A, M>, A: Addr, M> SendMessage<All, M> for Ind
         self.inner_net.send_to_each(addrs, message)
     }
 }
+
+// a raw net that records every sent message with its destination, for testing the nets that are
+// stacked on top of raw nets. the clones share the record
+#[cfg(test)]
+#[derive(Debug)]
+pub struct Capture<A, M>(pub std::sync::Arc<std::sync::Mutex<Vec<(A, M)>>>);
+
+#[cfg(test)]
+impl<A, M> Capture<A, M> {
+    pub fn take(&self) -> Vec<(A, M)> {
+        std::mem::take(&mut *self.0.lock().unwrap())
+    }
+}
+
+#[cfg(test)]
+impl<A, M> Clone for Capture<A, M> {
+    fn clone(&self) -> Self {
+        Self(self.0.clone())
+    }
+}
+
+#[cfg(test)]
+impl<A, M> Default for Capture<A, M> {
+    fn default() -> Self {
+        Self(Default::default())
+    }
+}
+
+#[cfg(test)]
+impl<A, M> SendMessage<A, M> for Capture<A, M> {
+    fn send(&mut self, dest: A, message: M) -> anyhow::Result<()> {
+        self.0.lock().unwrap().push((dest, message));
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+impl<A, M: Clone> SendMessage<IterAddr<'_, A>, M> for Capture<A, M> {
+    fn send(&mut self, dest: IterAddr<'_, A>, message: M) -> anyhow::Result<()> {
+        for dest in dest.0 {
+            self.send(dest, message.clone())?
+        }
+        Ok(())
+    }
+}
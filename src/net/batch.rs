@@ -0,0 +1,446 @@
+// batched UDP raw net
+//
+// `session::Udp` spawns a Tokio task and makes a `send_to` syscall for every
+// outgoing datagram, and makes a `recv_from` syscall for every incoming one.
+// this raw net instead queues the outgoing buffers, and a flushing loop sends
+// all of them at once with `sendmmsg`. the flushing loop is woken up by the
+// first queued buffer but only gets to run after the sending task yields, so
+// everything sent by the state machine in one event loop tick (and more, under
+// load) ends up in the same batch. the receiving loop similarly takes up to
+// `RECV_BATCH` datagrams with one `recvmmsg`
+//
+// with `with_gso`, consecutive buffers that are sent to the same destination
+// are further coalesced into one UDP GSO message, i.e. the kernel does the
+// segmentation, as long as they are in the same size except the last one. this
+// is the case for e.g. bulk data that is split into fixed size chunks, while
+// less useful for the replication protocols where the consecutive messages
+// mostly go to different destinations. GSO requires Linux 4.18 and a network
+// device that supports it (or the kernel falls back to software segmentation),
+// so it is opt-in
+//
+// rustix does not cover `sendmmsg`, `recvmmsg` and the GSO control messages
+// (yet), so they are called through libc
+
+use std::{
+    io::{self, ErrorKind},
+    mem::{size_of, zeroed},
+    net::{Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6},
+    ops::Range,
+    os::fd::{AsRawFd as _, RawFd},
+    ptr::null_mut,
+    sync::{Arc, Mutex},
+};
+
+use bytes::Bytes;
+use tokio::{io::Interest, net::UdpSocket, sync::Notify};
+use tracing::warn;
+
+use super::{with_recv_buf, with_recv_from, Chunk, IterAddr, SendMessage};
+
+// UIO_MAXIOV, the limit of both the number of messages per `sendmmsg` and the
+// number of segments per message
+const MAX_BATCH: usize = 1024;
+// UDP_MAX_SEGMENTS
+const MAX_SEGMENTS: usize = 64;
+// leave room for headers
+const MAX_GSO_LEN: usize = 65000;
+const RECV_BATCH: usize = 16;
+const RECV_SLOT: usize = 1 << 16;
+// not exported by libc for glibc targets
+const UDP_SEGMENT: libc::c_int = 103;
+
+#[derive(Debug, Clone)]
+pub struct Udp {
+    socket: Arc<UdpSocket>,
+    queue: Arc<Mutex<Vec<(SocketAddr, Bytes)>>>,
+    flush: Arc<Notify>,
+    gso: bool,
+}
+
+impl Udp {
+    pub fn new(socket: impl Into<Arc<UdpSocket>>) -> Self {
+        Self {
+            socket: socket.into(),
+            queue: Default::default(),
+            flush: Default::default(),
+            gso: false,
+        }
+    }
+
+    pub fn with_gso(self) -> Self {
+        Self { gso: true, ..self }
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.socket.local_addr()
+    }
+
+    pub async fn flush_session(&self) -> anyhow::Result<()> {
+        let mut bufs = Vec::new();
+        loop {
+            self.flush.notified().await;
+            std::mem::swap(&mut *self.queue.lock().unwrap(), &mut bufs);
+            let mut messages = messages(&bufs, self.gso);
+            let mut sent = 0;
+            while sent < messages.len() {
+                let fd = self.socket.as_raw_fd();
+                match self
+                    .socket
+                    .async_io(Interest::WRITABLE, || {
+                        sendmmsg(fd, &bufs, &messages[sent..])
+                    })
+                    .await
+                {
+                    Ok(num_sent) => sent += num_sent,
+                    Err(err) if err.kind() == ErrorKind::Interrupted => {}
+                    // the error belongs to the first message. if it is a GSO message, e.g. the
+                    // device turns out to not support it, retry its datagrams one by one
+                    Err(err) if messages[sent].1.is_some() => {
+                        warn!("{:?} GSO {err}, fall back", self.socket.local_addr());
+                        let range = messages[sent].0.clone();
+                        messages.splice(sent..sent + 1, range.map(|i| (i..i + 1, None)));
+                    }
+                    // otherwise drop it and move on, as what `session::Udp` does
+                    Err(err) => {
+                        warn!(
+                            "{:?} >>> {} {err}",
+                            self.socket.local_addr(),
+                            bufs[messages[sent].0.start].0
+                        );
+                        sent += 1
+                    }
+                }
+            }
+            bufs.clear()
+        }
+    }
+
+    pub async fn recv_session(
+        &self,
+        mut on_buf: impl FnMut(&[u8]) -> anyhow::Result<()>,
+    ) -> anyhow::Result<()> {
+        // the datagrams are received into a fixed set of slots that is reused across batches,
+        // then only the received bytes are copied into a chunk that holds many of them, see
+        // `with_recv_buf`. so a short datagram does not pin a whole slot, and the slots are only
+        // zeroed once, to keep the unsafe part small
+        let mut slots = vec![0; RECV_BATCH * RECV_SLOT];
+        let mut chunk = Chunk::new(RECV_BATCH * RECV_SLOT);
+        let mut received = Vec::new();
+        loop {
+            let fd = self.socket.as_raw_fd();
+            match self
+                .socket
+                .async_io(Interest::READABLE, || {
+                    recvmmsg(fd, &mut slots, &mut received)
+                })
+                .await
+            {
+                Ok(()) => {}
+                Err(err) if err.kind() == ErrorKind::Interrupted => continue,
+                Err(err) => Err(err)?,
+            }
+            for (slot, (len, truncated, remote)) in
+                slots.chunks_exact(RECV_SLOT).zip(received.drain(..))
+            {
+                if truncated {
+                    warn!("truncated datagram of {len} bytes");
+                    continue;
+                }
+                chunk.reserve(len).extend_from_slice(&slot[..len]);
+                let buf = chunk.split();
+                match remote {
+                    Some(remote) => with_recv_from(remote, || with_recv_buf(buf, &mut on_buf))?,
+                    None => with_recv_buf(buf, &mut on_buf)?,
+                }
+            }
+        }
+    }
+}
+
+impl SendMessage<SocketAddr, Bytes> for Udp {
+    fn send(&mut self, dest: SocketAddr, buf: Bytes) -> anyhow::Result<()> {
+        let mut queue = self.queue.lock().unwrap();
+        if queue.is_empty() {
+            self.flush.notify_one()
+        }
+        queue.push((dest, buf));
+        Ok(())
+    }
+}
+
+impl SendMessage<IterAddr<'_, SocketAddr>, Bytes> for Udp {
+    fn send(&mut self, dest: IterAddr<'_, SocketAddr>, buf: Bytes) -> anyhow::Result<()> {
+        let mut queue = self.queue.lock().unwrap();
+        if queue.is_empty() {
+            self.flush.notify_one()
+        }
+        queue.extend(dest.0.map(|addr| (addr, buf.clone())));
+        Ok(())
+    }
+}
+
+// group the buffers into messages, each message is a range of the buffers with
+// a segment size if it is a GSO message
+fn messages(bufs: &[(SocketAddr, Bytes)], gso: bool) -> Vec<(Range<usize>, Option<u16>)> {
+    let mut messages = Vec::new();
+    let mut start = 0;
+    while start < bufs.len() {
+        let (dest, buf) = &bufs[start];
+        let segment_len = buf.len();
+        let mut end = start + 1;
+        if gso && segment_len > 0 {
+            let mut len = segment_len;
+            while end < bufs.len()
+                && end - start < MAX_SEGMENTS
+                && bufs[end].0 == *dest
+                && bufs[end - 1].1.len() == segment_len
+                && (1..=segment_len).contains(&bufs[end].1.len())
+                && len + bufs[end].1.len() <= MAX_GSO_LEN
+            {
+                len += bufs[end].1.len();
+                end += 1
+            }
+        }
+        messages.push((
+            start..end,
+            // a single datagram never exceeds u16
+            (end - start > 1).then_some(segment_len as u16),
+        ));
+        start = end
+    }
+    messages
+}
+
+fn sockaddr(addr: SocketAddr) -> (libc::sockaddr_storage, libc::socklen_t) {
+    // all zero is a valid `sockaddr_storage`
+    let mut storage = unsafe { zeroed::<libc::sockaddr_storage>() };
+    let len = match addr {
+        SocketAddr::V4(addr) => {
+            let sin = libc::sockaddr_in {
+                sin_family: libc::AF_INET as _,
+                sin_port: addr.port().to_be(),
+                sin_addr: libc::in_addr {
+                    s_addr: u32::from_ne_bytes(addr.ip().octets()),
+                },
+                sin_zero: Default::default(),
+            };
+            // `sockaddr_storage` is large and aligned enough for any `sockaddr_*`
+            unsafe {
+                (&mut storage as *mut libc::sockaddr_storage)
+                    .cast::<libc::sockaddr_in>()
+                    .write(sin)
+            }
+            size_of::<libc::sockaddr_in>()
+        }
+        SocketAddr::V6(addr) => {
+            let sin6 = libc::sockaddr_in6 {
+                sin6_family: libc::AF_INET6 as _,
+                sin6_port: addr.port().to_be(),
+                sin6_flowinfo: addr.flowinfo(),
+                sin6_addr: libc::in6_addr {
+                    s6_addr: addr.ip().octets(),
+                },
+                sin6_scope_id: addr.scope_id(),
+            };
+            unsafe {
+                (&mut storage as *mut libc::sockaddr_storage)
+                    .cast::<libc::sockaddr_in6>()
+                    .write(sin6)
+            }
+            size_of::<libc::sockaddr_in6>()
+        }
+    };
+    (storage, len as _)
+}
+
+fn socket_addr(storage: &libc::sockaddr_storage) -> Option<SocketAddr> {
+    // `sockaddr_storage` is large and aligned enough for any `sockaddr_*`, and the family tells
+    // which one it holds
+    match storage.ss_family as libc::c_int {
+        libc::AF_INET => {
+            let sin =
+                unsafe { &*(storage as *const libc::sockaddr_storage).cast::<libc::sockaddr_in>() };
+            Some(SocketAddr::V4(SocketAddrV4::new(
+                Ipv4Addr::from(sin.sin_addr.s_addr.to_ne_bytes()),
+                u16::from_be(sin.sin_port),
+            )))
+        }
+        libc::AF_INET6 => {
+            let sin6 = unsafe {
+                &*(storage as *const libc::sockaddr_storage).cast::<libc::sockaddr_in6>()
+            };
+            Some(SocketAddr::V6(SocketAddrV6::new(
+                Ipv6Addr::from(sin6.sin6_addr.s6_addr),
+                u16::from_be(sin6.sin6_port),
+                sin6.sin6_flowinfo,
+                sin6.sin6_scope_id,
+            )))
+        }
+        _ => None,
+    }
+}
+
+// returns the number of sent messages
+fn sendmmsg(
+    fd: RawFd,
+    bufs: &[(SocketAddr, Bytes)],
+    messages: &[(Range<usize>, Option<u16>)],
+) -> io::Result<usize> {
+    let messages = &messages[..messages.len().min(MAX_BATCH)];
+    let offset = messages[0].0.start;
+    let iovecs = bufs[offset..messages.last().unwrap().0.end]
+        .iter()
+        .map(|(_, buf)| libc::iovec {
+            // not written through by `sendmmsg`
+            iov_base: buf.as_ptr() as *mut _,
+            iov_len: buf.len(),
+        })
+        .collect::<Vec<_>>();
+    let mut addrs = messages
+        .iter()
+        .map(|(range, _)| sockaddr(bufs[range.start].0))
+        .collect::<Vec<_>>();
+    // 8-byte aligned, and large enough for CMSG_SPACE(size_of::<u16>())
+    let mut controls = vec![[0u64; 4]; messages.len()];
+    let mut headers = Vec::with_capacity(messages.len());
+    for (((range, segment_len), (addr, addr_len)), control) in
+        messages.iter().zip(&mut addrs).zip(&mut controls)
+    {
+        let mut header = unsafe { zeroed::<libc::msghdr>() };
+        header.msg_name = (addr as *mut libc::sockaddr_storage).cast();
+        header.msg_namelen = *addr_len;
+        header.msg_iov = iovecs[range.start - offset..].as_ptr() as *mut _;
+        header.msg_iovlen = range.len() as _;
+        if let Some(segment_len) = segment_len {
+            header.msg_control = control.as_mut_ptr().cast();
+            header.msg_controllen = unsafe { libc::CMSG_SPACE(size_of::<u16>() as _) } as _;
+            // the control buffer is valid and large enough for one cmsg as set above
+            unsafe {
+                let cmsg = libc::CMSG_FIRSTHDR(&header);
+                (*cmsg).cmsg_level = libc::SOL_UDP;
+                (*cmsg).cmsg_type = UDP_SEGMENT;
+                (*cmsg).cmsg_len = libc::CMSG_LEN(size_of::<u16>() as _) as _;
+                libc::CMSG_DATA(cmsg)
+                    .cast::<u16>()
+                    .write_unaligned(*segment_len)
+            }
+        }
+        headers.push(libc::mmsghdr {
+            msg_hdr: header,
+            msg_len: 0,
+        })
+    }
+    // every pointer in the headers refers to the locals above that outlive the call
+    let num_sent = unsafe { libc::sendmmsg(fd, headers.as_mut_ptr(), headers.len() as _, 0) };
+    if num_sent < 0 {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(num_sent as _)
+    }
+}
+
+// receive into the `RECV_SLOT`-sized `slots`, push (length, is truncated, source) of the
+// received datagrams into `received`
+fn recvmmsg(
+    fd: RawFd,
+    slots: &mut [u8],
+    received: &mut Vec<(usize, bool, Option<SocketAddr>)>,
+) -> io::Result<()> {
+    let mut iovecs = slots
+        .chunks_exact_mut(RECV_SLOT)
+        .take(RECV_BATCH)
+        .map(|slot| libc::iovec {
+            iov_base: slot.as_mut_ptr().cast(),
+            iov_len: slot.len(),
+        })
+        .collect::<Vec<_>>();
+    // all zero is a valid `sockaddr_storage`
+    let mut addrs = vec![unsafe { zeroed::<libc::sockaddr_storage>() }; iovecs.len()];
+    let mut headers = iovecs
+        .iter_mut()
+        .zip(&mut addrs)
+        .map(|(iovec, addr)| {
+            let mut header = unsafe { zeroed::<libc::msghdr>() };
+            header.msg_name = (addr as *mut libc::sockaddr_storage).cast();
+            header.msg_namelen = size_of::<libc::sockaddr_storage>() as _;
+            header.msg_iov = iovec;
+            header.msg_iovlen = 1;
+            libc::mmsghdr {
+                msg_hdr: header,
+                msg_len: 0,
+            }
+        })
+        .collect::<Vec<_>>();
+    // the socket is nonblocking, so this returns whatever is available, or `WouldBlock` if
+    // nothing is
+    let num_received =
+        unsafe { libc::recvmmsg(fd, headers.as_mut_ptr(), headers.len() as _, 0, null_mut()) };
+    if num_received < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    received.extend(
+        headers[..num_received as usize]
+            .iter()
+            .zip(&addrs)
+            .map(|(header, addr)| {
+                (
+                    header.msg_len as usize,
+                    header.msg_hdr.msg_flags & libc::MSG_TRUNC != 0,
+                    socket_addr(addr),
+                )
+            }),
+    );
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::sync::mpsc::unbounded_channel;
+
+    use super::*;
+
+    async fn loopback(gso: bool) -> anyhow::Result<()> {
+        let mut sender = Udp::new(UdpSocket::bind("127.0.0.1:0").await?);
+        if gso {
+            sender = sender.with_gso()
+        }
+        let receiver = Udp::new(UdpSocket::bind("127.0.0.1:0").await?);
+        let dest = receiver.local_addr()?;
+        let source = sender.local_addr()?;
+        let (buf_sender, mut buf_receiver) = unbounded_channel();
+        let recv_session = receiver.recv_session(|buf| {
+            assert_eq!(crate::net::recv_from(), Some(source));
+            Ok(buf_sender.send(buf.to_vec())?)
+        });
+        let flush_session = sender.flush_session();
+        let sends = async {
+            for i in 0..100u8 {
+                // the last one of every 10 is shorter, exercising the GSO grouping
+                let len = if i % 10 == 9 { 10 } else { 100 };
+                sender.clone().send(dest, Bytes::from(vec![i; len]))?
+            }
+            for i in 0..100u8 {
+                let buf = buf_receiver.recv().await.unwrap();
+                assert_eq!(buf[0], i);
+                assert_eq!(buf.len(), if i % 10 == 9 { 10 } else { 100 });
+            }
+            anyhow::Result::<_>::Ok(())
+        };
+        tokio::select! {
+            result = recv_session => result?,
+            result = flush_session => result?,
+            result = sends => return result,
+        }
+        anyhow::bail!("unexpected exit")
+    }
+
+    #[tokio::test]
+    async fn batch() -> anyhow::Result<()> {
+        loopback(false).await
+    }
+
+    #[tokio::test]
+    async fn gso() -> anyhow::Result<()> {
+        loopback(true).await
+    }
+}
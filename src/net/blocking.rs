@@ -1,6 +1,6 @@
 use std::{
     io::ErrorKind,
-    net::{SocketAddr, UdpSocket},
+    net::{IpAddr, SocketAddr, UdpSocket},
     sync::Arc,
     time::Instant,
 };
@@ -11,6 +11,13 @@ use super::{Buf, IterAddr, SendMessage};
 pub struct Udp(pub Arc<UdpSocket>);
 
 impl Udp {
+    // dual-stack without `ip`, see `session::bind_socket`
+    pub fn bind(ip: impl Into<Option<IpAddr>>, port: u16) -> anyhow::Result<Self> {
+        let fd =
+            super::session::bind_socket(ip.into(), port, rustix::net::SocketType::DGRAM, false)?;
+        Ok(Self(Arc::new(fd.into())))
+    }
+
     pub fn recv(
         &self,
         mut on_buf: impl FnMut(&[u8]) -> anyhow::Result<()>,
@@ -24,7 +31,7 @@ impl Udp {
                 if timeout.is_zero() {
                     return Ok(());
                 }
-                self.0.set_write_timeout(Some(timeout))?;
+                self.0.set_read_timeout(Some(timeout))?;
             }
             let (len, _) = match self.0.recv_from(&mut buf) {
                 Ok(result) => result,
@@ -53,3 +60,20 @@ impl<B: Buf> SendMessage<IterAddr<'_, SocketAddr>, B> for Udp {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::{net::Ipv4Addr, time::Duration};
+
+    use super::*;
+
+    #[test]
+    fn recv_deadline() -> anyhow::Result<()> {
+        let net = Udp::bind(IpAddr::from(Ipv4Addr::LOCALHOST), 0)?;
+        let start = Instant::now();
+        net.recv(|_| unreachable!(), start + Duration::from_millis(50))?;
+        let elapsed = start.elapsed();
+        assert!(elapsed >= Duration::from_millis(50) && elapsed < Duration::from_secs(1));
+        Ok(())
+    }
+}
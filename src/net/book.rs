@@ -0,0 +1,171 @@
+// address book routing net
+//
+// `IndexNet` translates replica ids into addresses with a table that is fixed
+// at construction. `AddrBookNet` does the same, with the table behind a shared
+// `AddrBook` handle instead, which replaces the table when it is sent an
+// `UpdateAddrs`. a replica can then be moved to another address, or replaced by
+// a fresh node, in the middle of an experiment without reconstructing the
+// state machines that own the nets, similar to how `fault::FaultControl` works.
+// the state machines keep addressing by `u8` and `All`, so they are not aware
+// of the change at all
+//
+// this is only the routing part of membership changes. the messages that have
+// been sent to the old address are lost as usual, and it is up to the protocol
+// (or the experiment) to bring the new node up to date
+
+use std::sync::{Arc, Mutex};
+
+use crate::event::SendEvent;
+
+use super::{All, SendMessage, SendMessageToEach, SendMessageToEachExt as _};
+
+#[derive(Debug, Clone)]
+pub struct UpdateAddrs<A>(pub Vec<A>);
+
+// the table is replaced as a whole instead of modified in place, so the nets take a snapshot of it
+// and release the lock before sending. a message that is being sent concurrently with an update
+// goes to either the old or the new addresses, and a broadcast never mixes the two
+#[derive(Debug)]
+pub struct AddrBook<A>(Arc<Mutex<Arc<Vec<A>>>>);
+
+impl<A> Clone for AddrBook<A> {
+    fn clone(&self) -> Self {
+        Self(self.0.clone())
+    }
+}
+
+impl<A> Default for AddrBook<A> {
+    fn default() -> Self {
+        Self(Default::default())
+    }
+}
+
+impl<A> AddrBook<A> {
+    pub fn new(addrs: Vec<A>) -> Self {
+        Self(Arc::new(Mutex::new(Arc::new(addrs))))
+    }
+
+    pub fn set(&self, addrs: Vec<A>) {
+        *self.0.lock().unwrap() = Arc::new(addrs)
+    }
+
+    pub fn len(&self) -> usize {
+        self.0.lock().unwrap().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    fn snapshot(&self) -> Arc<Vec<A>> {
+        self.0.lock().unwrap().clone()
+    }
+}
+
+impl<A: Clone> AddrBook<A> {
+    pub fn addrs(&self) -> Vec<A> {
+        self.snapshot().to_vec()
+    }
+}
+
+impl<A> SendEvent<UpdateAddrs<A>> for AddrBook<A> {
+    fn send(&mut self, UpdateAddrs(addrs): UpdateAddrs<A>) -> anyhow::Result<()> {
+        self.set(addrs);
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, derive_more::Deref, derive_more::DerefMut)]
+pub struct AddrBookNet<N, A> {
+    #[deref]
+    #[deref_mut]
+    inner_net: N,
+    book: AddrBook<A>,
+    local_index: Option<usize>,
+}
+
+impl<N, A> AddrBookNet<N, A> {
+    pub fn new(inner_net: N, book: AddrBook<A>, local_index: impl Into<Option<usize>>) -> Self {
+        Self {
+            inner_net,
+            book,
+            local_index: local_index.into(),
+        }
+    }
+}
+
+impl<N: SendMessage<A, M>, A: Clone, M, B: Into<usize>> SendMessage<B, M> for AddrBookNet<N, A> {
+    fn send(&mut self, dest: B, message: M) -> anyhow::Result<()> {
+        let addrs = self.book.snapshot();
+        let dest = addrs
+            .get(dest.into())
+            .ok_or(anyhow::anyhow!("index out of bound"))?;
+        self.inner_net.send(dest.clone(), message)
+    }
+}
+
+impl<N: for<'a> SendMessageToEach<A, M>, A: Clone + Send + Sync, M> SendMessage<All, M>
+    for AddrBookNet<N, A>
+{
+    fn send(&mut self, All: All, message: M) -> anyhow::Result<()> {
+        let addrs = self.book.snapshot();
+        let addrs = addrs.iter().enumerate().filter_map(|(id, addr)| {
+            if self.local_index == Some(id) {
+                None
+            } else {
+                Some(addr.clone())
+            }
+        });
+        self.inner_net.send_to_each(addrs, message)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::net::Capture;
+
+    use super::*;
+
+    #[test]
+    fn update() -> anyhow::Result<()> {
+        let mut book = AddrBook::new(vec![10, 11, 12]);
+        let mut net = AddrBookNet::new(Capture::default(), book.clone(), 0);
+        net.send(1u8, 0)?;
+        net.send(All, 1)?;
+        book.send(UpdateAddrs(vec![10, 21, 12, 13]))?;
+        net.send(1u8, 2)?;
+        net.send(All, 3)?;
+        assert_eq!(
+            net.inner_net.take(),
+            [
+                (11, 0),
+                (11, 1),
+                (12, 1),
+                (21, 2),
+                (21, 3),
+                (12, 3),
+                (13, 3)
+            ]
+        );
+        Ok(())
+    }
+
+    // updates the book it routes through while it is sending
+    struct Mover(AddrBook<u16>);
+
+    impl SendMessage<u16, u8> for Mover {
+        fn send(&mut self, dest: u16, _: u8) -> anyhow::Result<()> {
+            self.0.set(vec![dest + 10]);
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn update_while_sending() -> anyhow::Result<()> {
+        let book = AddrBook::new(vec![10]);
+        let mut net = AddrBookNet::new(Mover(book.clone()), book.clone(), None);
+        net.send(0u8, 0)?;
+        assert_eq!(book.addrs(), [20]);
+        Ok(())
+    }
+}
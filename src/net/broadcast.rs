@@ -0,0 +1,189 @@
+// broadcast raw net with optional IP multicast
+//
+// `MessageNet` already serializes a message once for `IterAddr`, and the
+// batched `batch::Udp` below shares the resulting `Bytes` among all
+// destinations and sends them with one `sendmmsg`. on top of that, if a
+// multicast group is configured and the destinations of a broadcast are exactly
+// the group members, a single datagram is sent to the group instead, and the
+// network duplicates it. this is the case for e.g. PBFT's all-to-all `Prepare`
+// and `Commit`, where every replica sends to all the others. any other
+// destination set, e.g. a subset of the replicas or a client, falls back to
+// the unicast sending
+//
+// multicast requires the network to route it (mostly the same L2 segment, or
+// IGMP snooping on the switches), which is not the case in e.g. most public
+// clouds, so it is opt-in with `with_group`. every member joins the group with
+// a separated socket from `join`, and runs `recv_group_session` on it in
+// addition to the usual receiving session on the unicast socket. the receiving
+// side filters out the copy looped back to the sender itself, so the members
+// observe the same messages as with the unicast sending
+//
+// only IPv4 multicast is supported for now
+
+use std::{
+    collections::BTreeSet,
+    net::{Ipv4Addr, SocketAddr, SocketAddrV4},
+};
+
+use bytes::Bytes;
+use tokio::net::UdpSocket;
+
+use super::{batch, with_recv_buf, with_recv_from, Chunk, IterAddr, SendMessage};
+
+#[derive(Debug, Clone)]
+pub struct Broadcast {
+    pub inner: batch::Udp,
+    group: Option<(SocketAddr, BTreeSet<SocketAddr>)>,
+}
+
+impl Broadcast {
+    pub fn new(inner: batch::Udp) -> Self {
+        Self { inner, group: None }
+    }
+
+    // `members` are the unicast addresses of the nodes that has joined `group`, excluding the
+    // sender itself
+    pub fn with_group(
+        self,
+        group: SocketAddrV4,
+        members: impl IntoIterator<Item = SocketAddr>,
+    ) -> Self {
+        assert!(group.ip().is_multicast());
+        Self {
+            group: Some((group.into(), members.into_iter().collect())),
+            ..self
+        }
+    }
+
+    pub async fn flush_session(&self) -> anyhow::Result<()> {
+        self.inner.flush_session().await
+    }
+}
+
+impl SendMessage<SocketAddr, Bytes> for Broadcast {
+    fn send(&mut self, dest: SocketAddr, buf: Bytes) -> anyhow::Result<()> {
+        self.inner.send(dest, buf)
+    }
+}
+
+impl SendMessage<IterAddr<'_, SocketAddr>, Bytes> for Broadcast {
+    fn send(&mut self, dest: IterAddr<'_, SocketAddr>, buf: Bytes) -> anyhow::Result<()> {
+        let Some((group, members)) = &self.group else {
+            return self.inner.send(dest, buf);
+        };
+        // the destinations are few, collecting them is cheaper than the syscalls saved
+        let dests = dest.0.collect::<BTreeSet<_>>();
+        if dests == *members {
+            self.inner.send(*group, buf)
+        } else {
+            self.inner.send(IterAddr(&mut dests.into_iter()), buf)
+        }
+    }
+}
+
+// the socket is bound to the group address with `SO_REUSEADDR`, so the nodes on the same host
+// can all join the group
+pub fn join(group: SocketAddrV4, interface: Ipv4Addr) -> anyhow::Result<UdpSocket> {
+    use rustix::net::{
+        bind_v4, socket_with, sockopt::set_socket_reuseaddr, AddressFamily, SocketFlags, SocketType,
+    };
+    let fd = socket_with(
+        AddressFamily::INET,
+        SocketType::DGRAM,
+        SocketFlags::NONBLOCK | SocketFlags::CLOEXEC,
+        None,
+    )?;
+    set_socket_reuseaddr(&fd, true)?;
+    bind_v4(&fd, &group)?;
+    let socket = std::net::UdpSocket::from(fd);
+    socket.join_multicast_v4(group.ip(), &interface)?;
+    Ok(UdpSocket::from_std(socket)?)
+}
+
+// `local_addr` is the unicast address of this node, where the multicast datagrams sent by itself
+// come from
+pub async fn recv_group_session(
+    socket: &UdpSocket,
+    local_addr: SocketAddr,
+    mut on_buf: impl FnMut(&[u8]) -> anyhow::Result<()>,
+) -> anyhow::Result<()> {
+    let mut chunk = Chunk::new(1 << 20);
+    loop {
+        let (_, remote) = socket.recv_buf_from(chunk.reserve(1 << 16)).await?;
+        let buf = chunk.split();
+        if remote != local_addr {
+            with_recv_from(remote, || with_recv_buf(buf, &mut on_buf))?
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use tokio::{sync::mpsc::unbounded_channel, time::timeout};
+
+    use super::*;
+
+    #[tokio::test]
+    async fn unicast_fallback() -> anyhow::Result<()> {
+        let group = SocketAddrV4::new(Ipv4Addr::new(239, 255, 0, 42), 20000);
+        let receiver = UdpSocket::bind("127.0.0.1:0").await?;
+        let receiver_addr = receiver.local_addr()?;
+        let net = Broadcast::new(batch::Udp::new(UdpSocket::bind("127.0.0.1:0").await?))
+            .with_group(group, [receiver_addr]);
+        let sends = async {
+            // not exactly the members
+            let other_addr = SocketAddr::from(([127, 0, 0, 1], 1));
+            net.clone().send(
+                IterAddr(&mut [receiver_addr, other_addr].into_iter()),
+                Bytes::from_static(b"some"),
+            )?;
+            let mut buf = vec![0; 16];
+            let len = timeout(Duration::from_secs(1), receiver.recv(&mut buf)).await??;
+            assert_eq!(&buf[..len], b"some");
+            anyhow::Result::<_>::Ok(())
+        };
+        tokio::select! {
+            result = net.flush_session() => result?,
+            result = sends => return result,
+        }
+        anyhow::bail!("unexpected exit")
+    }
+
+    // the loopback interface is not multicast capable, so the group datagrams go through the
+    // default interface and loop back, which needs a multicast route on it, e.g.
+    // `ip route add 239.0.0.0/8 dev eth0`, and usually is not the case in a container
+    #[tokio::test]
+    #[ignore = "requires a multicast route on the default interface"]
+    async fn multicast() -> anyhow::Result<()> {
+        let group = SocketAddrV4::new(
+            Ipv4Addr::new(239, 255, 0, 42),
+            20000 + rand::random::<u16>() % 10000,
+        );
+        let socket = UdpSocket::bind("0.0.0.0:0").await?;
+        let receiver_addr = SocketAddr::from(([127, 0, 0, 1], 1));
+        let group_socket = join(group, Ipv4Addr::UNSPECIFIED)?;
+        let net = Broadcast::new(batch::Udp::new(socket)).with_group(group, [receiver_addr]);
+
+        let (buf_sender, mut buf_receiver) = unbounded_channel();
+        let group_session = recv_group_session(&group_socket, receiver_addr, |buf| {
+            Ok(buf_sender.send(buf.to_vec())?)
+        });
+        let sends = async {
+            net.clone().send(
+                IterAddr(&mut [receiver_addr].into_iter()),
+                Bytes::from_static(b"all"),
+            )?;
+            let buf = timeout(Duration::from_secs(1), buf_receiver.recv()).await?;
+            assert_eq!(buf.unwrap(), b"all");
+            anyhow::Result::<_>::Ok(())
+        };
+        tokio::select! {
+            result = group_session => result?,
+            result = net.flush_session() => result?,
+            result = sends => return result,
+        }
+        anyhow::bail!("unexpected exit")
+    }
+}
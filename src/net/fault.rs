@@ -0,0 +1,431 @@
+// fault injection on top of raw nets
+//
+// the protocols are mostly evaluated under perfect network where nothing gets
+// lost, while their interesting parts e.g. resending and view changing are only
+// exercised when something goes wrong. wrapping the raw net with `Faulty` makes
+// the sending side (and only the sending side) misbehave as configured
+//
+// the faults are configured per destination, with a default for the
+// unconfigured destinations. the configuration lives behind a shared
+// `FaultControl` handle, so it can be changed at run time e.g. from the HTTP
+// endpoint of the evaluation artifact, without reconstructing the state machine
+// that owns the net
+//
+// reordering happens in two ways. one is jitter: a random extra delay per
+// message drawn from the configured distribution, so the later sent messages
+// may arrive earlier. the other is `reorder_rate`, which works as netem's
+// `reorder`: the selected messages skip the delay, and overtake the delayed ones
+// that are sent earlier
+//
+// the delayed messages are sent by detached Tokio tasks, so `Faulty` must be
+// used in asynchronous context if there's any delay. the sending errors of the
+// delayed messages are logged, as what `Udp` does for all messages
+
+use std::{
+    collections::HashMap,
+    hash::Hash,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use rand::{rngs::StdRng, Rng, SeedableRng};
+use rand_distr::{Distribution, Exp, Normal};
+use serde::{Deserialize, Serialize};
+use tracing::warn;
+
+use super::{IterAddr, SendMessage};
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct Faults {
+    // the probabilities in [0, 1]
+    pub drop_rate: f64,
+    pub duplicate_rate: f64,
+    pub reorder_rate: f64,
+    pub delay: Duration,
+    // the scale of the random extra delay, see `Jitter`
+    pub jitter: Duration,
+    pub jitter_distribution: Jitter,
+    // drop everything. equivalent to `drop_rate = 1.`, just more readable
+    pub partitioned: bool,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Jitter {
+    // extra delay in [0, jitter)
+    #[default]
+    Uniform,
+    // the delay is normally distributed around `delay` with `jitter` as the standard deviation,
+    // truncated at zero
+    Normal,
+    // extra delay with `jitter` as the mean, i.e. a long tail
+    Exponential,
+}
+
+impl Faults {
+    fn is_nop(&self) -> bool {
+        *self == Self::default()
+    }
+
+    pub fn validate(&self) -> anyhow::Result<()> {
+        for (name, rate) in [
+            ("drop", self.drop_rate),
+            ("duplicate", self.duplicate_rate),
+            ("reorder", self.reorder_rate),
+        ] {
+            // also rejects NaN
+            anyhow::ensure!(
+                (0. ..=1.).contains(&rate),
+                "{name} rate {rate} not in [0, 1]"
+            )
+        }
+        Ok(())
+    }
+
+    fn sample_delay(&self, rng: &mut impl Rng) -> Duration {
+        if rng.gen::<f64>() < self.reorder_rate {
+            return Duration::ZERO;
+        }
+        if self.jitter.is_zero() {
+            return self.delay;
+        }
+        let jitter = self.jitter.as_secs_f64();
+        let delay = self.delay.as_secs_f64()
+            + match self.jitter_distribution {
+                Jitter::Uniform => rng.gen::<f64>() * jitter,
+                Jitter::Normal => Normal::new(0., jitter).unwrap().sample(rng),
+                Jitter::Exponential => Exp::new(1. / jitter).unwrap().sample(rng),
+            };
+        Duration::from_secs_f64(delay.max(0.))
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct FaultConfig<A> {
+    pub default: Faults,
+    pub per_dest: HashMap<A, Faults>,
+}
+
+impl<A> Default for FaultConfig<A> {
+    fn default() -> Self {
+        Self {
+            default: Default::default(),
+            per_dest: Default::default(),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct FaultControl<A>(Arc<Mutex<FaultConfig<A>>>);
+
+impl<A> Clone for FaultControl<A> {
+    fn clone(&self) -> Self {
+        Self(self.0.clone())
+    }
+}
+
+impl<A> Default for FaultControl<A> {
+    fn default() -> Self {
+        Self(Default::default())
+    }
+}
+
+impl<A: Eq + Hash> FaultControl<A> {
+    pub fn set(&self, config: FaultConfig<A>) {
+        *self.0.lock().unwrap() = config
+    }
+
+    pub fn set_default(&self, faults: Faults) {
+        self.0.lock().unwrap().default = faults
+    }
+
+    pub fn set_dest(&self, dest: A, faults: Faults) {
+        self.0.lock().unwrap().per_dest.insert(dest, faults);
+    }
+
+    // only the outgoing direction. for a complete partition, the nodes on the other side should
+    // partition the local node as well
+    pub fn partition(&self, dests: impl IntoIterator<Item = A>) {
+        let mut config = self.0.lock().unwrap();
+        for dest in dests {
+            config.per_dest.entry(dest).or_default().partitioned = true
+        }
+    }
+
+    pub fn clear(&self) {
+        self.set(Default::default())
+    }
+
+    fn faults(&self, dest: &A) -> Faults {
+        let config = self.0.lock().unwrap();
+        config.per_dest.get(dest).unwrap_or(&config.default).clone()
+    }
+}
+
+// the clones share the random number generator, so the fates of all messages are decided by one
+// sequence, which is reproducible with `with_seed` (given a deterministic sending order)
+#[derive(Debug, Clone)]
+pub struct Faulty<N, A> {
+    inner: N,
+    control: FaultControl<A>,
+    rng: Arc<Mutex<StdRng>>,
+}
+
+impl<N, A> Faulty<N, A> {
+    pub fn new(inner: N, control: FaultControl<A>) -> Self {
+        Self {
+            inner,
+            control,
+            rng: Arc::new(Mutex::new(StdRng::from_entropy())),
+        }
+    }
+
+    pub fn with_seed(self, seed: u64) -> Self {
+        *self.rng.lock().unwrap() = StdRng::seed_from_u64(seed);
+        self
+    }
+}
+
+impl<N, A, M> SendMessage<A, M> for Faulty<N, A>
+where
+    N: SendMessage<A, M> + Clone + Send + 'static,
+    A: Eq + Hash + Clone + Send + 'static,
+    M: Clone + Send + 'static,
+{
+    fn send(&mut self, dest: A, message: M) -> anyhow::Result<()> {
+        let faults = self.control.faults(&dest);
+        if faults.is_nop() {
+            return self.inner.send(dest, message);
+        }
+        // decide the fate under the lock, and send after releasing it, so the clones are not
+        // serialized by the sending, and the inner net is free to send through a clone
+        let delays = {
+            let mut rng = self.rng.lock().unwrap();
+            // comparing instead of `gen_bool`, which panics on the rates out of range
+            if faults.partitioned || rng.gen::<f64>() < faults.drop_rate {
+                return Ok(());
+            }
+            let num_copy = if rng.gen::<f64>() < faults.duplicate_rate {
+                2
+            } else {
+                1
+            };
+            (0..num_copy)
+                .map(|_| faults.sample_delay(&mut *rng))
+                .collect::<Vec<_>>()
+        };
+        for delay in delays {
+            if delay.is_zero() {
+                self.inner.send(dest.clone(), message.clone())?;
+                continue;
+            }
+            let mut inner = self.inner.clone();
+            let dest = dest.clone();
+            let message = message.clone();
+            tokio::spawn(async move {
+                tokio::time::sleep(delay).await;
+                if let Err(err) = inner.send(dest, message) {
+                    warn!("delayed sending {err}")
+                }
+            });
+        }
+        Ok(())
+    }
+}
+
+// apply faults per destination, so every destination gets an independent fate
+impl<N, A, M> SendMessage<IterAddr<'_, A>, M> for Faulty<N, A>
+where
+    N: SendMessage<A, M> + Clone + Send + 'static,
+    A: Eq + Hash + Clone + Send + 'static,
+    M: Clone + Send + 'static,
+{
+    fn send(&mut self, dest: IterAddr<'_, A>, message: M) -> anyhow::Result<()> {
+        for addr in dest.0 {
+            self.send(addr, message.clone())?
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::{
+        sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender},
+        time::{sleep, Instant},
+    };
+
+    use crate::net::memory::Network;
+
+    use super::*;
+
+    fn faulty(seed: u64) -> (Faulty<Network<u8, u32>, u8>, UnboundedReceiver<u32>) {
+        let net = Network::new();
+        let (sender, receiver) = unbounded_channel();
+        net.register(0, sender).unwrap();
+        (
+            Faulty::new(net, Default::default()).with_seed(seed),
+            receiver,
+        )
+    }
+
+    fn received(receiver: &mut UnboundedReceiver<u32>) -> Vec<u32> {
+        let mut messages = Vec::new();
+        while let Ok(message) = receiver.try_recv() {
+            messages.push(message)
+        }
+        messages
+    }
+
+    #[test]
+    fn drop_and_duplicate() -> anyhow::Result<()> {
+        let (mut net, mut receiver) = faulty(0);
+        let control = net.control.clone();
+        control.set_default(Faults {
+            drop_rate: 0.5,
+            ..Default::default()
+        });
+        for i in 0..1000 {
+            net.send(0, i)?
+        }
+        let num_received = received(&mut receiver).len();
+        assert!((400..600).contains(&num_received), "{num_received}");
+
+        control.set_default(Faults {
+            duplicate_rate: 0.5,
+            ..Default::default()
+        });
+        for i in 0..1000 {
+            net.send(0, i)?
+        }
+        let num_received = received(&mut receiver).len();
+        assert!((1400..1600).contains(&num_received), "{num_received}");
+        Ok(())
+    }
+
+    #[test]
+    fn partition() -> anyhow::Result<()> {
+        let (mut net, mut receiver) = faulty(0);
+        let control = net.control.clone();
+        control.partition([0]);
+        net.send(0, 0)?;
+        assert!(received(&mut receiver).is_empty());
+        control.clear();
+        net.send(0, 1)?;
+        assert_eq!(received(&mut receiver), [1]);
+        Ok(())
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn delay_and_reorder() -> anyhow::Result<()> {
+        let (mut net, mut receiver) = faulty(0);
+        let control = net.control.clone();
+        control.set_dest(
+            0,
+            Faults {
+                delay: Duration::from_millis(10),
+                ..Default::default()
+            },
+        );
+        let start = Instant::now();
+        net.send(0, 0)?;
+        assert_eq!(receiver.recv().await, Some(0));
+        assert_eq!(start.elapsed().as_millis(), 10);
+
+        control.set_dest(
+            0,
+            Faults {
+                delay: Duration::from_millis(10),
+                reorder_rate: 0.5,
+                ..Default::default()
+            },
+        );
+        for i in 0..100 {
+            net.send(0, i)?
+        }
+        // the ones that skip the delay arrive at once
+        let skipped = received(&mut receiver);
+        assert!((30..70).contains(&skipped.len()), "{}", skipped.len());
+        sleep(Duration::from_millis(11)).await;
+        let delayed = received(&mut receiver);
+        assert_eq!(skipped.len() + delayed.len(), 100);
+        // the later sent skipped ones overtake the earlier delayed ones
+        assert!(skipped.last() > delayed.first());
+        Ok(())
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn jitter() -> anyhow::Result<()> {
+        for distribution in [Jitter::Uniform, Jitter::Normal, Jitter::Exponential] {
+            let (mut net, mut receiver) = faulty(0);
+            let control = net.control.clone();
+            control.set_default(Faults {
+                delay: Duration::from_millis(100),
+                jitter: Duration::from_millis(10),
+                jitter_distribution: distribution,
+                ..Default::default()
+            });
+            let start = Instant::now();
+            for i in 0..100 {
+                net.send(0, i)?
+            }
+            let mut messages = Vec::new();
+            let mut delays = Vec::new();
+            for _ in 0..100 {
+                messages.push(receiver.recv().await.unwrap());
+                delays.push(start.elapsed().as_secs_f64() * 1000.)
+            }
+            assert!(messages.windows(2).any(|window| window[0] > window[1]));
+            let mean = delays.iter().sum::<f64>() / 100.;
+            let expected = match distribution {
+                Jitter::Uniform => 105.,
+                Jitter::Normal => 100.,
+                Jitter::Exponential => 110.,
+            };
+            assert!((mean - expected).abs() < 3., "{distribution:?} {mean}")
+        }
+        Ok(())
+    }
+
+    // forwards the messages to 1 through the `Faulty` that wraps itself
+    #[derive(Clone)]
+    struct Relay(Arc<Mutex<Option<Faulty<Relay, u8>>>>, UnboundedSender<u32>);
+
+    impl SendMessage<u8, u32> for Relay {
+        fn send(&mut self, dest: u8, message: u32) -> anyhow::Result<()> {
+            if dest == 1 {
+                let mut net = self.0.lock().unwrap().clone().unwrap();
+                return net.send(0, message);
+            }
+            self.1.send(message)?;
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn reentrant() -> anyhow::Result<()> {
+        let (sender, mut receiver) = unbounded_channel();
+        let relay = Relay(Default::default(), sender);
+        let control = FaultControl::default();
+        control.set_default(Faults {
+            duplicate_rate: 1.,
+            ..Default::default()
+        });
+        let mut net = Faulty::new(relay.clone(), control).with_seed(0);
+        *relay.0.lock().unwrap() = Some(net.clone());
+        net.send(1, 42)?;
+        assert_eq!(received(&mut receiver), [42; 4]);
+        Ok(())
+    }
+
+    #[test]
+    fn validate() {
+        for rate in [-0.1, 1.1, f64::NAN] {
+            let faults = Faults {
+                drop_rate: rate,
+                ..Default::default()
+            };
+            assert!(faults.validate().is_err())
+        }
+        assert!(Faults::default().validate().is_ok())
+    }
+}
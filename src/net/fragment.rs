@@ -0,0 +1,317 @@
+// fragmentation and reassembly on top of raw nets
+//
+// the datagram based raw nets e.g. `Udp` cannot carry a buffer larger than
+// 64K, and a buffer larger than MTU is fragmented by IP anyway, where losing
+// any fragment loses the whole datagram silently. `Fragmented` splits the
+// buffers that are larger than a configurable size into numbered fragments,
+// and `Reassemble` in front of `on_buf` puts them back together
+//
+// every buffer gets a header, so the two sides must agree on whether the
+// fragmentation is enabled, similar to `auth`
+// layout: 1 byte kind, for `WHOLE` followed by the original buffer, for
+// `FRAGMENT` followed by 8 bytes message id, 2 bytes fragment index and 2 bytes
+// fragment count (all little endian) then the fragment
+//
+// the message id is unique per sender by starting from a random number, so the
+// receiver does not need to know who sent the fragments. the partially received
+// messages are discarded after a timeout. there's no retransmission of the
+// missing fragments, the whole message is considered lost as a datagram does
+// and the protocols deal with it
+//
+// the receiver bounds its memory regardless of what the senders claim: the
+// reassembled message is limited to the `MAX_BUF_LEN` of the stream based nets,
+// the fragment count is limited to what such a message needs with the smallest
+// allowed fragments, and at most `MAX_PARTIALS` messages are reassembled at the
+// same time, with the oldest one discarded to make room
+
+use std::{
+    collections::{HashMap, VecDeque},
+    time::{Duration, Instant},
+};
+
+use bytes::{BufMut as _, Bytes, BytesMut};
+use tracing::warn;
+
+use super::{session::MAX_BUF_LEN, with_recv_buf, Buf, IterAddr, SendMessage};
+
+const WHOLE: u8 = 0;
+const FRAGMENT: u8 = 1;
+const FRAGMENT_HEADER_LEN: usize = 1 + 8 + 2 + 2;
+// the minimum datagram size that every IPv4 host accepts, less IP and UDP headers
+pub const MIN_MAX_SIZE: usize = 576 - 60 - 8;
+const MAX_COUNT: usize = MAX_BUF_LEN.div_ceil(MIN_MAX_SIZE - FRAGMENT_HEADER_LEN);
+const MAX_PARTIALS: usize = 1024;
+
+#[derive(Debug)]
+pub struct Fragmented<N> {
+    inner: N,
+    max_size: usize,
+    message_id: u64,
+}
+
+// the clones may send to the same destination, so they must not share message ids
+impl<N: Clone> Clone for Fragmented<N> {
+    fn clone(&self) -> Self {
+        Self::new(self.inner.clone(), self.max_size)
+    }
+}
+
+impl<N> Fragmented<N> {
+    // `max_size` is the largest buffer that is sent to the inner net, including the header
+    pub fn new(inner: N, max_size: usize) -> Self {
+        assert!(max_size >= MIN_MAX_SIZE);
+        Self {
+            inner,
+            max_size,
+            message_id: rand::random(),
+        }
+    }
+
+    fn fragments(&mut self, buf: &[u8]) -> anyhow::Result<Vec<Bytes>> {
+        if buf.len() < self.max_size {
+            let mut whole = BytesMut::with_capacity(1 + buf.len());
+            whole.put_u8(WHOLE);
+            whole.put_slice(buf);
+            return Ok(vec![whole.freeze()]);
+        }
+        anyhow::ensure!(
+            buf.len() <= MAX_BUF_LEN,
+            "buffer of {} bytes too large",
+            buf.len()
+        );
+        let chunks = buf.chunks(self.max_size - FRAGMENT_HEADER_LEN);
+        let count = u16::try_from(chunks.len())?;
+        self.message_id = self.message_id.wrapping_add(1);
+        Ok(chunks
+            .enumerate()
+            .map(|(index, chunk)| {
+                let mut fragment = BytesMut::with_capacity(FRAGMENT_HEADER_LEN + chunk.len());
+                fragment.put_u8(FRAGMENT);
+                fragment.put_u64_le(self.message_id);
+                fragment.put_u16_le(index as _);
+                fragment.put_u16_le(count);
+                fragment.put_slice(chunk);
+                fragment.freeze()
+            })
+            .collect())
+    }
+}
+
+impl<N: SendMessage<A, Bytes>, A: Clone, B: Buf> SendMessage<A, B> for Fragmented<N> {
+    fn send(&mut self, dest: A, message: B) -> anyhow::Result<()> {
+        for fragment in self.fragments(message.as_ref())? {
+            self.inner.send(dest.clone(), fragment)?
+        }
+        Ok(())
+    }
+}
+
+impl<N: for<'a> SendMessage<IterAddr<'a, A>, Bytes>, A: Clone + Send + Sync, B: Buf>
+    SendMessage<IterAddr<'_, A>, B> for Fragmented<N>
+{
+    fn send(&mut self, dest: IterAddr<'_, A>, message: B) -> anyhow::Result<()> {
+        let addrs = dest.0.collect::<Vec<_>>();
+        for fragment in self.fragments(message.as_ref())? {
+            self.inner
+                .send(IterAddr(&mut addrs.iter().cloned()), fragment)?
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug)]
+struct Partial {
+    fragments: Vec<Option<Bytes>>,
+    num_missing: usize,
+    len: usize,
+    deadline: Instant,
+}
+
+#[derive(Debug)]
+pub struct Reassemble {
+    partials: HashMap<u64, Partial>,
+    // the timeout is fixed so the insertion order is also the deadline order. the entries of the
+    // completed messages are left behind and skipped when they are reached
+    deadlines: VecDeque<(Instant, u64)>,
+    timeout: Duration,
+}
+
+impl Reassemble {
+    pub fn new(timeout: Duration) -> Self {
+        Self {
+            partials: Default::default(),
+            deadlines: Default::default(),
+            timeout,
+        }
+    }
+
+    pub fn on_buf(
+        &mut self,
+        buf: &[u8],
+        on_buf: impl FnOnce(&[u8]) -> anyhow::Result<()>,
+    ) -> anyhow::Result<()> {
+        // the expiration is only checked when buffers keep arriving, which is fine since the memory
+        // is only at risk in that case
+        let now = Instant::now();
+        while let Some(&(deadline, _)) = self.deadlines.front() {
+            if deadline > now {
+                break;
+            }
+            self.expire()
+        }
+        match buf.first() {
+            Some(&WHOLE) => return on_buf(&buf[1..]),
+            Some(&FRAGMENT) if buf.len() >= FRAGMENT_HEADER_LEN => {}
+            _ => {
+                warn!("malformed buffer of {} bytes", buf.len());
+                return Ok(());
+            }
+        }
+        let message_id = u64::from_le_bytes(buf[1..9].try_into().unwrap());
+        let index = u16::from_le_bytes(buf[9..11].try_into().unwrap()) as usize;
+        let count = u16::from_le_bytes(buf[11..13].try_into().unwrap()) as usize;
+        if index >= count || count > MAX_COUNT {
+            warn!("malformed fragment {index}/{count}");
+            return Ok(());
+        }
+
+        if !self.partials.contains_key(&message_id) {
+            while self.partials.len() >= MAX_PARTIALS {
+                self.expire()
+            }
+            self.partials.insert(
+                message_id,
+                Partial {
+                    fragments: vec![None; count],
+                    num_missing: count,
+                    len: 0,
+                    deadline: now + self.timeout,
+                },
+            );
+            self.deadlines.push_back((now + self.timeout, message_id))
+        }
+        let partial = self.partials.get_mut(&message_id).unwrap();
+        if partial.fragments.len() != count {
+            warn!("inconsistent fragment count {count}");
+            return Ok(());
+        }
+        if partial.fragments[index].is_none() {
+            let fragment = &buf[FRAGMENT_HEADER_LEN..];
+            partial.len += fragment.len();
+            if partial.len > MAX_BUF_LEN {
+                warn!("oversized message of at least {} bytes", partial.len);
+                self.partials.remove(&message_id);
+                return Ok(());
+            }
+            partial.fragments[index] = Some(Bytes::copy_from_slice(fragment));
+            partial.num_missing -= 1
+        }
+        if partial.num_missing > 0 {
+            return Ok(());
+        }
+
+        let partial = self.partials.remove(&message_id).unwrap();
+        let mut message = BytesMut::with_capacity(partial.len);
+        for fragment in partial.fragments {
+            message.put(fragment.unwrap())
+        }
+        with_recv_buf(message.freeze(), on_buf)
+    }
+
+    // discard the partial message of the oldest deadline entry, if it is still there
+    fn expire(&mut self) {
+        let (deadline, message_id) = self.deadlines.pop_front().unwrap();
+        // the message id may have been completed and then reused, which comes with a later deadline
+        if self
+            .partials
+            .get(&message_id)
+            .is_some_and(|partial| partial.deadline == deadline)
+        {
+            self.partials.remove(&message_id);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::net::Capture;
+
+    use super::*;
+
+    #[test]
+    fn reassemble() -> anyhow::Result<()> {
+        let mut net = Fragmented::new(Capture::default(), MIN_MAX_SIZE);
+        let message = (0..12 * (MIN_MAX_SIZE - FRAGMENT_HEADER_LEN))
+            .map(|i| i as u8)
+            .collect::<Vec<_>>();
+        net.send((), Bytes::from(message.clone()))?;
+        net.send((), Bytes::from_static(b"small"))?;
+        let mut fragments = net
+            .inner
+            .take()
+            .into_iter()
+            .map(|((), fragment)| fragment)
+            .collect::<Vec<_>>();
+        assert_eq!(fragments.len(), 13);
+
+        let mut received = Vec::new();
+        let mut reassemble = Reassemble::new(Duration::from_millis(10));
+        // out of order and duplicated
+        fragments.swap(0, 10);
+        fragments.insert(3, fragments[2].clone());
+        for fragment in &fragments {
+            reassemble.on_buf(fragment, |buf| {
+                received.push(buf.to_vec());
+                Ok(())
+            })?
+        }
+        assert_eq!(received, [message, b"small".to_vec()]);
+
+        // missing fragment is discarded after timeout
+        for fragment in &fragments[..5] {
+            reassemble.on_buf(fragment, |_| unreachable!())?
+        }
+        std::thread::sleep(Duration::from_millis(20));
+        reassemble.on_buf(fragments.last().unwrap(), |_| Ok(()))?;
+        assert!(reassemble.partials.is_empty());
+        Ok(())
+    }
+
+    fn fragment(message_id: u64, index: u16, count: u16) -> Vec<u8> {
+        let mut fragment = vec![FRAGMENT];
+        fragment.extend(message_id.to_le_bytes());
+        fragment.extend(index.to_le_bytes());
+        fragment.extend(count.to_le_bytes());
+        fragment.extend([0; 10]);
+        fragment
+    }
+
+    #[test]
+    fn bounded() -> anyhow::Result<()> {
+        let mut reassemble = Reassemble::new(Duration::from_secs(1));
+        reassemble.on_buf(&fragment(0, 0, MAX_COUNT as u16 + 1), |_| unreachable!())?;
+        assert!(reassemble.partials.is_empty());
+
+        for message_id in 0..MAX_PARTIALS as u64 + 10 {
+            reassemble.on_buf(&fragment(message_id, 0, 2), |_| unreachable!())?
+        }
+        assert_eq!(reassemble.partials.len(), MAX_PARTIALS);
+        // the oldest ones are discarded
+        assert!(!reassemble.partials.contains_key(&9));
+        assert!(reassemble.partials.contains_key(&10));
+        let mut received = false;
+        reassemble.on_buf(&fragment(10, 1, 2), |buf| {
+            received = buf.len() == 20;
+            Ok(())
+        })?;
+        assert!(received);
+
+        let oversized = fragment(u64::MAX, 0, 2)
+            .into_iter()
+            .chain(vec![0; MAX_BUF_LEN])
+            .collect::<Vec<_>>();
+        reassemble.on_buf(&oversized, |_| unreachable!())?;
+        assert!(!reassemble.partials.contains_key(&u64::MAX));
+        Ok(())
+    }
+}
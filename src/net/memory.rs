@@ -0,0 +1,113 @@
+// in-memory network for running multiple nodes in one process
+//
+// every node registers a receiving side under its address, and every node
+// sends through a clone of the same `Network`, which looks the destination up
+// and delivers the message by calling into the registered receiving side. the
+// receiving side is expected to be a sender of some channel e.g. the sender of
+// a `Session`, so the delivery is merely enqueuing and the message is handled
+// later by the destination's own event loop, just like with a socket net
+//
+// the network can carry structural messages directly, or carry `Bytes` as a raw
+// net. in the latter case wrap it with `MessageNet` on the sending side and
+// register with `register_on_buf` on the receiving side, then the same
+// `*_on_buf` functions that work with `Udp` work here as well, and the wire
+// format gets exercised for free
+//
+// the registry is locked during the delivery, so the registered receiving side
+// must not send through the network inline, or it deadlocks. there's no reason
+// to do that with a channel sender anyway
+//
+// sending to an unregistered address silently loses the message, matching what
+// happens when sending UDP packet to a port that nobody listens on. this also
+// lets nodes to be removed (i.e. crashed) with `unregister` while the others are
+// still sending to them
+
+use std::{
+    collections::HashMap,
+    hash::Hash,
+    sync::{Arc, Mutex},
+};
+
+use tracing::warn;
+
+use crate::event::SendEvent;
+
+use super::{IterAddr, SendMessage};
+
+type Deliver<M> = Box<dyn FnMut(M) -> anyhow::Result<()> + Send>;
+
+pub struct Network<A, M>(Arc<Mutex<HashMap<A, Deliver<M>>>>);
+
+impl<A, M> std::fmt::Debug for Network<A, M> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Network").finish_non_exhaustive()
+    }
+}
+
+impl<A, M> Clone for Network<A, M> {
+    fn clone(&self) -> Self {
+        Self(self.0.clone())
+    }
+}
+
+impl<A, M> Default for Network<A, M> {
+    fn default() -> Self {
+        Self(Default::default())
+    }
+}
+
+impl<A: Eq + Hash, M> Network<A, M> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn register(
+        &self,
+        addr: A,
+        mut sender: impl SendEvent<M> + Send + 'static,
+    ) -> anyhow::Result<()> {
+        self.insert(addr, Box::new(move |message| sender.send(message)))
+    }
+
+    pub fn unregister(&self, addr: &A) {
+        self.0.lock().unwrap().remove(addr);
+    }
+
+    fn insert(&self, addr: A, deliver: Deliver<M>) -> anyhow::Result<()> {
+        let replaced = self.0.lock().unwrap().insert(addr, deliver);
+        anyhow::ensure!(replaced.is_none(), "duplicated address");
+        Ok(())
+    }
+}
+
+impl<A: Eq + Hash, M: AsRef<[u8]>> Network<A, M> {
+    pub fn register_on_buf(
+        &self,
+        addr: A,
+        mut on_buf: impl FnMut(&[u8]) -> anyhow::Result<()> + Send + 'static,
+    ) -> anyhow::Result<()> {
+        self.insert(addr, Box::new(move |buf: M| on_buf(buf.as_ref())))
+    }
+}
+
+impl<A: Eq + Hash, M> SendMessage<A, M> for Network<A, M> {
+    fn send(&mut self, dest: A, message: M) -> anyhow::Result<()> {
+        if let Some(deliver) = self.0.lock().unwrap().get_mut(&dest) {
+            // the receiving side failing is not the fault of the sender, and with a socket net the
+            // sender would not notice it at all
+            if let Err(err) = deliver(message) {
+                warn!("deliver {err}")
+            }
+        }
+        Ok(())
+    }
+}
+
+impl<A: Eq + Hash, M: Clone> SendMessage<IterAddr<'_, A>, M> for Network<A, M> {
+    fn send(&mut self, dest: IterAddr<'_, A>, message: M) -> anyhow::Result<()> {
+        for addr in dest.0 {
+            self.send(addr, message.clone())?
+        }
+        Ok(())
+    }
+}
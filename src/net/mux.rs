@@ -0,0 +1,160 @@
+// multiplexing independent protocols over one raw net
+//
+// a protocol stack e.g. Kademlia + bulk + the application used to share one
+// socket by declaring a combined message enum that covers every protocol, and
+// a hand-written `on_buf` that splits it back. instead, each protocol can have
+// its own `MessageNet` on top of a `Channel` of the shared raw net, which
+// prefixes the buffers with a channel id, and the receiving side runs a `Demux`
+// that strips the prefix and routes the buffers to the per-channel `on_buf`s
+//
+// the channel ids are assigned by the user, and every node must agree on them
+// layout: 2 bytes little endian channel id, followed by the original buffer
+//
+// the prefixing costs one copy per outgoing buffer (the `Bytes` produced by
+// `MessageNet` cannot be prepended to), which is done once for `IterAddr`. the
+// copies are cut from a pooled chunk in the same way as `MessageNet`. the
+// receiving side does not copy, so `slice_recv_buf` keeps working
+
+use std::collections::HashMap;
+
+use bytes::{BufMut as _, Bytes};
+use tracing::warn;
+
+use super::{Buf, Chunk, SendMessage};
+
+pub type ChannelId = u16;
+
+const HEADER_LEN: usize = 2;
+const SEND_CHUNK: usize = 1 << 16;
+
+#[derive(Debug)]
+pub struct Channel<N> {
+    inner: N,
+    id: ChannelId,
+    chunk: Chunk,
+}
+
+impl<N> Channel<N> {
+    pub fn new(inner: N, id: ChannelId) -> Self {
+        Self {
+            inner,
+            id,
+            chunk: Chunk::new(SEND_CHUNK),
+        }
+    }
+
+    fn prefix(&mut self, buf: &[u8]) -> Bytes {
+        let chunk = self.chunk.reserve(HEADER_LEN + buf.len());
+        chunk.put_u16_le(self.id);
+        chunk.put_slice(buf);
+        self.chunk.split()
+    }
+}
+
+impl<N: Clone> Clone for Channel<N> {
+    fn clone(&self) -> Self {
+        Self {
+            inner: self.inner.clone(),
+            id: self.id,
+            chunk: self.chunk.clone(),
+        }
+    }
+}
+
+// this covers `IterAddr` as well, and the prefixed buffer is shared by all destinations
+impl<N: SendMessage<A, Bytes>, A, B: Buf> SendMessage<A, B> for Channel<N> {
+    fn send(&mut self, dest: A, message: B) -> anyhow::Result<()> {
+        let buf = self.prefix(message.as_ref());
+        self.inner.send(dest, buf)
+    }
+}
+
+type OnBuf<'a> = Box<dyn FnMut(&[u8]) -> anyhow::Result<()> + Send + 'a>;
+
+pub struct Demux<'a> {
+    channels: HashMap<ChannelId, OnBuf<'a>>,
+}
+
+impl std::fmt::Debug for Demux<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Demux")
+            .field("channels", &self.channels.keys())
+            .finish()
+    }
+}
+
+impl Default for Demux<'_> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<'a> Demux<'a> {
+    pub fn new() -> Self {
+        Self {
+            channels: Default::default(),
+        }
+    }
+
+    pub fn with_channel(
+        mut self,
+        id: ChannelId,
+        on_buf: impl FnMut(&[u8]) -> anyhow::Result<()> + Send + 'a,
+    ) -> Self {
+        let replaced = self.channels.insert(id, Box::new(on_buf));
+        assert!(replaced.is_none(), "duplicated channel {id}");
+        self
+    }
+
+    // the buffers of unknown channels are dropped as well as the malformed ones, e.g. the ones
+    // from a peer running a richer protocol stack
+    pub fn on_buf(&mut self, buf: &[u8]) -> anyhow::Result<()> {
+        if buf.len() < HEADER_LEN {
+            warn!("malformed buffer of {} bytes", buf.len());
+            return Ok(());
+        }
+        let id = ChannelId::from_le_bytes(buf[..HEADER_LEN].try_into().unwrap());
+        let Some(on_buf) = self.channels.get_mut(&id) else {
+            warn!("unknown channel {id}");
+            return Ok(());
+        };
+        on_buf(&buf[HEADER_LEN..])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::net::Capture;
+
+    use super::*;
+
+    #[test]
+    fn demux() -> anyhow::Result<()> {
+        let capture = Capture::default();
+        let mut channel1 = Channel::new(capture.clone(), 1);
+        let mut channel2 = Channel::new(capture.clone(), 2);
+        channel1.send((), Bytes::from_static(b"hello"))?;
+        channel2.send((), Bytes::from_static(b"world"))?;
+        // no one listens on this channel
+        Channel::new(capture.clone(), 3).send((), Bytes::from_static(b"!"))?;
+
+        let mut received1 = Vec::new();
+        let mut received2 = Vec::new();
+        let mut demux = Demux::new()
+            .with_channel(1, |buf| {
+                received1.push(buf.to_vec());
+                Ok(())
+            })
+            .with_channel(2, |buf| {
+                received2.push(buf.to_vec());
+                Ok(())
+            });
+        for ((), buf) in capture.take() {
+            demux.on_buf(&buf)?
+        }
+        drop(demux);
+        assert_eq!(received1, [b"hello"]);
+        assert_eq!(received2, [b"world"]);
+        Ok(())
+    }
+}
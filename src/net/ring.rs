@@ -0,0 +1,575 @@
+// shared memory ring transport for the processes on the same host
+//
+// every directed pair of nodes gets a single-producer single-consumer ring in a
+// memory mapped file under a shared directory (usually on tmpfs e.g. /dev/shm),
+// named after the two addresses. the sender appends length-prefixed records and
+// publishes them by bumping the tail position, the receiver consumes them in
+// place and bumps the head position. both positions are atomics in the shared
+// header, so passing a buffer costs no syscall and no lock
+// layout: 8 bytes head, 8 bytes tail, 8 bytes closed flag and 4 bytes waiting
+// flag followed by 4 bytes wakeup counter, each on its own cache line, then the
+// data region with a power of two capacity. a record is 4
+// bytes little endian length, 4 bytes padding and the buffer, padded to 8 bytes.
+// a record never wraps around, instead a `WRAP` length skips the rest of the
+// data region
+//
+// the catch is that there's no wakeup for free either. the receiver spins (and
+// yields) for a while after the last record, then raises the waiting flag and
+// parks on the wakeup counter with a (shared, i.e. cross-process) futex, on a
+// blocking thread. the sender bumps the counter and wakes the receiver if the
+// flag is raised after publishing, which costs a syscall only for the first
+// record after the receiver goes idle. the other direction is not covered: a
+// sender blocked by a full ring polls every `POLL_INTERVAL`, which is rare
+// enough for the local scale tests
+//
+// `Ring` implements `Protocol` for `Dispatch`. connecting creates the ring to
+// the remote on the first outgoing buffer, and `ring_accept_session` scans the
+// directory for the rings toward the local node. a full ring blocks the sender
+// (i.e. back pressure like TCP) until the receiver catches up, or until
+// `STALL_TIMEOUT`, after which the connection is considered broken. either side
+// sets the closed flag when it leaves, and the rings are never deleted while in
+// use, only replaced by a new connection or cleaned up by `Ring::new` of the
+// next run
+//
+// the unsafe code is confined in `Mapping`. similar to the unsafe (and the
+// panics) elsewhere in this codebase, see the notes in `lib.rs`, it is safe
+// because of the following invariants that the compiler cannot check
+// * the mapping covers the whole file, whose size is validated when it is
+//   mapped, and it stays mapped until `Mapping` is dropped
+// * the header is only accessed through atomics, at the offsets that are
+//   aligned and on their own cache lines
+// * the data region is partitioned by the positions: the producer only writes
+//   the free space from the tail, and the consumer only reads the published
+//   records from the head. the positions and the record lengths are validated
+//   before use, so a malicious peer can feed garbage, same as it can through
+//   any socket, but not steer an access out of the mapping
+// the trust boundary is the directory. the ring files cannot be sealed since
+// they are regular files instead of memfds, so every process that can write to
+// the directory is trusted not to truncate a ring that is mapped by others,
+// which is a SIGBUS on the next access. the file size is validated when it is
+// mapped, and `Ring::new` creates the directory accessible by the owner only,
+// so a shared directory must be set up with the same care
+
+use std::{
+    collections::HashMap,
+    fs::{File, OpenOptions},
+    net::SocketAddr,
+    os::unix::fs::{DirBuilderExt as _, MetadataExt as _},
+    path::{Path, PathBuf},
+    ptr::{copy_nonoverlapping, null_mut, NonNull},
+    sync::{
+        atomic::{fence, AtomicU32, AtomicU64, Ordering::*},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+
+use rustix::{
+    io::Errno,
+    mm::{mmap, munmap, MapFlags, ProtFlags},
+    thread::futex,
+};
+use tokio::{sync::mpsc::UnboundedReceiver, time::sleep};
+use tracing::warn;
+
+use crate::event::SendEvent;
+
+use super::{
+    session::{ConnectionEvent, Incoming, Monitor, OnBuf, Protocol},
+    Buf,
+};
+
+const HEAD_OFFSET: usize = 0;
+const TAIL_OFFSET: usize = 64;
+const CLOSED_OFFSET: usize = 128;
+const WAITING_OFFSET: usize = 192;
+const WAKE_OFFSET: usize = 196;
+const HEADER_LEN: usize = 256;
+const RECORD_HEADER_LEN: usize = 8;
+const WRAP: u32 = u32::MAX;
+
+const NUM_SPIN: usize = 64;
+const POLL_INTERVAL: Duration = Duration::from_millis(1);
+// in case the peer dies without closing
+const PARK_TIMEOUT: Duration = Duration::from_millis(100);
+const STALL_TIMEOUT: Duration = Duration::from_secs(1);
+const ACCEPT_INTERVAL: Duration = Duration::from_millis(100);
+
+#[derive(Debug)]
+pub struct Mapping {
+    ptr: NonNull<u8>,
+    len: usize,
+    ino: u64,
+}
+
+// the header is only accessed through atomics, and the data region is partitioned between the
+// producer and the consumer by the positions
+unsafe impl Send for Mapping {}
+unsafe impl Sync for Mapping {}
+
+impl Drop for Mapping {
+    fn drop(&mut self) {
+        if let Err(err) = unsafe { munmap(self.ptr.as_ptr().cast(), self.len) } {
+            warn!("munmap {err}")
+        }
+    }
+}
+
+impl Mapping {
+    fn map(file: &File) -> anyhow::Result<Self> {
+        let metadata = file.metadata()?;
+        let len = metadata.len() as usize;
+        anyhow::ensure!(
+            len > HEADER_LEN
+                && (len - HEADER_LEN).is_power_of_two()
+                && len - HEADER_LEN < WRAP as usize,
+            "invalid ring size {len}"
+        );
+        let ptr = unsafe {
+            mmap(
+                null_mut(),
+                len,
+                ProtFlags::READ | ProtFlags::WRITE,
+                MapFlags::SHARED,
+                file,
+                0,
+            )?
+        };
+        Ok(Self {
+            ptr: NonNull::new(ptr.cast()).unwrap(),
+            len,
+            ino: metadata.ino(),
+        })
+    }
+
+    // the file is completely set up before it appears at `path`, so the accepting side never sees
+    // a partial one
+    fn create(path: &Path, capacity: usize) -> anyhow::Result<Self> {
+        let temp_path = path.with_extension("tmp");
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(&temp_path)?;
+        // zero filled, i.e. empty and not closed
+        file.set_len((HEADER_LEN + capacity) as _)?;
+        let mapping = Self::map(&file)?;
+        std::fs::rename(temp_path, path)?;
+        Ok(mapping)
+    }
+
+    fn open(path: &Path) -> anyhow::Result<Self> {
+        Self::map(&OpenOptions::new().read(true).write(true).open(path)?)
+    }
+
+    fn atomic(&self, offset: usize) -> &AtomicU64 {
+        unsafe { &*self.ptr.as_ptr().add(offset).cast::<AtomicU64>() }
+    }
+
+    fn atomic32(&self, offset: usize) -> &AtomicU32 {
+        unsafe { &*self.ptr.as_ptr().add(offset).cast::<AtomicU32>() }
+    }
+
+    fn head(&self) -> &AtomicU64 {
+        self.atomic(HEAD_OFFSET)
+    }
+
+    fn tail(&self) -> &AtomicU64 {
+        self.atomic(TAIL_OFFSET)
+    }
+
+    fn closed(&self) -> &AtomicU64 {
+        self.atomic(CLOSED_OFFSET)
+    }
+
+    fn is_closed(&self) -> bool {
+        self.closed().load(Acquire) != 0
+    }
+
+    fn close(&self) {
+        self.closed().store(1, Release);
+        self.unpark()
+    }
+
+    fn waiting(&self) -> &AtomicU32 {
+        self.atomic32(WAITING_OFFSET)
+    }
+
+    fn wake(&self) -> &AtomicU32 {
+        self.atomic32(WAKE_OFFSET)
+    }
+
+    // the consumer side, block until there may be something to pop
+    fn park(&self) -> anyhow::Result<()> {
+        self.waiting().store(1, SeqCst);
+        let wake = self.wake().load(SeqCst);
+        // checked after raising the flag, so a record that is published in between either is seen
+        // here or comes with a wakeup
+        if self.head().load(Relaxed) == self.tail().load(SeqCst) && !self.is_closed() {
+            let timeout = futex::Timespec {
+                tv_sec: 0,
+                tv_nsec: PARK_TIMEOUT.as_nanos() as _,
+            };
+            match futex::wait(self.wake(), futex::Flags::empty(), wake, Some(timeout)) {
+                Ok(()) | Err(Errno::AGAIN | Errno::INTR | Errno::TIMEDOUT) => {}
+                Err(err) => Err(err)?,
+            }
+        }
+        self.waiting().store(0, Relaxed);
+        Ok(())
+    }
+
+    // the producer side, after publishing
+    fn unpark(&self) {
+        fence(SeqCst);
+        if self.waiting().load(Relaxed) != 0 {
+            self.wake().fetch_add(1, Release);
+            if let Err(err) = futex::wake(self.wake(), futex::Flags::empty(), 1) {
+                warn!("futex wake {err}")
+            }
+        }
+    }
+
+    fn capacity(&self) -> usize {
+        self.len - HEADER_LEN
+    }
+
+    fn data(&self) -> *mut u8 {
+        unsafe { self.ptr.as_ptr().add(HEADER_LEN) }
+    }
+
+    // the producer side, return false if there's not enough space at the moment
+    fn push(&self, buf: &[u8]) -> anyhow::Result<bool> {
+        let capacity = self.capacity();
+        let record_len = RECORD_HEADER_LEN + buf.len().next_multiple_of(8);
+        anyhow::ensure!(
+            record_len <= capacity / 2,
+            "buffer of {} bytes is too large",
+            buf.len()
+        );
+        let mut tail = self.tail().load(Relaxed) as usize;
+        let head = self.head().load(Acquire) as usize;
+        let offset = tail % capacity;
+        let padding = if capacity - offset < record_len {
+            capacity - offset
+        } else {
+            0
+        };
+        if tail + padding + record_len - head > capacity {
+            return Ok(false);
+        }
+        unsafe {
+            if padding > 0 {
+                self.data().add(offset).cast::<u32>().write(WRAP.to_le());
+                tail += padding
+            }
+            let offset = tail % capacity;
+            let record = self.data().add(offset);
+            record.cast::<u32>().write((buf.len() as u32).to_le());
+            copy_nonoverlapping(buf.as_ptr(), record.add(RECORD_HEADER_LEN), buf.len())
+        }
+        self.tail().store((tail + record_len) as _, Release);
+        self.unpark();
+        Ok(true)
+    }
+
+    // the consumer side, the buffer is passed to `on_buf` in place
+    fn pop<T>(&self, on_buf: impl FnOnce(&[u8]) -> T) -> anyhow::Result<Option<T>> {
+        let capacity = self.capacity();
+        let mut head = self.head().load(Relaxed) as usize;
+        let tail = self.tail().load(Acquire) as usize;
+        if head == tail {
+            return Ok(None);
+        }
+        let mut offset = head % capacity;
+        let mut len = u32::from_le(unsafe { self.data().add(offset).cast::<u32>().read() });
+        if len == WRAP {
+            head += capacity - offset;
+            offset = 0;
+            anyhow::ensure!(head < tail, "corrupted ring");
+            len = u32::from_le(unsafe { self.data().cast::<u32>().read() })
+        }
+        let len = len as usize;
+        anyhow::ensure!(
+            offset + RECORD_HEADER_LEN + len <= capacity && head + RECORD_HEADER_LEN + len <= tail,
+            "corrupted ring"
+        );
+        let buf =
+            unsafe { std::slice::from_raw_parts(self.data().add(offset + RECORD_HEADER_LEN), len) };
+        let output = on_buf(buf);
+        self.head().store(
+            (head + RECORD_HEADER_LEN + len.next_multiple_of(8)) as _,
+            Release,
+        );
+        Ok(Some(output))
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Ring {
+    dir: PathBuf,
+    local: SocketAddr,
+    capacity: usize,
+}
+
+pub fn ring_path(dir: impl AsRef<Path>, from: SocketAddr, to: SocketAddr) -> PathBuf {
+    dir.as_ref().join(format!("{from}_{to}.ring"))
+}
+
+fn parse_ring_path(path: &Path) -> Option<(SocketAddr, SocketAddr)> {
+    let (from, to) = path
+        .file_name()?
+        .to_str()?
+        .strip_suffix(".ring")?
+        .split_once('_')?;
+    Some((from.parse().ok()?, to.parse().ok()?))
+}
+
+impl Ring {
+    // the rings from and to `local` are left by a previous run, and are cleaned up
+    pub fn new(dir: impl Into<PathBuf>, local: SocketAddr) -> anyhow::Result<Self> {
+        let dir = dir.into();
+        std::fs::DirBuilder::new()
+            .recursive(true)
+            .mode(0o700)
+            .create(&dir)?;
+        for entry in std::fs::read_dir(&dir)? {
+            let path = entry?.path();
+            if let Some((from, to)) = parse_ring_path(&path) {
+                if from == local || to == local {
+                    std::fs::remove_file(path)?
+                }
+            }
+        }
+        Ok(Self {
+            dir,
+            local,
+            capacity: 1 << 22,
+        })
+    }
+
+    pub fn with_capacity(mut self, capacity: usize) -> Self {
+        assert!(capacity.is_power_of_two() && capacity >= 1 << 12);
+        self.capacity = capacity;
+        self
+    }
+
+    async fn write_task<B: Buf>(
+        self,
+        remote: SocketAddr,
+        mut receiver: UnboundedReceiver<B>,
+        monitor: Monitor,
+        connecting: bool,
+    ) {
+        let path = ring_path(&self.dir, self.local, remote);
+        let mut ring = None;
+        while let Some(buf) = receiver.recv().await {
+            let ring = match &ring {
+                Some(ring) => ring,
+                None => match Mapping::create(&path, self.capacity) {
+                    Ok(mapping) => {
+                        if connecting {
+                            monitor.report(ConnectionEvent::Connected(remote))
+                        }
+                        ring.insert(mapping)
+                    }
+                    Err(err) => {
+                        warn!(">>> {remote} {err}");
+                        if connecting {
+                            monitor.report(ConnectionEvent::ConnectFailed(remote))
+                        }
+                        return;
+                    }
+                },
+            };
+            if let Err(err) = async {
+                let mut stalled = None;
+                while !ring.push(buf.as_ref())? {
+                    anyhow::ensure!(!ring.is_closed(), "closed by remote");
+                    anyhow::ensure!(
+                        stalled.get_or_insert_with(Instant::now).elapsed() < STALL_TIMEOUT,
+                        "remote stalled"
+                    );
+                    sleep(POLL_INTERVAL).await
+                }
+                anyhow::Result::<_>::Ok(())
+            }
+            .await
+            {
+                warn!(">>> {remote} {err}");
+                monitor.report(ConnectionEvent::Disconnected(remote));
+                break;
+            }
+        }
+        if let Some(ring) = ring {
+            ring.close()
+        }
+    }
+
+    async fn read_task(
+        ring: Mapping,
+        mut on_buf: impl FnMut(&[u8]) -> anyhow::Result<()>,
+        remote: SocketAddr,
+        monitor: Monitor,
+    ) {
+        let ring = Arc::new(ring);
+        let mut num_idle = 0;
+        if let Err(err) = async {
+            loop {
+                // checked before polling, so nothing published before closing is missed
+                let closed = ring.is_closed();
+                match ring.pop(&mut on_buf)? {
+                    Some(result) => {
+                        result?;
+                        num_idle = 0
+                    }
+                    None if closed => break anyhow::Result::<_>::Ok(()),
+                    None if num_idle < NUM_SPIN => {
+                        num_idle += 1;
+                        tokio::task::yield_now().await
+                    }
+                    None => {
+                        let ring = ring.clone();
+                        tokio::task::spawn_blocking(move || ring.park()).await??
+                    }
+                }
+            }
+        }
+        .await
+        {
+            warn!("<<< {remote} {err}");
+            monitor.report(ConnectionEvent::Disconnected(remote))
+        }
+        ring.close()
+    }
+}
+
+impl Protocol for Ring {
+    type Peer = ();
+
+    fn connect<B: Buf>(
+        &self,
+        remote: SocketAddr,
+        _: impl OnBuf<()>,
+        receiver: UnboundedReceiver<B>,
+        monitor: Monitor,
+    ) {
+        // the incoming direction is a separated ring that is accepted by `ring_accept_session`
+        tokio::spawn(self.clone().write_task(remote, receiver, monitor, true));
+    }
+
+    type Incoming = (Ring, SocketAddr, Mapping);
+
+    fn accept<B: Buf>(
+        (ring, remote, mapping): Self::Incoming,
+        mut on_buf: impl OnBuf<()>,
+        receiver: UnboundedReceiver<B>,
+        monitor: Monitor,
+    ) -> Option<SocketAddr> {
+        monitor.report(ConnectionEvent::Connected(remote));
+        tokio::spawn(Ring::read_task(
+            mapping,
+            move |buf: &[u8]| on_buf.on_buf(&(), buf),
+            remote,
+            monitor.clone(),
+        ));
+        tokio::spawn(ring.write_task(remote, receiver, monitor, false));
+        Some(remote)
+    }
+}
+
+pub async fn ring_accept_session(
+    ring: Ring,
+    mut sender: impl SendEvent<Incoming<(Ring, SocketAddr, Mapping)>>,
+) -> anyhow::Result<()> {
+    // a ring is identified by its inode, since a reconnecting replaces the file at the same path
+    let mut accepted = HashMap::<PathBuf, u64>::new();
+    loop {
+        let mut entries = tokio::fs::read_dir(&ring.dir).await?;
+        while let Some(entry) = entries.next_entry().await? {
+            let path = entry.path();
+            match parse_ring_path(&path) {
+                Some((remote, local)) if local == ring.local => {
+                    if accepted.get(&path) == Some(&entry.ino()) {
+                        continue;
+                    }
+                    match Mapping::open(&path) {
+                        Ok(mapping) => {
+                            // the file may have been replaced again after listing
+                            accepted.insert(path, mapping.ino);
+                            sender.send(Incoming((ring.clone(), remote, mapping)))?
+                        }
+                        Err(err) => warn!("<<< {remote} {err}"),
+                    }
+                }
+                _ => {}
+            }
+        }
+        sleep(ACCEPT_INTERVAL).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bytes::Bytes;
+    use tokio::sync::mpsc::unbounded_channel;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn ring() -> anyhow::Result<()> {
+        let dir = tempfile::tempdir()?;
+        let dir = dir.path();
+        let mapping = Mapping::create(&dir.join("test.ring"), 1 << 12)?;
+        // varying sizes to exercise the wrapping around, and popping only when full
+        let mut received = Vec::new();
+        for i in 0..100usize {
+            let buf = vec![i as u8; i * 7 % 500];
+            while !mapping.push(&buf)? {
+                assert!(mapping.pop(|buf| received.push(buf.to_vec()))?.is_some())
+            }
+        }
+        while mapping.pop(|buf| received.push(buf.to_vec()))?.is_some() {}
+        assert_eq!(
+            received,
+            (0..100usize)
+                .map(|i| vec![i as u8; i * 7 % 500])
+                .collect::<Vec<_>>()
+        );
+        // eventually full
+        assert!((0..5).any(|_| !mapping.push(&[0; 1000]).unwrap()));
+
+        let addr = SocketAddr::from(([127, 0, 0, 1], 10000));
+        let remote_addr = SocketAddr::from(([127, 0, 0, 1], 10001));
+        let ring = Ring::new(dir, addr)?;
+        let remote = Ring::new(dir, remote_addr)?;
+        let (incoming_sender, mut incoming_receiver) = unbounded_channel();
+        tokio::spawn(ring_accept_session(ring, incoming_sender));
+        let (sender, receiver) = unbounded_channel();
+        remote.connect(addr, |_: &[u8]| Ok(()), receiver, Monitor::default());
+        sender.send(Bytes::from_static(b"hello"))?;
+
+        let Some(Incoming(incoming)) = incoming_receiver.recv().await else {
+            anyhow::bail!("no incoming ring")
+        };
+        let (buf_sender, mut buf_receiver) = unbounded_channel();
+        let (_, receiver) = unbounded_channel::<Bytes>();
+        let accepted = Ring::accept(
+            incoming,
+            move |buf: &[u8]| Ok(buf_sender.send(buf.to_vec())?),
+            receiver,
+            Monitor::default(),
+        );
+        assert_eq!(accepted, Some(remote_addr));
+        assert_eq!(buf_receiver.recv().await, Some(b"hello".to_vec()));
+        // the receiver has parked by now, and is woken up instead of timing out
+        sleep(Duration::from_millis(10)).await;
+        sender.send(Bytes::from_static(b"world"))?;
+        assert_eq!(
+            tokio::time::timeout(PARK_TIMEOUT / 2, buf_receiver.recv()).await?,
+            Some(b"world".to_vec())
+        );
+        Ok(())
+    }
+}
@@ -1,17 +1,19 @@
 use std::{
-    collections::{hash_map::Entry, HashMap},
     fmt::Debug,
-    io::ErrorKind,
+    io::{ErrorKind, IoSlice},
     mem::replace,
-    net::SocketAddr,
-    sync::Arc,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    os::fd::OwnedFd,
+    sync::{Arc, Mutex},
     time::Duration,
 };
 
 use bincode::Options;
+use bytes::{Buf as _, BytesMut};
+use lru::LruCache;
 use rustls::RootCertStore;
 use tokio::{
-    io::{AsyncReadExt, AsyncWriteExt},
+    io::{AsyncReadExt, AsyncWrite, AsyncWriteExt},
     net::{
         tcp::{OwnedReadHalf, OwnedWriteHalf},
         TcpListener, TcpStream,
@@ -21,11 +23,14 @@ use tokio::{
 use tracing::{info, warn, Instrument};
 
 use crate::event::{
-    erased::{events::Init, OnEvent},
-    OnTimer, SendEvent, Timer,
+    erased::{
+        events::{Init, Stop},
+        OnEvent,
+    },
+    Exit, OnTimer, SendEvent, Timer,
 };
 
-use super::{Buf, IterAddr, SendMessage};
+use super::{with_recv_buf, with_recv_from, Buf, Chunk, IterAddr, SendMessage};
 
 #[derive(Debug, Clone)]
 pub struct Udp(pub Arc<tokio::net::UdpSocket>);
@@ -35,10 +40,11 @@ impl Udp {
         &self,
         mut on_buf: impl FnMut(&[u8]) -> anyhow::Result<()>,
     ) -> anyhow::Result<()> {
-        let mut buf = vec![0; 1 << 16];
+        // the packets are cut from a chunk that holds many of them, see `with_recv_buf`
+        let mut chunk = Chunk::new(1 << 20);
         loop {
-            let (len, _) = self.0.recv_from(&mut buf).await?;
-            on_buf(&buf[..len])?
+            let (_, remote) = self.0.recv_buf_from(chunk.reserve(1 << 16)).await?;
+            with_recv_from(remote, || with_recv_buf(chunk.split(), &mut on_buf))?
         }
     }
 }
@@ -70,7 +76,7 @@ impl<B: Buf> SendMessage<IterAddr<'_, SocketAddr>, B> for Udp {
     }
 }
 
-const MAX_BUF_LEN: usize = 1 << 20;
+pub(crate) const MAX_BUF_LEN: usize = 1 << 20;
 
 // a construction that enables connection reusing
 // the client side of a connection informs its server address to the connected
@@ -104,8 +110,9 @@ const MAX_BUF_LEN: usize = 1 << 20;
 // message pattern may be more in a unidirectional oneshot style. not sure
 // whether that's inherent difference among protocols or it just libp2p's
 // implementation conforms its network interface
-// anyway, we have to define our own reclaiming policy. tentatively a connection
-// is reclaimed if it has no outgoing traffic for one whole second. the
+// anyway, we have to define our own reclaiming policy. by default a connection
+// is reclaimed if it has no outgoing traffic for one whole second (or for up to
+// two seconds to be precise, since the check happens once per second). the
 // rationale is that
 // * network communication is usually ping-pong style. if we haven't sent to
 //   some address for one whole second, then it probably is not sending to us
@@ -118,6 +125,14 @@ const MAX_BUF_LEN: usize = 1 << 20;
 //   each other's latest message almost immediately, and all exchanged messages
 //   are around the same topic/context. we guarantee to never interrupt a
 //   session, but we don't try to predict when the next session will come
+// the timeout can be changed with `with_idle_timeout`, and the connection table
+// can be capped with `with_max_connections`, which reclaims the least recently
+// used connection when a new one goes beyond the cap. the latter does interrupt
+// sessions, so it is only for the cases where the file descriptors are scarce
+//
+// the connection level happenings are invisible through `SendMessage`, which
+// is fine for most protocols. the ones that maintain peer liveness e.g.
+// kademlia can get them as `ConnectionEvent`s by `with_upcall`
 //
 // this solution comes with inherint overhead: each outgoing message must go
 // through two channels, the first one for getting into `TcpControl` and the
@@ -127,8 +142,11 @@ const MAX_BUF_LEN: usize = 1 << 20;
 #[derive(Debug)]
 pub struct Dispatch<P, B, F> {
     protocol: P,
-    connections: HashMap<SocketAddr, Connection<B>>,
+    connections: LruCache<SocketAddr, Connection<B>>,
     on_buf: F,
+    monitor: Monitor,
+    idle_timeout: Duration,
+    max_connections: Option<usize>,
 }
 
 #[derive(Debug)]
@@ -137,37 +155,151 @@ struct Connection<B> {
     using: bool,
 }
 
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum ConnectionEvent {
+    Connected(SocketAddr),
+    ConnectFailed(SocketAddr),
+    // the connection breaks with an error. the graceful closing e.g. by remote's reclaiming is not
+    // reported. may be reported by both directions of the same connection
+    Disconnected(SocketAddr),
+    // the connection is reclaimed locally, either idle or evicted
+    Reclaimed(SocketAddr),
+}
+
+type MonitorUpcall = Arc<Mutex<Box<dyn SendEvent<ConnectionEvent> + Send>>>;
+
+// the `Dispatch`'s upcall shared with the connection tasks
+#[derive(Clone, Default)]
+pub struct Monitor(Option<MonitorUpcall>);
+
+impl Debug for Monitor {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_tuple("Monitor").field(&self.0.is_some()).finish()
+    }
+}
+
+impl Monitor {
+    // the upcall failing, most likely because the receiving session has exited, should not bring
+    // down the connections
+    pub fn report(&self, event: ConnectionEvent) {
+        if let Some(upcall) = &self.0 {
+            if let Err(err) = upcall.lock().unwrap().send(event) {
+                warn!("connection upcall {err}")
+            }
+        }
+    }
+}
+
 impl<P, B, F> Dispatch<P, B, F> {
     pub fn new(protocol: P, on_buf: F) -> anyhow::Result<Self> {
         Ok(Self {
             protocol,
-            connections: HashMap::new(),
+            connections: LruCache::unbounded(),
             on_buf,
+            monitor: Default::default(),
+            idle_timeout: Duration::from_secs(1),
+            max_connections: None,
         })
     }
+
+    pub fn with_upcall(mut self, upcall: impl SendEvent<ConnectionEvent> + Send + 'static) -> Self {
+        self.monitor = Monitor(Some(Arc::new(Mutex::new(Box::new(upcall)))));
+        self
+    }
+
+    pub fn with_idle_timeout(mut self, idle_timeout: Duration) -> Self {
+        self.idle_timeout = idle_timeout;
+        self
+    }
+
+    pub fn with_max_connections(mut self, max_connections: usize) -> Self {
+        assert_ne!(max_connections, 0);
+        self.max_connections = Some(max_connections);
+        self
+    }
+
+    fn insert(&mut self, remote: SocketAddr, sender: UnboundedSender<B>) {
+        self.connections.put(
+            remote,
+            Connection {
+                sender,
+                using: true,
+            },
+        );
+        let Some(max_connections) = self.max_connections else {
+            return;
+        };
+        while self.connections.len() > max_connections {
+            let (evicted, _) = self.connections.pop_lru().unwrap();
+            info!(">=> {evicted} evicted");
+            self.monitor.report(ConnectionEvent::Reclaimed(evicted))
+        }
+    }
 }
 
 impl<P, B, F> OnEvent<Init> for Dispatch<P, B, F> {
     fn on_event(&mut self, Init: Init, timer: &mut impl Timer) -> anyhow::Result<()> {
-        timer.set(Duration::from_secs(1))?;
+        timer.set(self.idle_timeout)?;
         Ok(())
     }
 }
 
+// dropping the senders is the flushing: the write tasks keep writing until the
+// queued buffers are all written, then close the connections gracefully
+// the `Outgoing` events that are sent before `Stop` have been processed at this
+// point, and the ones that are racing with it are delivered by the session's
+// draining, which connects again if necessary, so nothing sent through
+// `DispatchNet` get lost, as long as the runtime is not shut down too soon
+impl<P, B, F> OnEvent<Stop> for Dispatch<P, B, F> {
+    fn on_event(&mut self, Stop: Stop, _: &mut impl Timer) -> anyhow::Result<()> {
+        info!("flushing {} connections", self.connections.len());
+        self.connections.clear();
+        Err(Exit)?
+    }
+}
+
+// the receiving callback of a `Protocol`, which is told about the remote of every buffer as much
+// as the protocol knows, e.g. the authenticated identity of `Quic`. the plain `on_buf` closures
+// ignore it, and the closures wrapped in `WithPeer` take it along with the buffer
+pub trait OnBuf<P>: Clone + Send + 'static {
+    fn on_buf(&mut self, peer: &P, buf: &[u8]) -> anyhow::Result<()>;
+}
+
+impl<F: FnMut(&[u8]) -> anyhow::Result<()> + Clone + Send + 'static, P> OnBuf<P> for F {
+    fn on_buf(&mut self, _: &P, buf: &[u8]) -> anyhow::Result<()> {
+        self(buf)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct WithPeer<F>(pub F);
+
+impl<F: FnMut(&P, &[u8]) -> anyhow::Result<()> + Clone + Send + 'static, P> OnBuf<P>
+    for WithPeer<F>
+{
+    fn on_buf(&mut self, peer: &P, buf: &[u8]) -> anyhow::Result<()> {
+        (self.0)(peer, buf)
+    }
+}
+
 pub trait Protocol {
+    type Peer;
+
     fn connect<B: Buf>(
         &self,
         remote: SocketAddr,
-        on_buf: impl FnMut(&[u8]) -> anyhow::Result<()> + Clone + Send + 'static,
+        on_buf: impl OnBuf<Self::Peer>,
         receiver: UnboundedReceiver<B>,
+        monitor: Monitor,
     );
 
     type Incoming;
 
     fn accept<B: Buf>(
         connection: Self::Incoming,
-        on_buf: impl FnMut(&[u8]) -> anyhow::Result<()> + Clone + Send + 'static,
+        on_buf: impl OnBuf<Self::Peer>,
         receiver: UnboundedReceiver<B>,
+        monitor: Monitor,
     ) -> Option<SocketAddr>;
 }
 
@@ -193,9 +325,7 @@ impl<E: SendEvent<Outgoing<B>>, B: Buf> SendMessage<IterAddr<'_, SocketAddr>, B>
     }
 }
 
-impl<P: Protocol, B: Buf, F: FnMut(&[u8]) -> anyhow::Result<()> + Clone + Send + 'static>
-    OnEvent<Outgoing<B>> for Dispatch<P, B, F>
-{
+impl<P: Protocol, B: Buf, F: OnBuf<P::Peer>> OnEvent<Outgoing<B>> for Dispatch<P, B, F> {
     fn on_event(
         &mut self,
         Outgoing(remote, mut buf): Outgoing<B>,
@@ -209,40 +339,34 @@ impl<P: Protocol, B: Buf, F: FnMut(&[u8]) -> anyhow::Result<()> + Clone + Send +
                 }
                 Err(err) => {
                     warn!(">=> {remote} reconnecting: {err}");
-                    self.connections.remove(&remote);
+                    self.connections.pop(&remote);
                     buf = err.0
                 }
             }
         }
         let (sender, receiver) = unbounded_channel();
-        self.protocol.connect(remote, self.on_buf.clone(), receiver);
+        self.protocol
+            .connect(remote, self.on_buf.clone(), receiver, self.monitor.clone());
         if sender.send(buf).is_err() {
             warn!(">=> {remote} new connection immediately fail")
         } else {
-            self.connections.insert(
-                remote,
-                Connection {
-                    sender,
-                    using: true,
-                },
-            );
+            self.insert(remote, sender)
         }
         Ok(())
     }
 }
 
-pub struct Incoming<T>(T);
+pub struct Incoming<T>(pub(crate) T);
 
-impl<P: Protocol, B: Buf, F: FnMut(&[u8]) -> anyhow::Result<()> + Clone + Send + 'static>
-    OnEvent<Incoming<P::Incoming>> for Dispatch<P, B, F>
-{
+impl<P: Protocol, B: Buf, F: OnBuf<P::Peer>> OnEvent<Incoming<P::Incoming>> for Dispatch<P, B, F> {
     fn on_event(
         &mut self,
         Incoming(event): Incoming<P::Incoming>,
         _: &mut impl Timer,
     ) -> anyhow::Result<()> {
         let (sender, receiver) = unbounded_channel();
-        if let Some(remote) = P::accept(event, self.on_buf.clone(), receiver) {
+        if let Some(remote) = P::accept(event, self.on_buf.clone(), receiver, self.monitor.clone())
+        {
             // let replaced = self.connections.insert(
             //     remote,
             //     Connection {
@@ -256,11 +380,8 @@ impl<P: Protocol, B: Buf, F: FnMut(&[u8]) -> anyhow::Result<()> + Clone + Send +
             // always prefer to keep the connection created locally
             // the connection in `self.connections` may not be created locally, but the incoming
             // connection is definitely created remotely
-            if let Entry::Vacant(entry) = self.connections.entry(remote) {
-                entry.insert(Connection {
-                    sender,
-                    using: true,
-                });
+            if !self.connections.contains(&remote) {
+                self.insert(remote, sender)
             } else {
                 info!("<<< {remote} skip inserting incoming connection")
             }
@@ -274,9 +395,21 @@ impl<P, B, F> OnTimer for Dispatch<P, B, F> {
         if self.connections.is_empty() {
             return Ok(());
         }
-        self.connections.retain(|_, connection| {
-            replace(&mut connection.using, false) && !connection.sender.is_closed()
-        });
+        let mut reclaimed = Vec::new();
+        for (remote, connection) in self.connections.iter_mut() {
+            if connection.sender.is_closed() {
+                // the write task has exited, and it has reported if that is not graceful
+                reclaimed.push((*remote, false))
+            } else if !replace(&mut connection.using, false) {
+                reclaimed.push((*remote, true))
+            }
+        }
+        for (remote, idle) in reclaimed {
+            self.connections.pop(&remote);
+            if idle {
+                self.monitor.report(ConnectionEvent::Reclaimed(remote))
+            }
+        }
         info!("retaining {} connections", self.connections.len());
         Ok(())
     }
@@ -286,7 +419,8 @@ pub struct Tcp(bytes::Bytes);
 
 type TcpPreamble = Option<SocketAddr>;
 
-const TCP_PREAMBLE_LEN: usize = 16;
+// large enough for an IPv6 address
+const TCP_PREAMBLE_LEN: usize = 32;
 
 impl Tcp {
     pub fn new(addr: impl Into<Option<SocketAddr>>) -> anyhow::Result<Self> {
@@ -297,25 +431,39 @@ impl Tcp {
         Ok(Self(preamble.into()))
     }
 
+    // the messages are cut from a chunk that holds many of them, as what `Udp::recv_session` does,
+    // so there is no allocation per message, and the payloads can slice the chunk
     async fn read_task(
         mut stream: OwnedReadHalf,
         mut on_buf: impl FnMut(&[u8]) -> anyhow::Result<()>,
         remote: impl Into<Option<SocketAddr>>,
+        monitor: Monitor,
     ) {
         let remote = remote.into();
         if let Err(err) = async {
+            let mut buf = BytesMut::new();
             loop {
-                let len = match stream.read_u64().await {
-                    Ok(len) => len as _,
-                    Err(err) if matches!(err.kind(), ErrorKind::UnexpectedEof) => break Ok(()),
-                    Err(err) => Err(err)?,
-                };
-                if len > MAX_BUF_LEN {
-                    anyhow::bail!("invalid buffer length {len}")
+                while buf.len() >= 8 {
+                    let len = u64::from_be_bytes(buf[..8].try_into().unwrap()) as usize;
+                    if len > MAX_BUF_LEN {
+                        anyhow::bail!("invalid buffer length {len}")
+                    }
+                    if buf.len() < 8 + len {
+                        buf.reserve(8 + len - buf.len());
+                        break;
+                    }
+                    buf.advance(8);
+                    with_recv_buf(buf.split_to(len).freeze(), &mut on_buf)?
+                }
+                if buf.capacity() - buf.len() < 1 << 16 {
+                    buf.reserve(1 << 20)
+                }
+                if stream.read_buf(&mut buf).await? == 0 {
+                    if buf.is_empty() {
+                        break Ok(());
+                    }
+                    anyhow::bail!("connection closed in the middle of a buffer")
                 }
-                let mut buf = vec![0; len];
-                stream.read_exact(&mut buf).await?;
-                on_buf(&buf)?
             }
         }
         .await
@@ -325,18 +473,32 @@ impl Tcp {
                 stream.peer_addr(),
                 stream.local_addr()
             );
+            if let Some(remote) = remote {
+                monitor.report(ConnectionEvent::Disconnected(remote))
+            }
         }
     }
 
+    // all the buffers that have been queued up are written together with as few syscalls as
+    // possible, and flushed once
     async fn write_task<B: Buf>(
         mut stream: OwnedWriteHalf,
         mut receiver: UnboundedReceiver<B>,
         remote: SocketAddr,
+        monitor: Monitor,
     ) {
+        const MAX_BATCH_LEN: usize = 1024;
+        let mut bufs = Vec::new();
         while let Some(buf) = receiver.recv().await {
+            bufs.push(buf);
+            while bufs.len() < MAX_BATCH_LEN {
+                let Ok(buf) = receiver.try_recv() else {
+                    break;
+                };
+                bufs.push(buf)
+            }
             if let Err(err) = async {
-                stream.write_u64(buf.as_ref().len() as _).await?;
-                stream.write_all(buf.as_ref()).await?;
+                write_batch(&mut stream, &bufs).await?;
                 stream.flush().await
             }
             .await
@@ -346,18 +508,64 @@ impl Tcp {
                     stream.local_addr(),
                     stream.peer_addr()
                 );
+                monitor.report(ConnectionEvent::Disconnected(remote));
                 break;
             }
+            bufs.clear()
         }
     }
 }
 
+// same format as `write_u64` followed by `write_all` for each buffer
+async fn write_batch<B: Buf>(
+    stream: &mut (impl AsyncWrite + Unpin),
+    bufs: &[B],
+) -> std::io::Result<()> {
+    // the limit of the number of slices of a single `writev`
+    const MAX_SLICES_LEN: usize = 1024;
+    let prefixes = bufs
+        .iter()
+        .map(|buf| (buf.as_ref().len() as u64).to_be_bytes())
+        .collect::<Vec<_>>();
+    let mut parts = prefixes
+        .iter()
+        .zip(bufs)
+        .flat_map(|(prefix, buf)| [&prefix[..], buf.as_ref()])
+        .filter(|part| !part.is_empty())
+        .collect::<Vec<_>>();
+    let mut start = 0;
+    while start < parts.len() {
+        let slices = parts[start..]
+            .iter()
+            .take(MAX_SLICES_LEN)
+            .map(|part| IoSlice::new(part))
+            .collect::<Vec<_>>();
+        let mut len = stream.write_vectored(&slices).await?;
+        if len == 0 {
+            return Err(ErrorKind::WriteZero.into());
+        }
+        while len > 0 {
+            let part = &mut parts[start];
+            if len < part.len() {
+                *part = &part[len..];
+                break;
+            }
+            len -= part.len();
+            start += 1
+        }
+    }
+    Ok(())
+}
+
 impl Protocol for Tcp {
+    type Peer = ();
+
     fn connect<B: Buf>(
         &self,
         remote: SocketAddr,
-        on_buf: impl FnMut(&[u8]) -> anyhow::Result<()> + Clone + Send + 'static,
+        mut on_buf: impl OnBuf<()>,
         receiver: UnboundedReceiver<B>,
+        monitor: Monitor,
     ) {
         let preamble = self.0.clone();
         tokio::spawn(async move {
@@ -374,12 +582,19 @@ impl Protocol for Tcp {
                 Ok(stream) => stream,
                 Err(err) => {
                     warn!(">=> {remote} {err}");
+                    monitor.report(ConnectionEvent::ConnectFailed(remote));
                     return;
                 }
             };
+            monitor.report(ConnectionEvent::Connected(remote));
             let (read, write) = stream.into_split();
-            tokio::spawn(Self::read_task(read, on_buf, remote));
-            tokio::spawn(Self::write_task(write, receiver, remote));
+            tokio::spawn(Self::read_task(
+                read,
+                move |buf: &[u8]| on_buf.on_buf(&(), buf),
+                remote,
+                monitor.clone(),
+            ));
+            tokio::spawn(Self::write_task(write, receiver, remote, monitor));
         });
     }
 
@@ -387,13 +602,20 @@ impl Protocol for Tcp {
 
     fn accept<B: Buf>(
         (preamble, stream): Self::Incoming,
-        on_buf: impl FnMut(&[u8]) -> anyhow::Result<()> + Clone + Send + 'static,
+        mut on_buf: impl OnBuf<()>,
         receiver: UnboundedReceiver<B>,
+        monitor: Monitor,
     ) -> Option<SocketAddr> {
         let (read, write) = stream.into_split();
-        tokio::spawn(Tcp::read_task(read, on_buf, preamble));
+        tokio::spawn(Tcp::read_task(
+            read,
+            move |buf: &[u8]| on_buf.on_buf(&(), buf),
+            preamble,
+            monitor.clone(),
+        ));
         if let Some(remote) = preamble {
-            tokio::spawn(Tcp::write_task(write, receiver, remote));
+            monitor.report(ConnectionEvent::Connected(remote));
+            tokio::spawn(Tcp::write_task(write, receiver, remote, monitor));
         } else {
             // write.forget()
         }
@@ -401,6 +623,72 @@ impl Protocol for Tcp {
     }
 }
 
+// the transports work with whichever address family their sockets are bound to. the following
+// binding functions are for the nodes that are not told which address to listen on: without an
+// address they bind the IPv6 wildcard `[::]`, with IPV6_V6ONLY turned off regardless of the
+// system default (`net.ipv6.bindv6only`), so one socket serves both the IPv4 and the IPv6 peers,
+// where the IPv4 ones show up with the mapped addresses e.g. `[::ffff:10.0.0.1]`. the IPv4
+// wildcard is the fallback for the hosts that have IPv6 disabled. an explicit `[::]` is bound
+// dual-stack as well
+pub(crate) fn bind_socket(
+    ip: Option<IpAddr>,
+    port: u16,
+    socket_type: rustix::net::SocketType,
+    nonblocking: bool,
+) -> anyhow::Result<OwnedFd> {
+    use rustix::net::{
+        bind, socket_with,
+        sockopt::{set_ipv6_v6only, set_socket_reuseaddr},
+        AddressFamily, SocketFlags, SocketType,
+    };
+    let Some(ip) = ip else {
+        return bind_socket(
+            Some(Ipv6Addr::UNSPECIFIED.into()),
+            port,
+            socket_type,
+            nonblocking,
+        )
+        .or_else(|_| {
+            bind_socket(
+                Some(Ipv4Addr::UNSPECIFIED.into()),
+                port,
+                socket_type,
+                nonblocking,
+            )
+        });
+    };
+    let mut flags = SocketFlags::CLOEXEC;
+    if nonblocking {
+        flags |= SocketFlags::NONBLOCK
+    }
+    let family = if ip.is_ipv4() {
+        AddressFamily::INET
+    } else {
+        AddressFamily::INET6
+    };
+    let fd = socket_with(family, socket_type, flags, None)?;
+    if ip.is_ipv6() && ip.is_unspecified() {
+        set_ipv6_v6only(&fd, false)?
+    }
+    // as what `TcpListener::bind` does
+    if socket_type == SocketType::STREAM {
+        set_socket_reuseaddr(&fd, true)?
+    }
+    bind(&fd, &SocketAddr::new(ip, port))?;
+    Ok(fd)
+}
+
+pub fn bind_tcp_listener(ip: impl Into<Option<IpAddr>>, port: u16) -> anyhow::Result<TcpListener> {
+    let fd = bind_socket(ip.into(), port, rustix::net::SocketType::STREAM, true)?;
+    rustix::net::listen(&fd, 1024)?;
+    Ok(TcpListener::from_std(fd.into())?)
+}
+
+pub fn bind_udp(ip: impl Into<Option<IpAddr>>, port: u16) -> anyhow::Result<tokio::net::UdpSocket> {
+    let fd = bind_socket(ip.into(), port, rustix::net::SocketType::DGRAM, true)?;
+    Ok(tokio::net::UdpSocket::from_std(fd.into())?)
+}
+
 pub async fn tcp_accept_session(
     listener: TcpListener,
     mut sender: impl SendEvent<Incoming<(TcpPreamble, TcpStream)>>,
@@ -459,7 +747,11 @@ pub mod simplex {
                     // have to enable REUSEADDR otherwise port number exhausted after sending to
                     // same `dest` 28K messages within 1min
                     // let mut stream = TcpStream::connect(dest).await?;
-                    let socket = tokio::net::TcpSocket::new_v4()?;
+                    let socket = if dest.is_ipv4() {
+                        tokio::net::TcpSocket::new_v4()?
+                    } else {
+                        tokio::net::TcpSocket::new_v6()?
+                    };
                     socket.set_reuseaddr(true)?;
                     let mut stream = socket.connect(dest).await?;
                     let mut preamble = bincode::options().serialize(&TcpPreamble::None)?;
@@ -489,50 +781,349 @@ pub mod simplex {
     }
 }
 
+// mutual TLS for QUIC
+// every node holds its own key and a certificate issued by a CA that all nodes
+// trust. the certificate is issued for a server name derived from the node's
+// address (see `quic_server_name`), and the name is checked when connecting,
+// so a node cannot impersonate another one without the CA's key. the incoming
+// connections are required to present a certificate issued by the same CA as
+// well, and the SHA-256 digest of the remote's certificate (`QuicPeer`) comes
+// along with every received buffer to the `on_buf` that is wrapped in
+// `WithPeer` (see `OnBuf`). `QuicIdentity::peer` computes the
+// same digest locally, so e.g. a kademlia `PeerId` can be bound to it out of
+// band
+// there are three ways to set up the identities
+// * `QuicIdentity::load` the DER files provisioned by `examples/rotate-cert.rs`,
+//   where the CA's key never leaves the provisioning machine
+// * `QuicIdentity::from_seed` derives the CA and the node keys from a seed that
+//   is shared by all nodes. anyone who knows the seed can issue certificates, so
+//   this is for testbed only, similar to the seed of `auth::Keys`
+// * `QuicIdentity::testbed` issues a wildcard certificate with the CA embedded
+//   in the source code. it protects nothing and is what `Quic::new` uses, so
+//   the existing setups keep working
+#[derive(Debug, Clone)]
+pub struct Quic(pub quinn::Endpoint, pub QuicMode);
+
+const QUIC_DOMAIN: &str = "neatworks.quic";
+
+pub fn quic_server_name(addr: SocketAddr) -> String {
+    // a DNS label cannot contain dots and colons, or start with hyphen
+    let label = format!("n{}-{}", addr.ip(), addr.port()).replace(['.', ':'], "-");
+    format!("{label}.{QUIC_DOMAIN}")
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct QuicPeer(pub [u8; 32]);
+
+impl QuicPeer {
+    fn of(cert: &rustls::Certificate) -> Self {
+        use sha2::Digest as _;
+        Self(sha2::Sha256::digest(&cert.0).into())
+    }
+}
+
 #[derive(Debug, Clone)]
-pub struct Quic(pub quinn::Endpoint);
-
-fn quic_config() -> anyhow::Result<(quinn::ServerConfig, quinn::ClientConfig)> {
-    let issuer_key = rcgen::KeyPair::from_pem(include_str!("../key.pem"))?;
-    let issuer = rcgen::Certificate::generate_self_signed(
-        rcgen::CertificateParams::from_ca_cert_pem(include_str!("../cert.pem"))?,
-        &issuer_key,
-    )?;
-    let priv_key = rcgen::KeyPair::generate()?;
-    let cert = rcgen::Certificate::generate(
-        rcgen::CertificateParams::new(vec!["neatworks.quic".into()])?,
-        &priv_key,
-        &issuer,
-        &issuer_key,
-    )?;
-    let priv_key = rustls::PrivateKey(priv_key.serialize_der());
-    let cert_chain = vec![rustls::Certificate(cert.der().to_vec())];
-
-    let mut server_config = quinn::ServerConfig::with_single_cert(cert_chain, priv_key)?;
-    let transport_config = Arc::get_mut(&mut server_config.transport).unwrap();
-    // transport_config.max_concurrent_uni_streams(0_u8.into());
-    transport_config.max_concurrent_uni_streams(4096u32.into());
-    transport_config.max_idle_timeout(Some(Duration::from_millis(2500).try_into()?));
-
-    let mut roots = RootCertStore::empty();
-    roots.add_parsable_certificates(&[issuer.der()]);
-    let client_config = quinn::ClientConfig::with_root_certificates(roots);
-    Ok((server_config, client_config))
+pub struct QuicIdentity {
+    ca_cert: rustls::Certificate,
+    cert_chain: Vec<rustls::Certificate>,
+    key: rustls::PrivateKey,
+}
+
+impl QuicIdentity {
+    pub fn load(
+        ca_cert: impl AsRef<std::path::Path>,
+        cert: impl AsRef<std::path::Path>,
+        key: impl AsRef<std::path::Path>,
+    ) -> anyhow::Result<Self> {
+        Ok(Self {
+            ca_cert: rustls::Certificate(std::fs::read(ca_cert)?),
+            cert_chain: vec![rustls::Certificate(std::fs::read(cert)?)],
+            key: rustls::PrivateKey(std::fs::read(key)?),
+        })
+    }
+
+    pub fn from_seed(seed: [u8; 32], addr: SocketAddr) -> anyhow::Result<Self> {
+        let (ca, ca_key) = quic_seeded_ca(seed)?;
+        let name = quic_server_name(addr);
+        Self::issue(&ca, &ca_key, quic_seeded_key(seed, &name)?, name)
+    }
+
+    pub fn testbed() -> anyhow::Result<Self> {
+        let issuer_key = rcgen::KeyPair::from_pem(include_str!("../key.pem"))?;
+        let issuer = rcgen::Certificate::generate_self_signed(
+            rcgen::CertificateParams::from_ca_cert_pem(include_str!("../cert.pem"))?,
+            &issuer_key,
+        )?;
+        Self::issue(
+            &issuer,
+            &issuer_key,
+            rcgen::KeyPair::generate()?,
+            format!("*.{QUIC_DOMAIN}"),
+        )
+    }
+
+    pub fn issue(
+        issuer: &rcgen::Certificate,
+        issuer_key: &rcgen::KeyPair,
+        key: rcgen::KeyPair,
+        name: String,
+    ) -> anyhow::Result<Self> {
+        let cert = rcgen::Certificate::generate(
+            rcgen::CertificateParams::new(vec![name])?,
+            &key,
+            issuer,
+            issuer_key,
+        )?;
+        Ok(Self {
+            ca_cert: rustls::Certificate(issuer.der().to_vec()),
+            cert_chain: vec![rustls::Certificate(cert.der().to_vec())],
+            key: rustls::PrivateKey(key.serialize_der()),
+        })
+    }
+
+    pub fn ca_cert_der(&self) -> &[u8] {
+        &self.ca_cert.0
+    }
+
+    pub fn cert_der(&self) -> &[u8] {
+        &self.cert_chain[0].0
+    }
+
+    pub fn key_der(&self) -> &[u8] {
+        &self.key.0
+    }
+
+    pub fn peer(&self) -> QuicPeer {
+        QuicPeer::of(&self.cert_chain[0])
+    }
+}
+
+// Ed25519 because its private key is an arbitrary 32 bytes string, which makes the derivation
+// trivial. the PKCS#8 v1 encoding is a fixed prefix followed by the key
+fn quic_seeded_key(seed: [u8; 32], label: &str) -> anyhow::Result<rcgen::KeyPair> {
+    use sha2::Digest as _;
+    const PKCS8_ED25519_PREFIX: [u8; 16] = [
+        0x30, 0x2e, 0x02, 0x01, 0x00, 0x30, 0x05, 0x06, 0x03, 0x2b, 0x65, 0x70, 0x04, 0x22, 0x04,
+        0x20,
+    ];
+    let secret = sha2::Sha256::new()
+        .chain_update(seed)
+        .chain_update(label)
+        .finalize();
+    let der = [&PKCS8_ED25519_PREFIX[..], &secret].concat();
+    Ok(rcgen::KeyPair::try_from(&*der)?)
+}
+
+pub fn quic_seeded_ca(seed: [u8; 32]) -> anyhow::Result<(rcgen::Certificate, rcgen::KeyPair)> {
+    let key = quic_seeded_key(seed, "ca")?;
+    Ok((quic_ca(&key)?, key))
+}
+
+pub fn quic_ca(key: &rcgen::KeyPair) -> anyhow::Result<rcgen::Certificate> {
+    let mut params = rcgen::CertificateParams::new(Vec::<String>::new())?;
+    params.is_ca = rcgen::IsCa::Ca(rcgen::BasicConstraints::Unconstrained);
+    params
+        .distinguished_name
+        .push(rcgen::DnType::CommonName, format!("{QUIC_DOMAIN} CA"));
+    Ok(rcgen::Certificate::generate_self_signed(params, key)?)
+}
+
+// how `Quic` sends. the receiving side accepts all of them regardless of the
+// local mode, so the nodes with different modes can talk to each other
+// `UniStream` opens a unidirectional stream per message. this is the simplest
+// and the slowest, since every stream costs a round of stream credit
+// `Datagram` sends the messages that fit in a QUIC datagram unreliably, similar
+// to `Udp` but congestion controlled and encrypted. the larger messages fall
+// back to `UniStream`
+// `Stream` writes all messages into one long-lived stream with the same length
+// framing as `Tcp`, reliable and ordered, with the head-of-line blocking that
+// comes with it
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+pub enum QuicMode {
+    #[default]
+    UniStream,
+    Datagram,
+    Stream,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+pub enum QuicCongestion {
+    #[default]
+    Cubic,
+    NewReno,
+    Bbr,
+}
+
+// the windows are left to `quinn`'s defaults unless specified
+#[derive(Debug, Clone)]
+pub struct QuicConfig {
+    identity: Option<QuicIdentity>,
+    mode: QuicMode,
+    congestion: QuicCongestion,
+    idle_timeout: Duration,
+    max_concurrent_uni_streams: u32,
+    stream_receive_window: Option<u32>,
+    receive_window: Option<u32>,
+    send_window: Option<u64>,
+}
+
+impl Default for QuicConfig {
+    fn default() -> Self {
+        Self {
+            identity: None,
+            mode: Default::default(),
+            congestion: Default::default(),
+            idle_timeout: Duration::from_millis(2500),
+            max_concurrent_uni_streams: 4096,
+            stream_receive_window: None,
+            receive_window: None,
+            send_window: None,
+        }
+    }
+}
+
+impl QuicConfig {
+    // default to `QuicIdentity::testbed`
+    pub fn with_identity(mut self, identity: QuicIdentity) -> Self {
+        self.identity = Some(identity);
+        self
+    }
+
+    pub fn with_mode(mut self, mode: QuicMode) -> Self {
+        self.mode = mode;
+        self
+    }
+
+    pub fn with_congestion(mut self, congestion: QuicCongestion) -> Self {
+        self.congestion = congestion;
+        self
+    }
+
+    pub fn with_idle_timeout(mut self, idle_timeout: Duration) -> Self {
+        self.idle_timeout = idle_timeout;
+        self
+    }
+
+    pub fn with_max_concurrent_uni_streams(mut self, max_concurrent_uni_streams: u32) -> Self {
+        self.max_concurrent_uni_streams = max_concurrent_uni_streams;
+        self
+    }
+
+    pub fn with_stream_receive_window(mut self, stream_receive_window: u32) -> Self {
+        self.stream_receive_window = Some(stream_receive_window);
+        self
+    }
+
+    pub fn with_receive_window(mut self, receive_window: u32) -> Self {
+        self.receive_window = Some(receive_window);
+        self
+    }
+
+    pub fn with_send_window(mut self, send_window: u64) -> Self {
+        self.send_window = Some(send_window);
+        self
+    }
+
+    fn transport(&self) -> anyhow::Result<quinn::TransportConfig> {
+        let mut transport = quinn::TransportConfig::default();
+        transport.max_concurrent_uni_streams(self.max_concurrent_uni_streams.into());
+        transport.max_idle_timeout(Some(self.idle_timeout.try_into()?));
+        if let Some(window) = self.stream_receive_window {
+            transport.stream_receive_window(window.into());
+        }
+        if let Some(window) = self.receive_window {
+            transport.receive_window(window.into());
+        }
+        if let Some(window) = self.send_window {
+            transport.send_window(window);
+        }
+        match self.congestion {
+            QuicCongestion::Cubic => transport
+                .congestion_controller_factory(Arc::new(quinn::congestion::CubicConfig::default())),
+            QuicCongestion::NewReno => transport.congestion_controller_factory(Arc::new(
+                quinn::congestion::NewRenoConfig::default(),
+            )),
+            QuicCongestion::Bbr => transport
+                .congestion_controller_factory(Arc::new(quinn::congestion::BbrConfig::default())),
+        };
+        Ok(transport)
+    }
+
+    fn build(&self) -> anyhow::Result<(quinn::ServerConfig, quinn::ClientConfig)> {
+        let testbed;
+        let identity = match &self.identity {
+            Some(identity) => identity,
+            None => {
+                testbed = QuicIdentity::testbed()?;
+                &testbed
+            }
+        };
+        let mut roots = RootCertStore::empty();
+        let (_, ignored) = roots.add_parsable_certificates(&[&identity.ca_cert.0]);
+        anyhow::ensure!(ignored == 0, "invalid CA certificate");
+        let transport = Arc::new(self.transport()?);
+
+        let server_crypto = rustls::ServerConfig::builder()
+            .with_safe_default_cipher_suites()
+            .with_safe_default_kx_groups()
+            .with_protocol_versions(&[&rustls::version::TLS13])?
+            .with_client_cert_verifier(
+                rustls::server::AllowAnyAuthenticatedClient::new(roots.clone()).boxed(),
+            )
+            .with_single_cert(identity.cert_chain.clone(), identity.key.clone())?;
+        let mut server_config = quinn::ServerConfig::with_crypto(Arc::new(server_crypto));
+        server_config.transport = transport.clone();
+
+        let client_crypto = rustls::ClientConfig::builder()
+            .with_safe_default_cipher_suites()
+            .with_safe_default_kx_groups()
+            .with_protocol_versions(&[&rustls::version::TLS13])?
+            .with_root_certificates(roots)
+            .with_client_auth_cert(identity.cert_chain.clone(), identity.key.clone())?;
+        let mut client_config = quinn::ClientConfig::new(Arc::new(client_crypto));
+        client_config.transport_config(transport);
+        Ok((server_config, client_config))
+    }
 }
 
 impl Quic {
     pub fn new(addr: SocketAddr) -> anyhow::Result<Self> {
-        let (server_config, client_config) = quic_config()?;
+        Self::with_config(addr, QuicConfig::default())
+    }
+
+    pub fn with_config(addr: SocketAddr, config: QuicConfig) -> anyhow::Result<Self> {
+        let (server_config, client_config) = config.build()?;
         let mut endpoint = quinn::Endpoint::server(server_config, addr)?;
         endpoint.set_default_client_config(client_config);
-        Ok(Self(endpoint))
+        Ok(Self(endpoint, config.mode))
     }
 
     async fn read_task(
         connection: quinn::Connection,
-        on_buf: impl FnMut(&[u8]) -> anyhow::Result<()> + Clone + Send + 'static,
+        on_buf: impl OnBuf<QuicPeer>,
+        monitor: Monitor,
     ) {
         let remote_addr = connection.remote_address();
+        // the handshake has completed so the remote's certificate has been verified
+        let Some(peer) = connection
+            .peer_identity()
+            .and_then(|identity| identity.downcast::<Vec<rustls::Certificate>>().ok())
+            .and_then(|certs| Some(QuicPeer::of(certs.first()?)))
+        else {
+            warn!("<<< {remote_addr} no peer certificate");
+            connection.close(Default::default(), Default::default());
+            return;
+        };
+        tokio::spawn(Self::read_datagram_task(
+            connection.clone(),
+            on_buf.clone(),
+            peer,
+        ));
+        tokio::spawn(Self::accept_stream_task(
+            connection.clone(),
+            on_buf.clone(),
+            peer,
+        ));
         loop {
             let mut stream = match connection.accept_uni().await {
                 Ok(stream) => stream,
@@ -544,6 +1135,7 @@ impl Quic {
                 ) => break,
                 Err(err) => {
                     warn!("<<< {remote_addr} {err}");
+                    monitor.report(ConnectionEvent::Disconnected(remote_addr));
                     break;
                 }
             };
@@ -551,7 +1143,8 @@ impl Quic {
             tokio::spawn(async move {
                 if let Err(err) = async {
                     // determine incomplete stream?
-                    on_buf(&stream.read_to_end(MAX_BUF_LEN).await?)?;
+                    let buf = stream.read_to_end(MAX_BUF_LEN).await?;
+                    on_buf.on_buf(&peer, &buf)?;
                     anyhow::Result::<_>::Ok(())
                 }
                 .await
@@ -562,8 +1155,97 @@ impl Quic {
         }
     }
 
-    async fn write_task<B: Buf>(connection: quinn::Connection, mut receiver: UnboundedReceiver<B>) {
+    // the connection errors are reported by `read_task` and silently end the following tasks
+    async fn read_datagram_task(
+        connection: quinn::Connection,
+        mut on_buf: impl OnBuf<QuicPeer>,
+        peer: QuicPeer,
+    ) {
+        while let Ok(buf) = connection.read_datagram().await {
+            if let Err(err) = with_recv_buf(buf, |buf| on_buf.on_buf(&peer, buf)) {
+                warn!("<<< {} {err}", connection.remote_address())
+            }
+        }
+    }
+
+    async fn accept_stream_task(
+        connection: quinn::Connection,
+        on_buf: impl OnBuf<QuicPeer>,
+        peer: QuicPeer,
+    ) {
+        while let Ok((_, mut stream)) = connection.accept_bi().await {
+            let mut on_buf = on_buf.clone();
+            let connection = connection.clone();
+            tokio::spawn(async move {
+                if let Err(err) = async {
+                    loop {
+                        let len = match stream.read_u64().await {
+                            Ok(len) => len as _,
+                            Err(err) if matches!(err.kind(), ErrorKind::UnexpectedEof) => {
+                                break Ok(())
+                            }
+                            Err(err) => Err(err)?,
+                        };
+                        if len > MAX_BUF_LEN {
+                            anyhow::bail!("invalid buffer length {len}")
+                        }
+                        let mut buf = vec![0; len];
+                        AsyncReadExt::read_exact(&mut stream, &mut buf).await?;
+                        on_buf.on_buf(&peer, &buf)?
+                    }
+                }
+                .await
+                {
+                    if connection.close_reason().is_none() {
+                        warn!("<<< {} {err}", connection.remote_address())
+                    }
+                }
+            });
+        }
+    }
+
+    async fn write_task<B: Buf>(
+        connection: quinn::Connection,
+        mut receiver: UnboundedReceiver<B>,
+        mode: QuicMode,
+        monitor: Monitor,
+    ) {
+        let remote = connection.remote_address();
+        let mut stream = None;
         while let Some(buf) = receiver.recv().await {
+            match mode {
+                QuicMode::Stream => {
+                    if let Err(err) = async {
+                        if stream.is_none() {
+                            stream = Some(connection.open_bi().await?.0)
+                        }
+                        let stream = stream.as_mut().unwrap();
+                        stream.write_u64(buf.as_ref().len() as _).await?;
+                        stream.write_all(buf.as_ref()).await?;
+                        anyhow::Result::<_>::Ok(())
+                    }
+                    .await
+                    {
+                        warn!(">>> {remote} {err}");
+                        monitor.report(ConnectionEvent::Disconnected(remote));
+                        break;
+                    }
+                    continue;
+                }
+                QuicMode::Datagram
+                    if connection
+                        .max_datagram_size()
+                        .is_some_and(|max_size| buf.as_ref().len() <= max_size) =>
+                {
+                    if let Err(err) =
+                        connection.send_datagram(bytes::Bytes::copy_from_slice(buf.as_ref()))
+                    {
+                        warn!(">>> {remote} {err}")
+                    }
+                    continue;
+                }
+                _ => {}
+            }
             let connection = connection.clone();
             tokio::spawn(async move {
                 if let Err(err) = async {
@@ -576,25 +1258,30 @@ impl Quic {
                 }
             });
         }
+        // dropping the long-lived stream finishes it
         // connection.close(Default::default(), Default::default())
     }
 }
 
 impl Protocol for Quic {
+    type Peer = QuicPeer;
+
     fn connect<B: Buf>(
         &self,
         remote: SocketAddr,
-        on_buf: impl FnMut(&[u8]) -> anyhow::Result<()> + Clone + Send + 'static,
+        on_buf: impl OnBuf<QuicPeer>,
         receiver: UnboundedReceiver<B>,
+        monitor: Monitor,
     ) {
         let endpoint = self.0.clone();
+        let mode = self.1;
         // tracing::debug!("{:?} connect {remote}", endpoint.local_addr());
         tokio::spawn(async move {
             let task = async {
                 let span = tracing::debug_span!("connecting", local = ?endpoint.local_addr(), remote = ?remote).entered();
                 let connecting = {
                     let _s = tracing::debug_span!(parent: None, "dummy detached").entered();
-                    endpoint.connect(remote, "neatworks.quic")
+                    endpoint.connect(remote, &quic_server_name(remote))
                 }?;
                 drop(span.exit());
                 anyhow::Result::<_>::Ok(
@@ -607,36 +1294,46 @@ impl Protocol for Quic {
                 Ok(connection) => connection,
                 Err(err) => {
                     warn!(">>> {remote} {err}");
+                    monitor.report(ConnectionEvent::ConnectFailed(remote));
                     return;
                 }
             };
-            tokio::spawn(Self::read_task(connection.clone(), on_buf));
-            tokio::spawn(Self::write_task(connection, receiver));
+            monitor.report(ConnectionEvent::Connected(remote));
+            tokio::spawn(Self::read_task(connection.clone(), on_buf, monitor.clone()));
+            tokio::spawn(Self::write_task(connection, receiver, mode, monitor));
         });
     }
 
-    type Incoming = quinn::Connection;
+    // the local mode is carried along since `accept` has no access to `self`
+    type Incoming = (QuicMode, quinn::Connection);
 
     fn accept<B: Buf>(
-        connection: Self::Incoming,
-        on_buf: impl FnMut(&[u8]) -> anyhow::Result<()> + Clone + Send + 'static,
+        (mode, connection): Self::Incoming,
+        on_buf: impl OnBuf<QuicPeer>,
         receiver: UnboundedReceiver<B>,
+        monitor: Monitor,
     ) -> Option<SocketAddr> {
         let remote = connection.remote_address();
-        tokio::spawn(Self::read_task(connection.clone(), on_buf));
-        tokio::spawn(Self::write_task(connection, receiver));
+        tokio::spawn(Self::read_task(connection.clone(), on_buf, monitor.clone()));
+        tokio::spawn(Self::write_task(
+            connection,
+            receiver,
+            mode,
+            monitor.clone(),
+        ));
         // tracing::debug!("{remote}");
         if remote.ip().is_unspecified() {
             None
         } else {
+            monitor.report(ConnectionEvent::Connected(remote));
             Some(remote)
         }
     }
 }
 
 pub async fn quic_accept_session(
-    Quic(endpoint): Quic,
-    sender: impl SendEvent<Incoming<quinn::Connection>> + Clone + Send + 'static,
+    Quic(endpoint, mode): Quic,
+    sender: impl SendEvent<Incoming<(QuicMode, quinn::Connection)>> + Clone + Send + 'static,
 ) -> anyhow::Result<()> {
     while let Some(conn) = endpoint.accept().await {
         let remote_addr = conn.remote_address();
@@ -647,7 +1344,7 @@ pub async fn quic_accept_session(
         let mut sender = sender.clone();
         tokio::spawn(async move {
             if let Err(err) = async {
-                sender.send(Incoming(conn.await?))?;
+                sender.send(Incoming((mode, conn.await?)))?;
                 anyhow::Result::<_>::Ok(())
             }
             .await
@@ -658,3 +1355,328 @@ pub async fn quic_accept_session(
     }
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use bytes::Bytes;
+
+    use crate::{
+        event::{
+            erased::{self, Blanket, Inline},
+            UnreachableTimer,
+        },
+        net::slice_recv_buf,
+    };
+
+    use super::*;
+
+    #[tokio::test]
+    async fn connection_events() -> anyhow::Result<()> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let open = listener.local_addr()?;
+        // a port that nobody listens on
+        let closed = TcpListener::bind("127.0.0.1:0").await?.local_addr()?;
+
+        let (sender, mut receiver) = unbounded_channel();
+        let mut dispatch = Dispatch::new(Tcp::new(None)?, |_: &[u8]| Ok(()))?
+            .with_upcall(sender)
+            .with_max_connections(1);
+        let mut net = DispatchNet(Inline(&mut dispatch, &mut UnreachableTimer));
+        net.send(open, Bytes::new())?;
+        assert_eq!(
+            receiver.recv().await,
+            Some(ConnectionEvent::Connected(open))
+        );
+        net.send(closed, Bytes::new())?;
+        assert_eq!(
+            receiver.recv().await,
+            Some(ConnectionEvent::Reclaimed(open))
+        );
+        assert_eq!(
+            receiver.recv().await,
+            Some(ConnectionEvent::ConnectFailed(closed))
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn tcp_batch() -> anyhow::Result<()> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let addr = listener.local_addr()?;
+        let bufs = (0..100)
+            .map(|i| Bytes::from(vec![i as u8; i * 3000]))
+            .collect::<Vec<_>>();
+        let (sender, receiver) = unbounded_channel();
+        for buf in &bufs {
+            sender.send(buf.clone())?
+        }
+        drop(sender);
+        let (_, write) = TcpStream::connect(addr).await?.into_split();
+        let (read, _) = listener.accept().await?.0.into_split();
+        tokio::spawn(Tcp::write_task(write, receiver, addr, Monitor(None)));
+        let mut received = Vec::new();
+        Tcp::read_task(
+            read,
+            |buf: &[u8]| {
+                assert!(buf.is_empty() || slice_recv_buf(buf).is_some());
+                received.push(Bytes::copy_from_slice(buf));
+                Ok(())
+            },
+            None,
+            Monitor(None),
+        )
+        .await;
+        assert_eq!(received, bufs);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn stop_flush() -> anyhow::Result<()> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let addr = listener.local_addr()?;
+        let (sender, mut receiver) = unbounded_channel();
+        let mut peer_session = erased::Session::new();
+        let mut peer = Blanket(erased::Unify(Dispatch::<_, Bytes, _>::new(
+            Tcp::new(addr)?,
+            move |buf: &[u8]| Ok(sender.send(buf.to_vec())?),
+        )?));
+        tokio::spawn(tcp_accept_session(
+            listener,
+            erased::session::Sender::from(peer_session.sender()),
+        ));
+        tokio::spawn(async move { peer_session.run(&mut peer).await });
+
+        let mut session = erased::Session::new();
+        let mut control = Blanket(erased::Unify(Dispatch::new(
+            Tcp::new(None)?,
+            |_: &[u8]| Ok(()),
+        )?));
+        let mut sender = erased::session::Sender::from(session.sender());
+        let mut net = DispatchNet(sender.clone());
+        net.send(addr, Bytes::from_static(b"before"))?;
+        sender.send(Stop)?;
+        // sent by someone that has not noticed the stopping yet
+        net.send(addr, Bytes::from_static(b"racing"))?;
+        session.run(&mut control).await?;
+        assert!(net.send(addr, Bytes::from_static(b"after")).is_err());
+
+        let mut received = vec![
+            receiver.recv().await.unwrap(),
+            receiver.recv().await.unwrap(),
+        ];
+        received.sort();
+        assert_eq!(received, [b"before".to_vec(), b"racing".to_vec()]);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn ipv6_cluster() -> anyhow::Result<()> {
+        let mut listeners = Vec::new();
+        for _ in 0..3 {
+            listeners.push(bind_tcp_listener(IpAddr::from(Ipv6Addr::LOCALHOST), 0)?)
+        }
+        let addrs = listeners
+            .iter()
+            .map(TcpListener::local_addr)
+            .collect::<Result<Vec<_>, _>>()?;
+        let (sender, mut receiver) = unbounded_channel();
+        let mut nets = Vec::new();
+        for (i, listener) in listeners.into_iter().enumerate() {
+            let mut session = erased::Session::new();
+            let sender = sender.clone();
+            let mut control = Blanket(erased::Unify(Dispatch::new(
+                Tcp::new(addrs[i])?,
+                move |buf: &[u8]| Ok(sender.send((i, buf.to_vec()))?),
+            )?));
+            tokio::spawn(tcp_accept_session(
+                listener,
+                erased::session::Sender::from(session.sender()),
+            ));
+            erased::session::Sender::from(session.sender()).send(Init)?;
+            nets.push(DispatchNet(erased::session::Sender::from(session.sender())));
+            tokio::spawn(async move { session.run(&mut control).await });
+        }
+        for (i, net) in nets.iter_mut().enumerate() {
+            for (j, addr) in addrs.iter().enumerate() {
+                if i != j {
+                    net.send(*addr, Bytes::from(vec![i as u8]))?
+                }
+            }
+        }
+        simplex::Tcp.send(addrs[0], Bytes::from_static(b"simplex"))?;
+
+        let mut received = Vec::new();
+        for _ in 0..7 {
+            received.push(receiver.recv().await.unwrap())
+        }
+        received.sort();
+        assert_eq!(
+            received,
+            [
+                (0, vec![1]),
+                (0, vec![2]),
+                (0, b"simplex".to_vec()),
+                (1, vec![0]),
+                (1, vec![2]),
+                (2, vec![0]),
+                (2, vec![1]),
+            ]
+        );
+
+        let net = Udp(bind_udp(IpAddr::from(Ipv6Addr::LOCALHOST), 0)?.into());
+        let addr = net.0.local_addr()?;
+        let mut remote_net = Udp(bind_udp(IpAddr::from(Ipv6Addr::LOCALHOST), 0)?.into());
+        let remote = remote_net.0.local_addr()?;
+        let (sender, mut receiver) = unbounded_channel();
+        tokio::spawn(async move {
+            net.recv_session(|buf| Ok(sender.send((crate::net::recv_from(), buf.to_vec()))?))
+                .await
+        });
+        remote_net.send(addr, Bytes::from_static(b"datagram"))?;
+        assert_eq!(
+            receiver.recv().await,
+            Some((Some(remote), b"datagram".to_vec()))
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn dual_stack() -> anyhow::Result<()> {
+        let listener = bind_tcp_listener(None, 0)?;
+        let port = listener.local_addr()?.port();
+        for ip in [
+            IpAddr::from(Ipv4Addr::LOCALHOST),
+            Ipv6Addr::LOCALHOST.into(),
+        ] {
+            let _stream = TcpStream::connect((ip, port)).await?;
+            listener.accept().await?;
+        }
+
+        let socket = crate::net::blocking::Udp::bind(None, 0)?;
+        let port = socket.0.local_addr()?.port();
+        for ip in [
+            IpAddr::from(Ipv4Addr::LOCALHOST),
+            Ipv6Addr::LOCALHOST.into(),
+        ] {
+            let mut net = crate::net::blocking::Udp::bind(ip, 0)?;
+            net.send(SocketAddr::new(ip, port), Bytes::from_static(b"hello"))?;
+            let mut received = false;
+            socket.recv(
+                |buf| {
+                    assert_eq!(buf, b"hello");
+                    received = true;
+                    Ok(())
+                },
+                std::time::Instant::now() + Duration::from_millis(100),
+            )?;
+            assert!(received)
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn quic_mtls() -> anyhow::Result<()> {
+        let server = Quic::new(SocketAddr::from((Ipv4Addr::LOCALHOST, 0)))?;
+        let addr = server.0.local_addr()?;
+        let (sender, mut receiver) = unbounded_channel();
+        let mut server_session = erased::Session::new();
+        let mut dispatch = Blanket(erased::Unify(Dispatch::<_, Bytes, _>::new(
+            server.clone(),
+            WithPeer(move |peer: &QuicPeer, buf: &[u8]| Ok(sender.send((*peer, buf.to_vec()))?)),
+        )?));
+        tokio::spawn(quic_accept_session(
+            server,
+            erased::session::Sender::from(server_session.sender()),
+        ));
+        tokio::spawn(async move { server_session.run(&mut dispatch).await });
+
+        let trusted = QuicIdentity::testbed()?;
+        // trusts the server, but is issued by another CA
+        let untrusted = QuicIdentity {
+            ca_cert: trusted.ca_cert.clone(),
+            ..QuicIdentity::from_seed([42; 32], addr)?
+        };
+        for (identity, buf) in [(untrusted, "untrusted"), (trusted.clone(), "trusted")] {
+            let mut session = erased::Session::new();
+            let mut client = Blanket(erased::Unify(Dispatch::<_, Bytes, _>::new(
+                Quic::with_config(
+                    SocketAddr::from((Ipv4Addr::LOCALHOST, 0)),
+                    QuicConfig::default().with_identity(identity),
+                )?,
+                |_: &[u8]| Ok(()),
+            )?));
+            DispatchNet(erased::session::Sender::from(session.sender()))
+                .send(addr, Bytes::from(buf))?;
+            tokio::spawn(async move { session.run(&mut client).await });
+        }
+        assert_eq!(
+            receiver.recv().await,
+            Some((trusted.peer(), b"trusted".to_vec()))
+        );
+        assert!(
+            tokio::time::timeout(Duration::from_millis(100), receiver.recv())
+                .await
+                .is_err()
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn quic_modes() -> anyhow::Result<()> {
+        for mode in [QuicMode::UniStream, QuicMode::Datagram, QuicMode::Stream] {
+            let mut nets = Vec::new();
+            let mut receivers = Vec::new();
+            for _ in 0..2 {
+                let quic = Quic::with_config(
+                    SocketAddr::from((Ipv4Addr::LOCALHOST, 0)),
+                    QuicConfig::default().with_mode(mode),
+                )?;
+                let addr = quic.0.local_addr()?;
+                let (sender, receiver) = unbounded_channel();
+                let mut session = erased::Session::new();
+                let mut dispatch = Blanket(erased::Unify(Dispatch::<_, Bytes, _>::new(
+                    quic.clone(),
+                    move |buf: &[u8]| Ok(sender.send(buf.to_vec())?),
+                )?));
+                tokio::spawn(quic_accept_session(
+                    quic,
+                    erased::session::Sender::from(session.sender()),
+                ));
+                nets.push((
+                    addr,
+                    DispatchNet(erased::session::Sender::from(session.sender())),
+                ));
+                receivers.push(receiver);
+                tokio::spawn(async move { session.run(&mut dispatch).await });
+            }
+            let (server_addr, mut server) = nets.pop().unwrap();
+            let (client_addr, mut client) = nets.pop().unwrap();
+            let mut server_receiver = receivers.pop().unwrap();
+            let mut client_receiver = receivers.pop().unwrap();
+
+            // the large one does not fit in a datagram and falls back to a stream in `Datagram`
+            // mode. the reply goes through the connection that is accepted by the server
+            let bufs = [vec![1; 10], vec![2; 1000], vec![3; 100_000]];
+            for buf in &bufs {
+                client.send(server_addr, Bytes::from(buf.clone()))?
+            }
+            let mut received = Vec::new();
+            for _ in &bufs {
+                received.push(server_receiver.recv().await.unwrap())
+            }
+            // only the `Stream` mode is ordered
+            received.sort();
+            assert_eq!(received, bufs, "{mode:?}");
+            for buf in received {
+                server.send(client_addr, Bytes::from(buf))?
+            }
+            let mut received = Vec::new();
+            for _ in &bufs {
+                received.push(client_receiver.recv().await.unwrap())
+            }
+            received.sort();
+            assert_eq!(received, bufs, "{mode:?}")
+        }
+        Ok(())
+    }
+}
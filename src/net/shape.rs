@@ -0,0 +1,421 @@
+// in-process bandwidth shaping
+//
+// the bandwidth constrained experiments used to throttle the hosts' network
+// devices with `tc`, which requires root, affects everything on the host, and
+// does not work for the nodes that share one machine over loopback. `Shaped`
+// instead throttles the outgoing buffers of a raw net with token buckets: one
+// per destination and an aggregate one shared by all destinations (of all the
+// nets and the bulk transfers that share the same `Shaper`)
+//
+// the limits live behind the shared `Shaper` handle and can be changed at run
+// time, similar to `fault::FaultControl`. a change resets the buckets, i.e. the
+// debt accumulated under the old limits is forgiven
+//
+// the buffers that exceed the limits are delayed up to a maximum delay, beyond
+// which they are dropped instead, as what a full queue of a bottleneck link
+// does. a dropped buffer does not consume tokens. the delayed buffers go into a
+// queue per destination, which is drained by a detached Tokio task while it is
+// not empty, and the buffers that are not delayed skip the queue only when it
+// is empty, so the buffers to a destination are always sent in order. the bulk
+// transfers go through `Shaper::write_all` instead, which throttles a stream by
+// pausing the writing and never drops
+
+use std::{
+    collections::{HashMap, VecDeque},
+    fmt::Debug,
+    hash::Hash,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use tokio::{
+    io::{AsyncWrite, AsyncWriteExt as _},
+    time::Instant,
+};
+use tracing::warn;
+
+use super::{Buf, IterAddr, SendMessage};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Rate {
+    pub bytes_per_sec: u64,
+    // the bytes that can be sent at once after idling
+    pub burst: u64,
+}
+
+impl Rate {
+    // the default burst is 10ms worth of bytes, or 64KB for low rates so that a single datagram is
+    // never delayed because of the burst size alone
+    pub fn new(bytes_per_sec: u64) -> Self {
+        assert_ne!(bytes_per_sec, 0);
+        Self {
+            bytes_per_sec,
+            burst: (bytes_per_sec / 100).max(64 << 10),
+        }
+    }
+
+    pub fn with_burst(self, burst: u64) -> Self {
+        Self { burst, ..self }
+    }
+}
+
+#[derive(Debug)]
+struct Bucket {
+    rate: Rate,
+    // negative when the sending goes ahead of the rate, i.e. the delayed buffers
+    tokens: f64,
+    refilled: Instant,
+}
+
+impl Bucket {
+    fn new(rate: Rate, now: Instant) -> Self {
+        Self {
+            rate,
+            tokens: rate.burst as _,
+            refilled: now,
+        }
+    }
+
+    fn refill(&mut self, now: Instant) {
+        self.tokens = (self.tokens
+            + (now - self.refilled).as_secs_f64() * self.rate.bytes_per_sec as f64)
+            .min(self.rate.burst as _);
+        self.refilled = now
+    }
+
+    fn delay(&self, len: usize) -> Duration {
+        let missing = len as f64 - self.tokens;
+        if missing <= 0. {
+            Duration::ZERO
+        } else {
+            Duration::from_secs_f64(missing / self.rate.bytes_per_sec as f64)
+        }
+    }
+}
+
+#[derive(Debug)]
+struct State<A> {
+    aggregate: Option<Bucket>,
+    default: Option<Rate>,
+    per_dest: HashMap<A, Rate>,
+    buckets: HashMap<A, Bucket>,
+}
+
+impl<A> Default for State<A> {
+    fn default() -> Self {
+        Self {
+            aggregate: None,
+            default: None,
+            per_dest: Default::default(),
+            buckets: Default::default(),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct Shaper<A>(Arc<Mutex<State<A>>>);
+
+impl<A> Clone for Shaper<A> {
+    fn clone(&self) -> Self {
+        Self(self.0.clone())
+    }
+}
+
+impl<A> Default for Shaper<A> {
+    fn default() -> Self {
+        Self(Default::default())
+    }
+}
+
+impl<A: Eq + Hash + Clone> Shaper<A> {
+    // `None` for unlimited
+    pub fn set_aggregate(&self, rate: impl Into<Option<Rate>>) {
+        let mut state = self.0.lock().unwrap();
+        state.aggregate = rate.into().map(|rate| Bucket::new(rate, Instant::now()))
+    }
+
+    // the limit of the destinations that are not set with `set_dest`
+    pub fn set_default(&self, rate: impl Into<Option<Rate>>) {
+        let mut state = self.0.lock().unwrap();
+        state.default = rate.into();
+        state.buckets.clear()
+    }
+
+    pub fn set_dest(&self, dest: A, rate: Rate) {
+        let mut state = self.0.lock().unwrap();
+        state.buckets.remove(&dest);
+        state.per_dest.insert(dest, rate);
+    }
+
+    pub fn unset_dest(&self, dest: &A) {
+        let mut state = self.0.lock().unwrap();
+        state.buckets.remove(dest);
+        state.per_dest.remove(dest);
+    }
+
+    pub fn clear(&self) {
+        *self.0.lock().unwrap() = Default::default()
+    }
+
+    // take `len` bytes worth of tokens for sending to `dest`, and return how long the sending
+    // should be delayed, or `None` if the delay would exceed `max_delay` and nothing is taken
+    fn reserve(&self, dest: &A, len: usize, max_delay: Option<Duration>) -> Option<Duration> {
+        let mut state = self.0.lock().unwrap();
+        let state = &mut *state;
+        let now = Instant::now();
+        let rate = state.per_dest.get(dest).copied().or(state.default);
+        let mut bucket = rate.map(|rate| {
+            state
+                .buckets
+                .entry(dest.clone())
+                .or_insert_with(|| Bucket::new(rate, now))
+        });
+        let mut delay = Duration::ZERO;
+        for bucket in bucket
+            .iter_mut()
+            .map(|bucket| &mut **bucket)
+            .chain(&mut state.aggregate)
+        {
+            bucket.refill(now);
+            delay = delay.max(bucket.delay(len))
+        }
+        if max_delay.is_some_and(|max_delay| delay > max_delay) {
+            return None;
+        }
+        for bucket in bucket.into_iter().chain(&mut state.aggregate) {
+            bucket.tokens -= len as f64
+        }
+        Some(delay)
+    }
+
+    // the writing is paused between the chunks when the limits are exceeded
+    pub async fn write_all(
+        &self,
+        dest: &A,
+        stream: &mut (impl AsyncWrite + Unpin),
+        buf: &[u8],
+    ) -> std::io::Result<()> {
+        const CHUNK_LEN: usize = 16 << 10;
+        for chunk in buf.chunks(CHUNK_LEN) {
+            let delay = self.reserve(dest, chunk.len(), None).unwrap();
+            if !delay.is_zero() {
+                tokio::time::sleep(delay).await
+            }
+            stream.write_all(chunk).await?
+        }
+        Ok(())
+    }
+}
+
+// the delayed sendings of a destination, each one is the deadline and the sending that captures
+// the buffer
+#[derive(Default)]
+struct Queue {
+    delayed: VecDeque<(Instant, Box<dyn FnOnce() + Send>)>,
+    draining: bool,
+}
+
+impl Debug for Queue {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Queue")
+            .field("delayed", &self.delayed.len())
+            .field("draining", &self.draining)
+            .finish()
+    }
+}
+
+// the limits are looked up by the key of the destination, which is the destination itself by
+// default. `new_keyed` takes a coarser key instead e.g. the IP address, so that the destinations
+// that share a key share the limits, as if they are behind the same link. the queues are still per
+// destination
+// the clones share the queues
+#[derive(Debug, Clone)]
+pub struct Shaped<N, A, K = A> {
+    inner: N,
+    shaper: Shaper<K>,
+    key: fn(&A) -> K,
+    max_delay: Duration,
+    queues: Arc<Mutex<HashMap<A, Queue>>>,
+}
+
+impl<N, A: Clone> Shaped<N, A> {
+    pub fn new(inner: N, shaper: Shaper<A>) -> Self {
+        Self::new_keyed(inner, shaper, A::clone)
+    }
+}
+
+impl<N, A, K> Shaped<N, A, K> {
+    pub fn new_keyed(inner: N, shaper: Shaper<K>, key: fn(&A) -> K) -> Self {
+        Self {
+            inner,
+            shaper,
+            key,
+            max_delay: Duration::from_millis(100),
+            queues: Default::default(),
+        }
+    }
+
+    pub fn with_max_delay(self, max_delay: Duration) -> Self {
+        Self { max_delay, ..self }
+    }
+}
+
+impl<N, A, K, B> SendMessage<A, B> for Shaped<N, A, K>
+where
+    N: SendMessage<A, B> + Clone + Send + 'static,
+    A: Eq + Hash + Clone + Send + 'static,
+    K: Eq + Hash + Clone,
+    B: Buf,
+{
+    fn send(&mut self, dest: A, buf: B) -> anyhow::Result<()> {
+        // the queue is locked before taking the tokens, so the concurrent sendings through the
+        // clones are queued in the order of their deadlines
+        let mut queues = self.queues.lock().unwrap();
+        let Some(delay) =
+            self.shaper
+                .reserve(&(self.key)(&dest), buf.as_ref().len(), Some(self.max_delay))
+        else {
+            return Ok(());
+        };
+        let queue = queues.entry(dest.clone()).or_default();
+        if delay.is_zero() && !queue.draining {
+            drop(queues);
+            return self.inner.send(dest, buf);
+        }
+        let mut inner = self.inner.clone();
+        let send_dest = dest.clone();
+        queue.delayed.push_back((
+            Instant::now() + delay,
+            Box::new(move || {
+                if let Err(err) = inner.send(send_dest, buf) {
+                    warn!("shaped sending {err}")
+                }
+            }),
+        ));
+        if !queue.draining {
+            queue.draining = true;
+            tokio::spawn(drain(self.queues.clone(), dest));
+        }
+        Ok(())
+    }
+}
+
+async fn drain<A: Eq + Hash>(queues: Arc<Mutex<HashMap<A, Queue>>>, dest: A) {
+    loop {
+        let delayed = {
+            let mut queues = queues.lock().unwrap();
+            let queue = queues.get_mut(&dest).unwrap();
+            let delayed = queue.delayed.pop_front();
+            queue.draining = delayed.is_some();
+            delayed
+        };
+        let Some((deadline, send)) = delayed else {
+            break;
+        };
+        tokio::time::sleep_until(deadline).await;
+        send()
+    }
+}
+
+impl<N, A, K, B> SendMessage<IterAddr<'_, A>, B> for Shaped<N, A, K>
+where
+    N: SendMessage<A, B> + Clone + Send + 'static,
+    A: Eq + Hash + Clone + Send + 'static,
+    K: Eq + Hash + Clone,
+    B: Buf,
+{
+    fn send(&mut self, dest: IterAddr<'_, A>, buf: B) -> anyhow::Result<()> {
+        for addr in dest.0 {
+            self.send(addr, buf.clone())?
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bytes::Bytes;
+    use tokio::sync::mpsc::{unbounded_channel, UnboundedSender};
+
+    use crate::net::Capture;
+
+    use super::*;
+
+    #[derive(Clone)]
+    struct Forward(UnboundedSender<(u8, Instant)>);
+
+    impl SendMessage<u8, Bytes> for Forward {
+        fn send(&mut self, dest: u8, _: Bytes) -> anyhow::Result<()> {
+            Ok(self.0.send((dest, Instant::now()))?)
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn shape() -> anyhow::Result<()> {
+        let shaper = Shaper::default();
+        // 1000 bytes per destination per 100ms, 1500 bytes per 100ms in total
+        shaper.set_default(Rate::new(10_000).with_burst(1000));
+        shaper.set_aggregate(Rate::new(15_000).with_burst(1000));
+        let (sender, mut receiver) = unbounded_channel();
+        let mut net = Shaped::new(Forward(sender), shaper.clone());
+        let start = Instant::now();
+        for _ in 0..4 {
+            net.send(0, Bytes::from(vec![0; 500]))?
+        }
+        // delayed only by the aggregate limit
+        net.send(1, Bytes::from(vec![0; 500]))?;
+        // beyond the maximum delay
+        net.send(0, Bytes::from(vec![0; 500]))?;
+
+        let mut sent = Vec::new();
+        for _ in 0..5 {
+            let (dest, instant) = receiver.recv().await.unwrap();
+            // the timers are in the granularity of milliseconds
+            sent.push((dest, (instant - start).as_millis() / 10 * 10))
+        }
+        sent.sort();
+        assert_eq!(sent, [(0, 0), (0, 0), (0, 50), (0, 100), (1, 100)]);
+        shaper.clear();
+        net.send(0, Bytes::from(vec![0; 500]))?;
+        assert_eq!(receiver.try_recv()?.0, 0);
+        Ok(())
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn in_order() -> anyhow::Result<()> {
+        let shaper = Shaper::default();
+        shaper.set_default(Rate::new(10_000).with_burst(1000));
+        let capture = Capture::default();
+        let mut net = Shaped::new(capture.clone(), shaper);
+        net.send(0u8, Bytes::from(vec![0; 1000]))?;
+        // the deadlines are 0.1ms apart, below the granularity of the timers
+        for i in 1..=20 {
+            net.send(0, Bytes::from(vec![i]))?
+        }
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        let sent = capture
+            .take()
+            .into_iter()
+            .map(|(_, buf)| buf[0])
+            .collect::<Vec<_>>();
+        assert_eq!(sent, (0..=20).collect::<Vec<_>>());
+        Ok(())
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn write_all() -> anyhow::Result<()> {
+        let shaper = Shaper::default();
+        shaper.set_default(Rate::new(100_000).with_burst(16 << 10));
+        let buf = (0..40 << 10).map(|i| i as u8).collect::<Vec<_>>();
+        let mut stream = Vec::new();
+        let start = Instant::now();
+        shaper.write_all(&0u8, &mut stream, &buf).await?;
+        assert_eq!(stream, buf);
+        // the first 16KB chunk is within the burst, and the rest 24KB is throttled
+        let expected = Duration::from_secs_f64((24 << 10) as f64 / 100_000.);
+        let elapsed = start.elapsed();
+        // the timers round up to milliseconds
+        assert!(elapsed >= expected && elapsed <= expected + Duration::from_millis(2));
+        Ok(())
+    }
+}
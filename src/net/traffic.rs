@@ -0,0 +1,262 @@
+// traffic accounting
+//
+// count the messages and the bytes per peer address and per message kind, for
+// both directions, into a shared `Traffic` handle that can be read at any time
+// e.g. from the HTTP endpoint of the evaluation artifact. the kind of a message
+// is the variant name for the enums declared with `messages!`, and the type
+// name for the standalone message types, see `Kind`
+//
+// the message kind is only known above `MessageNet` while the peer address is
+// only known after the routing nets e.g. `IndexNet` translate the replica ids,
+// so the sending side takes two pieces. `Counted` wraps a `MessageNet` and
+// tells the kind of the message being sent, and `Metered` is a raw net below
+// the routing nets that does the counting. the kind goes from the former to the
+// latter through a thread local, the same trick as `with_recv_buf`. without a
+// `Counted` above, `Metered` counts everything as `UNKNOWN_KIND`. place
+// `Metered` above e.g. `Fragmented` and `Faulty` to count the messages instead
+// of the fragments, and to count within the sending call
+//
+// the receiving side is `Traffic::on_buf`, which is given the source address
+// e.g. `super::recv_from()` of the IP raw nets, or `None` if the receiving loop
+// does not tell one. the kind is decided from the leading bincode variant tag
+// without decoding the whole message, so it only works with the default codec
+//
+// the counting happens on every sending and receiving thread, so the counts are
+// sharded, and each thread takes the lock of its own shard most of the time.
+// `counts` merges the shards
+
+use std::{
+    cell::Cell,
+    collections::HashMap,
+    hash::Hash,
+    sync::{
+        atomic::{AtomicUsize, Ordering::Relaxed},
+        Arc, Mutex,
+    },
+};
+
+use serde::{Deserialize, Serialize};
+
+use super::{Buf, IterAddr, MessageNet, SendMessage};
+
+pub trait Kind {
+    fn kind(&self) -> &'static str;
+
+    // the kind of an encoded message, or `None` if it cannot be told without decoding
+    fn kind_of_buf(buf: &[u8]) -> Option<&'static str>;
+}
+
+pub const UNKNOWN_KIND: &str = "?";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize, Deserialize)]
+pub enum Direction {
+    Sent,
+    Received,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Count {
+    pub messages: u64,
+    pub bytes: u64,
+}
+
+type Counts<A> = HashMap<(Direction, Option<A>, &'static str), Count>;
+
+const NUM_SHARD: usize = 16;
+
+#[derive(Debug)]
+pub struct Traffic<A>(Arc<[Mutex<Counts<A>>; NUM_SHARD]>);
+
+impl<A> Clone for Traffic<A> {
+    fn clone(&self) -> Self {
+        Self(self.0.clone())
+    }
+}
+
+impl<A> Default for Traffic<A> {
+    fn default() -> Self {
+        Self(Arc::new(std::array::from_fn(|_| Default::default())))
+    }
+}
+
+thread_local! {
+    // the threads are assigned to the shards in turn
+    static SHARD: usize = {
+        static NEXT_SHARD: AtomicUsize = AtomicUsize::new(0);
+        NEXT_SHARD.fetch_add(1, Relaxed) % NUM_SHARD
+    };
+}
+
+impl<A: Eq + Hash> Traffic<A> {
+    pub fn record(&self, direction: Direction, addr: Option<A>, kind: &'static str, len: usize) {
+        let mut counts = self.0[SHARD.with(|shard| *shard)].lock().unwrap();
+        let count = counts.entry((direction, addr, kind)).or_default();
+        count.messages += 1;
+        count.bytes += len as u64
+    }
+
+    // count the received `buf` that is decoded into `M` by the following `on_buf`
+    pub fn on_buf<M: Kind>(&self, remote: Option<A>, buf: &[u8]) {
+        self.record(
+            Direction::Received,
+            remote,
+            M::kind_of_buf(buf).unwrap_or(UNKNOWN_KIND),
+            buf.len(),
+        )
+    }
+
+    pub fn clear(&self) {
+        for counts in &*self.0 {
+            counts.lock().unwrap().clear()
+        }
+    }
+}
+
+impl<A: Clone + Eq + Hash> Traffic<A> {
+    pub fn counts(&self) -> Vec<(Direction, Option<A>, &'static str, Count)> {
+        let mut merged = Counts::new();
+        for counts in &*self.0 {
+            for (key, count) in &*counts.lock().unwrap() {
+                let merged = merged.entry(key.clone()).or_default();
+                merged.messages += count.messages;
+                merged.bytes += count.bytes
+            }
+        }
+        merged
+            .into_iter()
+            .map(|((direction, addr, kind), count)| (direction, addr, kind, count))
+            .collect()
+    }
+}
+
+thread_local! {
+    static SENDING_KIND: Cell<Option<&'static str>> = const { Cell::new(None) };
+}
+
+#[derive(Debug)]
+pub struct Counted<T, M>(pub MessageNet<T, M>);
+
+impl<T: Clone, M> Clone for Counted<T, M> {
+    fn clone(&self) -> Self {
+        Self(self.0.clone())
+    }
+}
+
+impl<T, M> From<MessageNet<T, M>> for Counted<T, M> {
+    fn from(value: MessageNet<T, M>) -> Self {
+        Self(value)
+    }
+}
+
+impl<T, A, M: Into<N>, N: Kind> SendMessage<A, M> for Counted<T, N>
+where
+    MessageNet<T, N>: SendMessage<A, N>,
+{
+    fn send(&mut self, dest: A, message: M) -> anyhow::Result<()> {
+        let message = message.into();
+        let saved = SENDING_KIND.with(|kind| kind.replace(Some(message.kind())));
+        let result = self.0.send(dest, message);
+        SENDING_KIND.with(|kind| kind.set(saved));
+        result
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Metered<N, A> {
+    inner: N,
+    traffic: Traffic<A>,
+}
+
+impl<N, A> Metered<N, A> {
+    pub fn new(inner: N, traffic: Traffic<A>) -> Self {
+        Self { inner, traffic }
+    }
+}
+
+fn sending_kind() -> &'static str {
+    SENDING_KIND.with(Cell::get).unwrap_or(UNKNOWN_KIND)
+}
+
+impl<N: SendMessage<A, B>, A: Clone + Eq + Hash, B: Buf> SendMessage<A, B> for Metered<N, A> {
+    fn send(&mut self, dest: A, buf: B) -> anyhow::Result<()> {
+        self.traffic.record(
+            Direction::Sent,
+            Some(dest.clone()),
+            sending_kind(),
+            buf.as_ref().len(),
+        );
+        self.inner.send(dest, buf)
+    }
+}
+
+impl<N: for<'a> SendMessage<IterAddr<'a, A>, B>, A: Clone + Eq + Hash + Send + Sync, B: Buf>
+    SendMessage<IterAddr<'_, A>, B> for Metered<N, A>
+{
+    fn send(&mut self, dest: IterAddr<'_, A>, buf: B) -> anyhow::Result<()> {
+        let addrs = dest.0.collect::<Vec<_>>();
+        let kind = sending_kind();
+        for addr in &addrs {
+            self.traffic.record(
+                Direction::Sent,
+                Some(addr.clone()),
+                kind,
+                buf.as_ref().len(),
+            )
+        }
+        self.inner.send(IterAddr(&mut addrs.into_iter()), buf)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bytes::Bytes;
+
+    use crate::{
+        event::Void,
+        net::{All, IndexNet},
+    };
+
+    use super::*;
+
+    crate::messages! {
+        enum Message {
+            Ping(u32),
+            Pong(u64),
+        }
+        type TestMessageNet;
+        trait SendRecvEvent;
+        fn on_buf;
+    }
+
+    #[test]
+    fn count() -> anyhow::Result<()> {
+        let traffic = Traffic::default();
+        let mut net = Counted::from(TestMessageNet::new(IndexNet::new(
+            Metered::new(Void, traffic.clone()),
+            vec![10u16, 11, 12],
+            0,
+        )));
+        net.send(All, 1u32)?;
+        net.send(1u8, 2u64)?;
+        Metered::<_, u16>::new(Void, traffic.clone()).send(11, Bytes::from_static(b"raw"))?;
+        let mut buf = bytes::BytesMut::new();
+        crate::net::codec::Codec::default().encode(&Message::Pong(3), &mut buf)?;
+        traffic.on_buf::<Message>(Some(12), &buf);
+        on_buf(&buf, &mut Void)?;
+
+        let mut counts = traffic.counts();
+        counts.sort_by_key(|(direction, addr, kind, _)| (*direction, *addr, *kind));
+        let count = |messages, bytes| Count { messages, bytes };
+        assert_eq!(
+            counts,
+            [
+                (Direction::Sent, Some(11), "?", count(1, 3)),
+                (Direction::Sent, Some(11), "Ping", count(1, 2)),
+                (Direction::Sent, Some(11), "Pong", count(1, 2)),
+                (Direction::Sent, Some(12), "Ping", count(1, 2)),
+                (Direction::Received, Some(12), "Pong", count(1, 2)),
+            ]
+        );
+        Ok(())
+    }
+}
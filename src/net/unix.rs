@@ -0,0 +1,220 @@
+// Unix domain datagram raw net for the nodes on the same host
+//
+// a local cluster over `127.0.0.x` goes through the whole loopback UDP/IP stack
+// for every packet. a Unix datagram socket skips the IP layer and the checksum,
+// and it is reliable: the sender blocks (or here, spawns a task and waits)
+// instead of dropping when the receiver's queue is full. the buffers arrive in
+// order as long as the receiver keeps up, i.e. no sending is deferred
+//
+// `Unix` is addressed by socket paths directly. `Mapped` keeps the protocols
+// addressed by `SocketAddr`, by mapping every address to a socket path under a
+// shared directory, so the same replica/client code runs on it unchanged. it
+// also implements `Protocol` so it can be a drop-in replacement of `Tcp` under
+// `Dispatch`, although there's nothing to reuse: every "connection" is just a
+// task that forwards to the mapped path, and there's no incoming connection.
+// the receiving side is always `recv_session`
+
+use std::{
+    convert::Infallible,
+    io::ErrorKind,
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use tokio::{net::UnixDatagram, sync::mpsc::UnboundedReceiver};
+use tracing::warn;
+
+use super::{
+    session::{ConnectionEvent, Monitor, OnBuf, Protocol},
+    with_recv_buf, Buf, Chunk, IterAddr, SendMessage,
+};
+
+#[derive(Debug, Clone)]
+pub struct Unix {
+    socket: Arc<UnixDatagram>,
+    // a nonblocking clone for sending without going through the runtime, which considers the
+    // socket not writable until it gets polled, so `try_send_to` would always fail at first
+    std_socket: Arc<std::os::unix::net::UnixDatagram>,
+}
+
+impl Unix {
+    // a stale socket file left by a previous run is removed, otherwise the binding fails
+    pub fn bind(path: impl AsRef<Path>) -> anyhow::Result<Self> {
+        match std::fs::remove_file(&path) {
+            Err(err) if err.kind() != ErrorKind::NotFound => Err(err)?,
+            _ => {}
+        }
+        let socket = std::os::unix::net::UnixDatagram::bind(path)?;
+        socket.set_nonblocking(true)?;
+        Ok(Self {
+            std_socket: Arc::new(socket.try_clone()?),
+            socket: Arc::new(UnixDatagram::from_std(socket)?),
+        })
+    }
+
+    pub async fn recv_session(
+        &self,
+        mut on_buf: impl FnMut(&[u8]) -> anyhow::Result<()>,
+    ) -> anyhow::Result<()> {
+        // same chunked receiving as `session::Udp`
+        let mut chunk = Chunk::new(1 << 20);
+        loop {
+            self.socket.recv_buf_from(chunk.reserve(1 << 16)).await?;
+            with_recv_buf(chunk.split(), &mut on_buf)?
+        }
+    }
+}
+
+impl<B: Buf> SendMessage<PathBuf, B> for Unix {
+    fn send(&mut self, dest: PathBuf, buf: B) -> anyhow::Result<()> {
+        // the fast path that does not spawn, taken as long as the receiver keeps up
+        match self.std_socket.send_to(buf.as_ref(), &dest) {
+            Ok(_) => return Ok(()),
+            Err(err) if err.kind() == ErrorKind::WouldBlock => {}
+            Err(err) => {
+                // the same as `Udp`, a failed sending is not the sender's failure
+                warn!(">>> {} {err}", dest.display());
+                return Ok(());
+            }
+        }
+        let socket = self.socket.clone();
+        tokio::spawn(async move {
+            if let Err(err) = socket.send_to(buf.as_ref(), &dest).await {
+                warn!(">>> {} {err}", dest.display())
+            }
+        });
+        Ok(())
+    }
+}
+
+impl<B: Buf> SendMessage<IterAddr<'_, PathBuf>, B> for Unix {
+    fn send(&mut self, dest: IterAddr<'_, PathBuf>, buf: B) -> anyhow::Result<()> {
+        for path in dest.0 {
+            self.send(path, buf.clone())?
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Mapped {
+    pub socket: Unix,
+    dir: PathBuf,
+}
+
+pub fn mapped_path(dir: impl AsRef<Path>, addr: SocketAddr) -> PathBuf {
+    dir.as_ref().join(format!("{addr}.sock"))
+}
+
+impl Mapped {
+    pub fn bind(dir: impl Into<PathBuf>, addr: SocketAddr) -> anyhow::Result<Self> {
+        let dir = dir.into();
+        std::fs::create_dir_all(&dir)?;
+        Ok(Self {
+            socket: Unix::bind(mapped_path(&dir, addr))?,
+            dir,
+        })
+    }
+
+    pub async fn recv_session(
+        &self,
+        on_buf: impl FnMut(&[u8]) -> anyhow::Result<()>,
+    ) -> anyhow::Result<()> {
+        self.socket.recv_session(on_buf).await
+    }
+}
+
+impl<B: Buf> SendMessage<SocketAddr, B> for Mapped {
+    fn send(&mut self, dest: SocketAddr, buf: B) -> anyhow::Result<()> {
+        self.socket.send(mapped_path(&self.dir, dest), buf)
+    }
+}
+
+impl<B: Buf> SendMessage<IterAddr<'_, SocketAddr>, B> for Mapped {
+    fn send(&mut self, dest: IterAddr<'_, SocketAddr>, buf: B) -> anyhow::Result<()> {
+        for addr in dest.0 {
+            self.send(addr, buf.clone())?
+        }
+        Ok(())
+    }
+}
+
+impl Protocol for Mapped {
+    type Peer = ();
+
+    fn connect<B: Buf>(
+        &self,
+        remote: SocketAddr,
+        _: impl OnBuf<()>,
+        mut receiver: UnboundedReceiver<B>,
+        monitor: Monitor,
+    ) {
+        let socket = self.socket.socket.clone();
+        let path = mapped_path(&self.dir, remote);
+        tokio::spawn(async move {
+            let mut connected = false;
+            while let Some(buf) = receiver.recv().await {
+                if let Err(err) = socket.send_to(buf.as_ref(), &path).await {
+                    warn!(">>> {remote} {err}");
+                    // nobody bound the path, which is the closest thing to a failed connecting
+                    monitor.report(if connected {
+                        ConnectionEvent::Disconnected(remote)
+                    } else {
+                        ConnectionEvent::ConnectFailed(remote)
+                    });
+                    break;
+                }
+                if !connected {
+                    connected = true;
+                    monitor.report(ConnectionEvent::Connected(remote))
+                }
+            }
+        });
+    }
+
+    type Incoming = Infallible;
+
+    fn accept<B: Buf>(
+        connection: Self::Incoming,
+        _: impl OnBuf<()>,
+        _: UnboundedReceiver<B>,
+        _: Monitor,
+    ) -> Option<SocketAddr> {
+        match connection {}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use bytes::Bytes;
+    use tokio::time::timeout;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn mapped() -> anyhow::Result<()> {
+        let dir = tempfile::tempdir()?;
+        let dir = dir.path();
+        let addr = SocketAddr::from(([127, 0, 0, 1], 10000));
+        let receiver = Mapped::bind(dir, addr)?;
+        let mut sender = Mapped::bind(dir, SocketAddr::from(([127, 0, 0, 1], 10001)))?;
+        for i in 0..10u8 {
+            sender.send(addr, Bytes::from(vec![i; 1000]))?
+        }
+
+        let mut received = Vec::new();
+        let _ = timeout(
+            Duration::from_millis(100),
+            receiver.recv_session(|buf| {
+                received.push(buf.to_vec());
+                Ok(())
+            }),
+        )
+        .await;
+        assert_eq!(received, (0..10).map(|i| vec![i; 1000]).collect::<Vec<_>>());
+        Ok(())
+    }
+}
@@ -9,8 +9,8 @@ use crate::{
         Crypto, DigestHash as _, Verifiable,
     },
     event::{
-        erased::{OnEventRichTimer as OnEvent, RichTimer as Timer},
-        SendEvent, TimerId,
+        erased::{events::Stop, OnEventRichTimer as OnEvent, RichTimer as Timer},
+        Exit, SendEvent, TimerId,
     },
     message::{Payload, Request},
     net::{deserialize, events::Recv, Addr, All, MessageNet, SendMessage},
@@ -49,6 +49,16 @@ pub struct Reply {
     replica_id: u8,
 }
 
+impl crate::net::traffic::Kind for Reply {
+    fn kind(&self) -> &'static str {
+        "Reply"
+    }
+
+    fn kind_of_buf(_: &[u8]) -> Option<&'static str> {
+        Some("Reply")
+    }
+}
+
 pub trait ToClientNet<A>: SendMessage<A, Reply> {}
 impl<T: SendMessage<A, Reply>, A> ToClientNet<A> for T {}
 
@@ -409,15 +419,14 @@ impl<S, A: Addr> OnEvent<Recv<(Verifiable<PrePrepare>, Vec<Request<A>>)>> for Re
         // commits) in order to mitigate faulty proposals
         // omitted since it makes no difference in normal path
         let replica_id = pre_prepare.view_num as usize % self.num_replica;
-        self.crypto_worker.submit(Box::new(move |crypto, sender| {
-            if requests.sha256() == pre_prepare.digest
-                && crypto.verify(replica_id, &pre_prepare).is_ok()
-            {
-                sender.send((Verified(pre_prepare), requests))
-            } else {
-                Ok(())
-            }
-        }))
+        self.crypto_worker
+            .submit_verify(replica_id, pre_prepare, move |pre_prepare, sender| {
+                if requests.sha256() == pre_prepare.digest {
+                    sender.send((Verified(pre_prepare), requests))
+                } else {
+                    Ok(())
+                }
+            })
     }
 }
 
@@ -515,13 +524,11 @@ impl<S, A> Replica<S, A> {
                 }
             }
         }
-        self.crypto_worker.submit(Box::new(move |crypto, sender| {
-            if crypto.verify(prepare.replica_id, &prepare).is_ok() {
-                sender.send(Verified(prepare))
-            } else {
-                Ok(())
-            }
-        }))?;
+        self.crypto_worker.submit_verify(
+            prepare.replica_id.into(),
+            prepare,
+            |prepare, sender| sender.send(Verified(prepare)),
+        )?;
         Ok(true)
     }
 }
@@ -646,13 +653,10 @@ impl<S, A> Replica<S, A> {
                 }
             }
         }
-        self.crypto_worker.submit(Box::new(move |crypto, sender| {
-            if crypto.verify(commit.replica_id, &commit).is_ok() {
+        self.crypto_worker
+            .submit_verify(commit.replica_id.into(), commit, |commit, sender| {
                 sender.send(Verified(commit))
-            } else {
-                Ok(())
-            }
-        }))?;
+            })?;
         Ok(true)
     }
 }
@@ -719,7 +723,7 @@ impl<S: App, A: Addr> Replica<S, A> {
             self.commit_num += 1;
             // println!("Commit {}", self.commit_num);
             for request in &entry.requests {
-                let result = Payload(self.app.execute(&request.op)?);
+                let result = Payload::from(self.app.execute(&request.op)?);
                 let seq = request.seq;
                 let reply = Reply {
                     seq,
@@ -750,6 +754,14 @@ impl<S: App, A: Addr> Replica<S, A> {
     }
 }
 
+// the crypto jobs that are still in the worker are abandoned. their results will
+// fail to be sent back to the closed session, which is fine
+impl<S, A> OnEvent<Stop> for Replica<S, A> {
+    fn on_event(&mut self, Stop: Stop, _: &mut impl Timer<Self>) -> anyhow::Result<()> {
+        Err(Exit)?
+    }
+}
+
 pub type ToClientMessageNet<T> = MessageNet<T, Reply>;
 
 pub fn to_client_on_buf(
@@ -759,41 +771,98 @@ pub fn to_client_on_buf(
     sender.send(Recv(deserialize(buf)?))
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize, derive_more::From)]
-pub enum ToReplica<A> {
-    Request(Request<A>),
-    PrePrepare(Verifiable<PrePrepare>, Vec<Request<A>>),
-    Prepare(Verifiable<Prepare>),
-    Commit(Verifiable<Commit>),
-}
-
-pub type ToReplicaMessageNet<T, A> = MessageNet<T, ToReplica<A>>;
-
-pub trait SendReplicaRecvEvent<A>:
-    SendEvent<Recv<Request<A>>>
-    + SendEvent<Recv<(Verifiable<PrePrepare>, Vec<Request<A>>)>>
-    + SendEvent<Recv<Verifiable<Prepare>>>
-    + SendEvent<Recv<Verifiable<Commit>>>
-{
-}
-impl<
-        T: SendEvent<Recv<Request<A>>>
-            + SendEvent<Recv<(Verifiable<PrePrepare>, Vec<Request<A>>)>>
-            + SendEvent<Recv<Verifiable<Prepare>>>
-            + SendEvent<Recv<Verifiable<Commit>>>,
-        A,
-    > SendReplicaRecvEvent<A> for T
-{
-}
-
-pub fn to_replica_on_buf<A: Addr>(
-    buf: &[u8],
-    sender: &mut impl SendReplicaRecvEvent<A>,
-) -> anyhow::Result<()> {
-    match deserialize(buf)? {
-        ToReplica::Request(message) => sender.send(Recv(message)),
-        ToReplica::PrePrepare(message, requests) => sender.send(Recv((message, requests))),
-        ToReplica::Prepare(message) => sender.send(Recv(message)),
-        ToReplica::Commit(message) => sender.send(Recv(message)),
+crate::messages! {
+    pub enum ToReplica<A> {
+        Request(Request<A>),
+        PrePrepare((Verifiable<PrePrepare>, Vec<Request<A>>)),
+        Prepare(Verifiable<Prepare>),
+        Commit(Verifiable<Commit>),
+    }
+    pub type ToReplicaMessageNet;
+    pub trait SendReplicaRecvEvent;
+    pub fn to_replica_on_buf;
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+
+    use tokio::{sync::mpsc::unbounded_channel, task::JoinSet};
+
+    use crate::{
+        app::Null,
+        crypto::CryptoFlavor,
+        event::erased::{
+            session::{Buffered, Sender},
+            Blanket, Session,
+        },
+        message::Payload,
+        net::{memory::Network, IndexNet},
+    };
+
+    use super::*;
+
+    #[tokio::test]
+    async fn in_memory_cluster() -> anyhow::Result<()> {
+        let num_replica = 4;
+        let num_faulty = 1;
+        let replica_addrs = (0..num_replica)
+            .map(|i| SocketAddr::from(([10, 0, 0, i as u8 + 1], 0)))
+            .collect::<Vec<_>>();
+        let client_addr = SocketAddr::from(([10, 0, 1, 1], 0));
+        let net = Network::new();
+        let mut sessions = JoinSet::new();
+
+        for replica_id in 0..num_replica as u8 {
+            let crypto = Crypto::new_hardcoded_replication(
+                num_replica,
+                replica_id,
+                CryptoFlavor::Schnorrkel,
+            )?;
+            let mut session = Session::new();
+            let mut state = Blanket(Buffered::from(Replica::<_, SocketAddr>::new(
+                replica_id,
+                Null,
+                ToReplicaMessageNet::new(IndexNet::new(
+                    net.clone(),
+                    replica_addrs.clone(),
+                    replica_id as usize,
+                )),
+                ToClientMessageNet::new(net.clone()),
+                Worker::new_inline(crypto, Box::new(Sender::from(session.sender()))),
+                num_replica,
+                num_faulty,
+            )));
+            let mut sender = Sender::from(session.sender());
+            net.register_on_buf(replica_addrs[replica_id as usize], move |buf| {
+                to_replica_on_buf(buf, &mut sender)
+            })?;
+            sessions.spawn(async move { session.run(&mut state).await });
+        }
+
+        let (upcall, mut upcall_receiver) = unbounded_channel::<InvokeOk>();
+        let mut session = Session::new();
+        let mut state = Blanket(Buffered::from(Client::new(
+            0,
+            client_addr,
+            ToReplicaMessageNet::new(IndexNet::new(net.clone(), replica_addrs, None)),
+            upcall,
+            num_replica,
+            num_faulty,
+        )));
+        let mut sender = Sender::from(session.sender());
+        net.register_on_buf(client_addr, move |buf| to_client_on_buf(buf, &mut sender))?;
+        let mut client_sender = Sender::from(session.sender());
+        sessions.spawn(async move { session.run(&mut state).await });
+
+        for _ in 0..10 {
+            client_sender.send(Invoke(Payload(Default::default())))?;
+            tokio::select! {
+                result = upcall_receiver.recv() => assert!(result.is_some()),
+                result = sessions.join_next() => result.unwrap()??,
+            }
+        }
+        sessions.shutdown().await;
+        Ok(())
     }
 }
@@ -4,7 +4,10 @@ use serde::{Deserialize, Serialize};
 
 use crate::{
     app::App,
-    event::{erased::OnEvent as On, OnEvent, OnTimer, SendEvent, Timer, TimerId},
+    event::{
+        erased::{events::Stop, OnEvent as On},
+        Exit, OnEvent, OnTimer, SendEvent, Timer, TimerId,
+    },
     message::{Payload, Request},
     net::{deserialize, events::Recv, Addr, MessageNet, SendMessage},
     workload::{Invoke, InvokeOk},
@@ -16,13 +19,23 @@ pub struct Reply {
     result: Payload,
 }
 
+impl crate::net::traffic::Kind for Reply {
+    fn kind(&self) -> &'static str {
+        "Reply"
+    }
+
+    fn kind_of_buf(_: &[u8]) -> Option<&'static str> {
+        Some("Reply")
+    }
+}
+
 pub trait ToClientNet<A>: SendMessage<A, Reply> {}
 impl<T: SendMessage<A, Reply>, A> ToClientNet<A> for T {}
 
 pub trait ToReplicaNet<A>: SendMessage<u8, Request<A>> {}
 impl<T: SendMessage<u8, Request<A>>, A> ToReplicaNet<A> for T {}
 
-#[derive(Debug)]
+#[derive(Debug, Serialize, Deserialize)]
 pub enum ClientEvent {
     Invoke(Payload),
     Ingress(Reply),
@@ -126,7 +139,7 @@ impl<N: ToReplicaNet<A>, U: ClientUpcall, A: Addr> Client<N, U, A> {
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Serialize, Deserialize)]
 pub enum ReplicaEvent<A> {
     Ingress(Request<A>),
     Dummy, //
@@ -186,13 +199,19 @@ impl<S: App, N: ToClientNet<A>, A> On<Recv<Request<A>>> for Replica<S, N, A> {
         }
         let reply = Reply {
             seq: request.seq,
-            result: Payload(self.app.execute(&request.op)?),
+            result: Payload::from(self.app.execute(&request.op)?),
         };
         self.replies.insert(request.client_id, reply.clone());
         self.net.send(request.client_addr, reply)
     }
 }
 
+impl<S, N, A> On<Stop> for Replica<S, N, A> {
+    fn on_event(&mut self, Stop: Stop, _: &mut impl Timer) -> anyhow::Result<()> {
+        Err(Exit)?
+    }
+}
+
 impl<S, N, A> OnTimer for Replica<S, N, A> {
     fn on_timer(&mut self, _: TimerId, _: &mut impl Timer) -> anyhow::Result<()> {
         unreachable!()
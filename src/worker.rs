@@ -3,6 +3,7 @@ use crate::event::SendEvent;
 // TODO find a use case for non-erased (type-preseved?) variant
 // or just remove it at all. anyway no performance gain here
 pub type Work<S, M> = erased::Work<S, dyn SendEvent<M>>;
+pub type AsyncWork<S, M> = erased::AsyncWork<S, dyn SendEvent<M>>;
 pub type Worker<S, M> = erased::Worker<S, dyn SendEvent<M>>;
 
 pub type SpawnExecutor<S, M> = erased::SpawnExecutor<S, dyn SendEvent<M>>;
@@ -13,8 +14,13 @@ pub fn spawn_backend<S, M>(state: S) -> (Worker<S, M>, SpawnExecutor<S, M>) {
 }
 
 pub mod erased {
+    use std::{future::Future, pin::Pin, sync::Arc};
+
     use tokio::{
-        sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender},
+        sync::{
+            mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender},
+            oneshot, Semaphore,
+        },
         task::JoinSet,
     };
 
@@ -34,14 +40,72 @@ pub mod erased {
     // so keep working on this if possible
     pub type Work<S, E> = Box<dyn FnOnce(&S, &mut E) -> anyhow::Result<()> + Send + Sync>;
 
+    // an answer to the question above that avoids the detached tasks: the future is polled by the
+    // executor as the other works, so the errors are propagated
+    // the concurrency among works is still encoded into `impl OnEvent`. this is for the cases that
+    // the work itself is asynchronous, e.g. waiting on a batch verification. such work mostly
+    // waits instead of occupying a core, so it is not counted in the concurrency limit
+    pub type AsyncWork<S, E> = Box<
+        dyn for<'a> FnOnce(
+                &'a S,
+                &'a mut E,
+            )
+                -> Pin<Box<dyn Future<Output = anyhow::Result<()>> + Send + 'a>>
+            + Send
+            + Sync,
+    >;
+
+    // enum polymorphism over the ways of running works. a `Blocking` work is a plain `Work` that
+    // is known to be CPU heavy e.g. erasure coding or proving, which should not occupy the
+    // asynchronous runtime threads
+    enum Task<S, E: ?Sized> {
+        Work(Work<S, E>),
+        Async(AsyncWork<S, E>),
+        Blocking(Work<S, E>),
+    }
+
+    impl<S, E: ?Sized> std::fmt::Debug for Task<S, E> {
+        fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+            match self {
+                Self::Work(_) => write!(f, "Work"),
+                Self::Async(_) => write!(f, "Async"),
+                Self::Blocking(_) => write!(f, "Blocking"),
+            }
+        }
+    }
+
     #[derive(Debug)]
     pub struct SpawnExecutor<S, E: ?Sized> {
         state: S,
-        receiver: UnboundedReceiver<Work<S, E>>,
+        receiver: UnboundedReceiver<Task<S, E>>,
         handles: JoinSet<anyhow::Result<()>>,
+        // the (non-async) works beyond the limit are queued (as tasks that wait for permits)
+        // instead of competing the cores with event loops
+        permits: Option<Arc<Semaphore>>,
+        // `None` for falling back to Tokio's blocking threads, which are not limited by default
+        // besides the limit above
+        pool: Option<Arc<rayon::ThreadPool>>,
     }
 
-    impl<S: Clone + Send + Sync + 'static, E: ?Sized + 'static> SpawnExecutor<S, E> {
+    impl<S, E: ?Sized> SpawnExecutor<S, E> {
+        pub fn with_concurrency_limit(self, limit: usize) -> Self {
+            Self {
+                permits: Some(Arc::new(Semaphore::new(limit))),
+                ..self
+            }
+        }
+
+        // the pool may be shared among executors, e.g. one pool sized to the cores that are not
+        // occupied by event loops
+        pub fn with_blocking_pool(self, pool: Arc<rayon::ThreadPool>) -> Self {
+            Self {
+                pool: Some(pool),
+                ..self
+            }
+        }
+    }
+
+    impl<S: Clone + Send + Sync + 'static, E: ?Sized + Send + 'static> SpawnExecutor<S, E> {
         pub async fn run<F: Clone + Send + 'static>(
             &mut self,
             sender: F,
@@ -59,19 +123,44 @@ pub mod erased {
         ) -> anyhow::Result<()> {
             loop {
                 enum Select<S, E: ?Sized> {
-                    Recv(Work<S, E>),
+                    Recv(Task<S, E>),
                     JoinNext(()),
                 }
-                if let Select::Recv(work) = tokio::select! {
+                let task = match tokio::select! {
                     Some(result) = self.handles.join_next() => Select::JoinNext(result??),
-                    work = self.receiver.recv() => Select::Recv(work.ok_or(anyhow::anyhow!("channel closed"))?),
+                    task = self.receiver.recv() => Select::Recv(task.ok_or(anyhow::anyhow!("channel closed"))?),
                 } {
-                    let state = self.state.clone();
-                    let mut sender = sender.clone();
-                    let mut as_sender = as_sender.clone();
-                    self.handles
-                        .spawn(async move { work(&state, as_sender(&mut sender)) });
-                }
+                    Select::Recv(task) => task,
+                    Select::JoinNext(()) => continue,
+                };
+                let state = self.state.clone();
+                let mut sender = sender.clone();
+                let mut as_sender = as_sender.clone();
+                let permits = self.permits.clone();
+                let pool = self.pool.clone();
+                self.handles.spawn(async move {
+                    let _permit = match (&task, permits) {
+                        (Task::Async(_), _) | (_, None) => None,
+                        (_, Some(permits)) => Some(permits.acquire_owned().await?),
+                    };
+                    match task {
+                        Task::Work(work) => work(&state, as_sender(&mut sender)),
+                        Task::Async(work) => work(&state, as_sender(&mut sender)).await,
+                        Task::Blocking(work) => {
+                            let work = move || work(&state, as_sender(&mut sender));
+                            if let Some(pool) = pool {
+                                let (result_sender, result_receiver) = oneshot::channel();
+                                pool.spawn(move || {
+                                    // the receiver is gone only if the executor is shutting down
+                                    let _ = result_sender.send(work());
+                                });
+                                result_receiver.await?
+                            } else {
+                                tokio::task::spawn_blocking(work).await?
+                            }
+                        }
+                    }
+                });
             }
         }
     }
@@ -91,7 +180,25 @@ pub mod erased {
         pub fn submit(&mut self, work: Work<S, E>) -> anyhow::Result<()> {
             match self {
                 Self::Inline(worker) => worker.submit(work),
-                Self::Spawn(worker) => worker.submit(work),
+                Self::Spawn(worker) => worker.submit(Task::Work(work)),
+                Self::Null => Ok(()),
+            }
+        }
+
+        // there's nowhere to poll the future on inline worker, so only spawn worker supports it
+        pub fn submit_async(&mut self, work: AsyncWork<S, E>) -> anyhow::Result<()> {
+            match self {
+                Self::Inline(_) => anyhow::bail!("async work on inline worker"),
+                Self::Spawn(worker) => worker.submit(Task::Async(work)),
+                Self::Null => Ok(()),
+            }
+        }
+
+        // inline worker blocks the event loop anyway, so it's not different from `submit`
+        pub fn submit_blocking(&mut self, work: Work<S, E>) -> anyhow::Result<()> {
+            match self {
+                Self::Inline(worker) => worker.submit(work),
+                Self::Spawn(worker) => worker.submit(Task::Blocking(work)),
                 Self::Null => Ok(()),
             }
         }
@@ -107,16 +214,16 @@ pub mod erased {
     }
 
     #[derive(Debug, Clone)]
-    pub struct SpawnWorker<S, E: ?Sized>(UnboundedSender<Work<S, E>>);
+    pub struct SpawnWorker<S, E: ?Sized>(UnboundedSender<Task<S, E>>);
 
     impl<S, E: ?Sized> SpawnWorker<S, E> {
-        fn submit(&self, work: Work<S, E>) -> anyhow::Result<()> {
+        fn submit(&self, task: Task<S, E>) -> anyhow::Result<()> {
             // requires S: 'static and E: 'static, which in turn requires many things 'static
             // allow they probably happen to 'static, feels too tricky when later reasoning about
             // the bound
             // self.0.send(work).map_err(anyhow::Error::msg)
             self.0
-                .send(work)
+                .send(task)
                 .map_err(|err| anyhow::anyhow!(err.to_string()))
         }
     }
@@ -128,7 +235,95 @@ pub mod erased {
             receiver,
             state,
             handles: Default::default(),
+            permits: None,
+            pool: None,
         };
         (Worker::Spawn(worker), executor)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        sync::{
+            atomic::{AtomicUsize, Ordering::SeqCst},
+            Arc,
+        },
+        time::Duration,
+    };
+
+    use tokio::{
+        sync::mpsc::{unbounded_channel, UnboundedReceiver},
+        time::sleep,
+    };
+
+    use super::erased::{spawn_backend, Worker};
+    use crate::event::SendEvent;
+
+    type E<M> = dyn SendEvent<M> + Send + Sync;
+
+    fn spawn<S: Clone + Send + Sync + 'static, M: Send + 'static>(
+        state: S,
+        limit: Option<usize>,
+        pool: Option<Arc<rayon::ThreadPool>>,
+    ) -> (Worker<S, E<M>>, UnboundedReceiver<M>) {
+        let (worker, mut executor) = spawn_backend::<_, E<M>>(state);
+        if let Some(limit) = limit {
+            executor = executor.with_concurrency_limit(limit)
+        }
+        if let Some(pool) = pool {
+            executor = executor.with_blocking_pool(pool)
+        }
+        let (sender, receiver) = unbounded_channel();
+        tokio::spawn(async move { executor.run(sender, |sender| sender).await });
+        (worker, receiver)
+    }
+
+    #[tokio::test]
+    async fn submit_async() -> anyhow::Result<()> {
+        let (mut worker, mut receiver) = spawn(42, None, None);
+        worker.submit_async(Box::new(|state, sender| {
+            Box::pin(async move {
+                sleep(Duration::from_millis(1)).await;
+                sender.send(*state)
+            })
+        }))?;
+        assert_eq!(receiver.recv().await, Some(42));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn submit_blocking() -> anyhow::Result<()> {
+        let pool = rayon::ThreadPoolBuilder::new()
+            .num_threads(1)
+            .thread_name(|_| "pool".into())
+            .build()?;
+        let (mut worker, mut receiver) = spawn((), None, Some(Arc::new(pool)));
+        worker.submit_blocking(Box::new(|(), sender| {
+            sender.send(std::thread::current().name().map(ToString::to_string))
+        }))?;
+        assert_eq!(receiver.recv().await, Some(Some("pool".into())));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn concurrency_limit() -> anyhow::Result<()> {
+        // the running works and the most running works ever
+        let counts = Arc::new((AtomicUsize::new(0), AtomicUsize::new(0)));
+        let (mut worker, mut receiver) = spawn(counts.clone(), Some(2), None);
+        for _ in 0..6 {
+            worker.submit_blocking(Box::new(|counts, sender| {
+                let running = counts.0.fetch_add(1, SeqCst) + 1;
+                counts.1.fetch_max(running, SeqCst);
+                std::thread::sleep(Duration::from_millis(10));
+                counts.0.fetch_sub(1, SeqCst);
+                sender.send(())
+            }))?
+        }
+        for _ in 0..6 {
+            receiver.recv().await;
+        }
+        assert_eq!(counts.1.load(SeqCst), 2);
+        Ok(())
+    }
+}
@@ -13,8 +13,11 @@ use std::{
 
 use crate::{
     event::{
-        erased::{events::Init, OnEvent},
-        OnTimer, SendEvent, Timer,
+        erased::{
+            events::{Init, Stop},
+            OnEvent,
+        },
+        Exit, OnTimer, SendEvent, Timer,
     },
     message::Payload,
 };
@@ -258,12 +261,13 @@ pub struct CloseLoop<W: Workload, E> {
     pub sender: E,
     pub workload: W,
     workload_attach: Option<W::Attach>,
-    pub stop: Option<CloseLoopStop>,
+    // request the hosting session to shut down when the workload is finished, i.e. return `Exit`
+    // after `done` is set. not enabled by default because model checking steps on close loops
+    // directly, and an `Exit` there will be taken as a violation
+    pub exit_on_done: bool,
     pub done: bool,
 }
 
-type CloseLoopStop = Box<dyn FnOnce() -> anyhow::Result<()> + Send + Sync>;
-
 impl<W: Workload, E> Debug for CloseLoop<W, E> {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         f.debug_struct("CloseLoop").finish_non_exhaustive()
@@ -276,7 +280,7 @@ impl<W: Workload, E> CloseLoop<W, E> {
             sender,
             workload,
             workload_attach: None,
-            stop: None,
+            exit_on_done: false,
             done: false,
         }
     }
@@ -287,14 +291,11 @@ where
     W::Attach: Clone,
 {
     fn clone(&self) -> Self {
-        if self.stop.is_some() {
-            panic!("cannot clone CloseLoop with some `stop`")
-        }
         Self {
             sender: self.sender.clone(),
             workload: self.workload.clone(),
             workload_attach: self.workload_attach.clone(),
-            stop: None,
+            exit_on_done: self.exit_on_done,
             done: self.done,
         }
     }
@@ -325,14 +326,21 @@ impl<W: Workload, E: SendEvent<Invoke>> OnEvent<InvokeOk> for CloseLoop<W, E> {
             self.sender.send(Invoke(op))
         } else {
             self.done = true;
-            if let Some(stop) = self.stop.take() {
-                stop()?
+            if self.exit_on_done {
+                Err(Exit)?
             }
             Ok(())
         }
     }
 }
 
+// stop in the middle of the workload, the outstanding invocation (if any) is abandoned
+impl<W: Workload, E> OnEvent<Stop> for CloseLoop<W, E> {
+    fn on_event(&mut self, Stop: Stop, _: &mut impl Timer) -> anyhow::Result<()> {
+        Err(Exit)?
+    }
+}
+
 impl<W: Workload, E> OnTimer for CloseLoop<W, E> {
     fn on_timer(&mut self, _: crate::event::TimerId, _: &mut impl Timer) -> anyhow::Result<()> {
         unreachable!()
@@ -15,6 +15,17 @@ pub struct StartPeersConfig {
     pub chunk_m: NonZeroUsize,
 }
 
+// the bandwidth limits of the outgoing traffic of a host, in bytes per second, `None` for
+// unlimited. the destinations are the hosts i.e. the IP addresses, and a limit is shared by all the
+// peers on the host
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct BandwidthConfig {
+    pub aggregate: Option<u64>,
+    pub default: Option<u64>,
+    #[serde(default)]
+    pub per_dest: Vec<(IpAddr, u64)>,
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub enum PeerUrl {
     Ipfs(String),
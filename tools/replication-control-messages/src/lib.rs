@@ -1,4 +1,7 @@
-use std::{net::SocketAddr, time::Duration};
+use std::{
+    net::{SocketAddr, SocketAddrV4},
+    time::Duration,
+};
 
 use serde::{Deserialize, Serialize};
 
@@ -6,6 +9,27 @@ use serde::{Deserialize, Serialize};
 pub struct BenchmarkResult {
     pub throughput: f32,
     pub latency: Duration,
+    // of the client node
+    #[serde(default)]
+    pub traffic: Vec<TrafficCount>,
+}
+
+// mirror of `augustus::net::traffic::Direction`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum TrafficDirection {
+    Sent,
+    Received,
+}
+
+// one entry of `augustus::net::traffic::Traffic`. `addr` is the destination of the sent messages
+// and the source of the received ones
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TrafficCount {
+    pub direction: TrafficDirection,
+    pub addr: Option<SocketAddr>,
+    pub kind: String,
+    pub messages: u64,
+    pub bytes: u64,
 }
 
 #[derive(Debug, Clone, Copy, Serialize, Deserialize)]
@@ -61,4 +85,33 @@ pub struct ReplicaConfig {
     pub replica_addrs: Vec<SocketAddr>,
     pub num_replica: usize,
     pub num_faulty: usize,
+    // PBFT only. the IPv4 multicast group that all replicas join, see `augustus::net::broadcast`
+    #[serde(default)]
+    pub multicast_group: Option<SocketAddrV4>,
+}
+
+// mirror of `augustus::net::fault::Faults`
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Faults {
+    pub drop_rate: f64,
+    pub duplicate_rate: f64,
+    pub reorder_rate: f64,
+    pub delay: Duration,
+    pub jitter: Duration,
+    pub jitter_distribution: Jitter,
+    pub partitioned: bool,
+}
+
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize)]
+pub enum Jitter {
+    #[default]
+    Uniform,
+    Normal,
+    Exponential,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct FaultConfig {
+    pub default: Faults,
+    pub per_dest: Vec<(SocketAddr, Faults)>,
 }
@@ -101,6 +101,7 @@ async fn benchmark_session(
             app: app.clone(),
             num_replica,
             num_faulty,
+            multicast_group: None,
         };
         control_client
             .post(format!("{replica_url}/start-replica"))